@@ -0,0 +1,191 @@
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::env;
+use teloxide::types::UserId;
+
+use crate::migrations::NewerSchema;
+use crate::session::{normalize_session_code, Session};
+use crate::store;
+
+// How long ended sessions are kept when KARAOKE_ARCHIVE_RETENTION_DAYS isn't set
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+// Ended sessions, kept so owners can look back at previous nights
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SessionArchive {
+    pub sessions: Vec<ArchivedSession>,
+    pub next_id: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ArchivedSession {
+    pub id: u64,
+    pub session: Session,
+    pub ended_at: i64, // Unix timestamp when the last user left
+}
+
+impl SessionArchive {
+    pub fn new() -> Result<Self> {
+        let mut archive = match Self::load() {
+            Ok(archive) => archive,
+            Err(e) if e.downcast_ref::<NewerSchema>().is_some() => return Err(e),
+            Err(e) => {
+                log::error!(
+                    "Failed to load the session archive, starting a new one: {}",
+                    e
+                );
+                Self::default()
+            }
+        };
+        archive.purge_expired();
+        Ok(archive)
+    }
+
+    // Have the archive saved in the background shortly, along with the sessions
+    pub fn save(&self) -> Result<()> {
+        store::schedule_archive_save()
+    }
+
+    pub fn load() -> Result<Self> {
+        Ok(store::store().load_archive()?.unwrap_or_default())
+    }
+
+    // Store an ended session and return its archive id
+    pub fn add(&mut self, session: Session) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.sessions.push(ArchivedSession {
+            id,
+            session,
+            ended_at: chrono::Utc::now().timestamp(),
+        });
+
+        self.purge_expired();
+
+        // Save archive after adding session
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session archive: {}", e);
+        }
+
+        id
+    }
+
+    // Archived sessions the user owned or took part in, newest first
+    pub fn sessions_for(&self, user_id: &UserId) -> Vec<&ArchivedSession> {
+        self.sessions
+            .iter()
+            .rev()
+            .filter(|archived| archived.involves(user_id))
+            .collect()
+    }
+
+    // Look up an archived session the user is allowed to see
+    pub fn get(&self, user_id: &UserId, id: u64) -> Option<&ArchivedSession> {
+        self.sessions
+            .iter()
+            .find(|archived| archived.id == id && archived.involves(user_id))
+    }
+
+    // Find an archived session the user can see by archive number (e.g. 3 or #3)
+    // or by its old session code, preferring the most recent one
+    pub fn find(&self, user_id: &UserId, source: &str) -> Option<&ArchivedSession> {
+        match source.trim_start_matches('#').parse() {
+            Ok(id) => self.get(user_id, id),
+            Err(_) => {
+                let code = normalize_session_code(source);
+                self.sessions_for(user_id)
+                    .into_iter()
+                    .find(|archived| archived.session.code == code)
+            }
+        }
+    }
+
+    // Drop archived sessions older than the retention period
+    fn purge_expired(&mut self) {
+        let cutoff = chrono::Utc::now().timestamp() - retention_days() * 24 * 60 * 60;
+        let before = self.sessions.len();
+
+        self.sessions.retain(|archived| archived.ended_at >= cutoff);
+
+        if self.sessions.len() < before {
+            info!(
+                "Purged {} expired sessions from the archive",
+                before - self.sessions.len()
+            );
+        }
+    }
+}
+
+impl ArchivedSession {
+    // One-line description for the /pastsessions list
+    pub fn summary(&self) -> String {
+        let played = self.session.queue.iter().filter(|item| item.played).count();
+
+        format!(
+            "#{} - session {} ended {} ({} songs played)",
+            self.id,
+            self.session.code,
+            self.session.format_time(self.ended_at),
+            played
+        )
+    }
+
+    // Full history and leftover queue of the session
+    pub fn details(&self) -> String {
+        let mut text = format!(
+            "Session {} (archive #{})\nStarted: {}\nEnded: {}",
+            self.session.code,
+            self.id,
+            self.session.format_time(self.session.created_at),
+            self.session.format_time(self.ended_at)
+        );
+
+        let (played, unplayed): (Vec<_>, Vec<_>) =
+            self.session.queue.iter().partition(|item| item.played);
+
+        for (heading, items) in [("Played", played), ("Never played", unplayed)] {
+            if items.is_empty() {
+                continue;
+            }
+
+            text.push_str(&format!("\n\n{}:", heading));
+            for (i, item) in items.iter().enumerate() {
+                let video_title = item
+                    .video_info
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+
+                text.push_str(&format!(
+                    "\n{}. {} (added by {})",
+                    i + 1,
+                    video_title,
+                    self.session.item_user_name(item)
+                ));
+            }
+        }
+
+        text
+    }
+
+    // Whether the user owned the session, was in it at the end, or added songs to it
+    fn involves(&self, user_id: &UserId) -> bool {
+        self.session.owner == *user_id
+            || self.session.users.iter().any(|(id, _)| id == user_id)
+            || self
+                .session
+                .queue
+                .iter()
+                .any(|item| item.added_by == *user_id)
+    }
+}
+
+// Number of days to keep archived sessions, from KARAOKE_ARCHIVE_RETENTION_DAYS
+fn retention_days() -> i64 {
+    env::var("KARAOKE_ARCHIVE_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
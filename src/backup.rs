@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::migrations;
+use crate::session::SessionState;
+use crate::store::{self, data_path};
+use crate::SharedState;
+
+// Directory in the data directory the snapshots go in
+const BACKUP_DIR: &str = "backups";
+
+// How often a snapshot of the sessions is taken
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How many snapshots are kept when KARAOKE_BACKUP_COUNT isn't set
+const DEFAULT_BACKUP_COUNT: usize = 24;
+
+// Snapshot files are named after when they were taken, e.g. sessions-20240601-200000.json
+const BACKUP_PREFIX: &str = "sessions-";
+const BACKUP_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+// A snapshot of the sessions on disk
+pub struct Backup {
+    pub path: PathBuf,
+    pub taken_at: i64, // Unix timestamp
+}
+
+// Background task that snapshots the sessions every hour, whichever store keeps them,
+// so an accidental change or a broken save can be rolled back with /restorebackup
+pub async fn run_backups(state: SharedState) {
+    let mut interval = tokio::time::interval(BACKUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match create_backup(&state).await {
+            Ok(path) => info!("Saved a backup of the sessions to {}", path.display()),
+            Err(e) => error!("Failed to back up the sessions: {}", e),
+        }
+    }
+}
+
+// Snapshot the sessions now, dropping the oldest snapshots past KARAOKE_BACKUP_COUNT
+pub async fn create_backup(state: &SharedState) -> Result<PathBuf> {
+    let json = serde_json::to_string_pretty(&*state.lock().await)?;
+
+    tokio::task::spawn_blocking(move || {
+        let dir = data_path(BACKUP_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let name = format!(
+            "{}{}.json",
+            BACKUP_PREFIX,
+            chrono::Utc::now().format(BACKUP_TIME_FORMAT)
+        );
+        let path = dir.join(name);
+        fs::write(&path, json)?;
+
+        for old in list_backups()?.into_iter().skip(backup_count()) {
+            fs::remove_file(&old.path)?;
+        }
+        Ok(path)
+    })
+    .await?
+}
+
+// The snapshots on disk, newest first
+pub fn list_backups() -> Result<Vec<Backup>> {
+    let dir = data_path(BACKUP_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups: Vec<Backup> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let taken_at = name
+                .strip_prefix(BACKUP_PREFIX)
+                .and_then(|rest| rest.strip_suffix(".json"))
+                .and_then(|time| {
+                    chrono::NaiveDateTime::parse_from_str(time, BACKUP_TIME_FORMAT).ok()
+                })?
+                .and_utc()
+                .timestamp();
+            Some(Backup {
+                path: entry.path(),
+                taken_at,
+            })
+        })
+        .collect();
+
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.taken_at));
+    Ok(backups)
+}
+
+// Read the sessions saved in a snapshot
+pub fn load_backup(backup: &Backup) -> Result<SessionState> {
+    let json = fs::read_to_string(&backup.path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", backup.path.display(), e))?;
+    store::parse(&json, migrations::migrate_state)
+        .map_err(|e| anyhow!("{} is corrupt: {}", backup.path.display(), e))
+}
+
+// How many snapshots to keep, from KARAOKE_BACKUP_COUNT
+fn backup_count() -> usize {
+    env::var("KARAOKE_BACKUP_COUNT")
+        .ok()
+        .and_then(|count| count.trim().parse::<usize>().ok())
+        .filter(|count| *count > 0)
+        .unwrap_or(DEFAULT_BACKUP_COUNT)
+}
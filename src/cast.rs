@@ -1,66 +1,539 @@
+mod airplay;
+mod castv2;
+mod chromecast;
+mod dlna;
+mod kodi;
+mod local;
+mod mock;
+mod web;
+
 use anyhow::{anyhow, Result};
-use log::{error, info};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::fmt;
 
 use crate::youtube::VideoInfo;
 
+pub use chromecast::run_heartbeat;
+pub use web::run_web_player;
+
+// Device used when the session owner hasn't picked one with /castto
+pub const DEFAULT_DEVICE: &str = "default device";
+
 // Cast status for a session
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CastStatus {
     pub current_video: Option<VideoInfo>,
     pub cast_device: Option<String>,
+    pub cast_backend: Option<String>, // Backend id of cast_device, None for Chromecast
+    pub audio_group: Option<String>,  // Chromecast speaker group playing along with the video
     pub is_playing: bool,
+    pub volume: Option<u8>, // Last volume set with /volume, 0-100
+    pub muted: bool,
+}
+
+impl CastStatus {
+    // The device chosen with /castto, if any
+    pub fn device(&self) -> Option<CastDevice> {
+        let name = self.cast_device.clone()?;
+        let backend = self
+            .cast_backend
+            .clone()
+            .unwrap_or_else(|| "chromecast".to_string());
+
+        Some(CastDevice { backend, name })
+    }
+
+    // Choose the device videos play on
+    pub fn set_device(&mut self, device: &CastDevice) {
+        self.cast_device = Some(device.name.clone());
+        self.cast_backend = Some(device.backend.clone());
+    }
+
+    // The chosen device and speaker group, for commands that go to both
+    pub fn target(&self) -> CastTarget {
+        CastTarget {
+            device: self.device(),
+            audio_group: self.audio_group.clone().map(|name| CastDevice {
+                backend: "chromecast".to_string(),
+                name,
+            }),
+        }
+    }
+}
+
+// Where a session's videos play: the chosen device, plus a speaker group playing the audio
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CastTarget {
+    pub device: Option<CastDevice>, // None means the default device
+    pub audio_group: Option<CastDevice>,
+}
+
+// A device a backend found on the network
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub name: String,
+    pub audio_only: bool, // Speakers and speaker groups, which can't show the video
+}
+
+// A device found by /devices, with the backend that talks to it
+#[derive(Debug, Clone)]
+pub struct AvailableDevice {
+    pub device: CastDevice,
+    pub audio_only: bool,
+}
+
+// A device videos can be cast to, and the backend that talks to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CastDevice {
+    pub backend: String, // Backend id, e.g. "chromecast" or "dlna"
+    pub name: String,    // Friendly name, e.g. "Living Room TV"
 }
 
-impl Default for CastStatus {
-    fn default() -> Self {
-        Self {
-            current_video: None,
-            cast_device: None,
-            is_playing: false,
+impl fmt::Display for CastDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match find_backend(&self.backend) {
+            Some(backend) => write!(f, "{} ({})", self.name, backend.label()),
+            None => write!(f, "{}", self.name),
         }
     }
 }
 
-// This function would actually send the video to a cast device
-// For now, it's a placeholder that simulates successful casting
-pub async fn cast_video(video_info: &VideoInfo, device_name: Option<&str>) -> Result<bool> {
-    // Get the embed URL for the video
-    let embed_url = crate::youtube::get_embed_url(&video_info.id);
+// Player state reported by a device, mirroring the Chromecast media status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerState {
+    Playing,
+    Paused,
+    Idle,
+}
 
-    // Log casting attempt
-    let device = device_name.unwrap_or("default device");
-    info!("Casting video {} to {}", video_info.id, device);
+// Media status reported by a device
+#[derive(Debug, Clone)]
+pub struct MediaStatus {
+    pub player_state: PlayerState,
+    pub position: u64,         // Seconds into the video
+    pub duration: Option<u64>, // Length of the video in seconds, if known
+    pub finished: bool,        // Idle because the video played to the end
+    pub error: Option<String>, // Why the device couldn't play the video, e.g. embedding is disabled
+}
+
+// A device refused to load a video, e.g. because the uploader disabled embedding.
+// Retrying won't help, so the song is skipped instead.
+#[derive(Debug)]
+pub struct LoadFailed {
+    pub reason: String,
+}
+
+impl fmt::Display for LoadFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for LoadFailed {}
+
+// Who's singing and what's coming, shown alongside a video on devices that can
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NowPlaying {
+    pub singer: String,
+    pub up_next: Vec<String>, // "Title — sung by Name" for the next few songs
+}
+
+// What the screen shows while nothing is playing, so newcomers know how to join
+#[derive(Debug, Clone, Serialize)]
+pub struct IdleCard {
+    pub title: String,
+    pub code: String,
+    pub join_link: Option<String>, // Telegram link that joins the session, shown as a QR code
+}
+
+// Volume reported by a device
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeStatus {
+    pub level: u8, // 0-100
+    pub muted: bool,
+}
 
-    // In a real implementation, this would interact with the Chromecast API
-    // For now, we'll just simulate success
+// A kind of device videos can be cast to, e.g. Chromecasts or DLNA renderers
+#[async_trait]
+pub trait CastBackend: Send + Sync {
+    // Id saved with the session to remember which backend a device belongs to
+    fn id(&self) -> &'static str;
 
-    // Simulating potential failures (could be expanded later)
+    // Name shown next to devices in /devices
+    fn label(&self) -> &'static str;
+
+    // The devices this backend can find on the network
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>>;
+
+    // Get ready to control a device, e.g. by opening a connection to it
+    async fn connect(&self, _device: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        now_playing: &NowPlaying,
+    ) -> Result<()>;
+    async fn pause(&self, device: &str) -> Result<()>;
+    async fn resume(&self, device: &str) -> Result<()>;
+    async fn seek(&self, device: &str, position: u64) -> Result<()>;
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()>;
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()>;
+    async fn stop(&self, device: &str) -> Result<()>;
+    async fn media_status(&self, device: &str) -> Result<MediaStatus>;
+
+    // Put a message on the screen between songs, returns false if the device can't show one
+    async fn show_message(&self, _device: &str, _text: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Buffer the song that comes after the current one so it starts instantly,
+    // returns false if the device can't
+    async fn preload(&self, _device: &str, _video_info: &VideoInfo) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Play a spoken announcement before a song, returns false if the device can't
+    async fn play_announcement(&self, _device: &str, _audio_url: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Show how to join the session while nothing is playing, returns false if the device can't
+    async fn show_idle(&self, _device: &str, _card: &IdleCard) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Refresh who's up next while a video plays, e.g. after songs are added
+    async fn update_now_playing(&self, _device: &str, _now_playing: &NowPlaying) -> Result<()> {
+        Ok(())
+    }
+
+    // The device's volume, if it can tell us
+    async fn volume(&self, _device: &str) -> Result<Option<VolumeStatus>> {
+        Ok(None)
+    }
+}
+
+lazy_static! {
+    // Every backend devices can be discovered with, the first one drives the default device.
+    // KARAOKE_CAST=mock replaces them all with one that only records what it's asked to do.
+    static ref CAST_BACKENDS: Vec<Box<dyn CastBackend>> = match env::var("KARAOKE_CAST") {
+        Ok(mode) if mode.eq_ignore_ascii_case("mock") => vec![Box::new(mock::MockCastBackend::new())],
+        _ => vec![
+            Box::new(chromecast::Chromecast),
+            Box::new(dlna::Dlna::new()),
+            Box::new(airplay::AirPlay::new()),
+            Box::new(kodi::Kodi::new()),
+            Box::new(local::LocalPlayer::new()),
+            Box::new(web::WebPlayer),
+        ],
+    };
+}
+
+fn find_backend(id: &str) -> Option<&'static dyn CastBackend> {
+    CAST_BACKENDS
+        .iter()
+        .find(|backend| backend.id() == id)
+        .map(|backend| backend.as_ref())
+}
+
+// The backend and device name to use, falling back to the default device
+fn resolve(device: Option<&CastDevice>) -> Result<(&'static dyn CastBackend, &str)> {
+    match device {
+        Some(device) => {
+            let backend = find_backend(&device.backend)
+                .ok_or_else(|| anyhow!("Unknown cast backend {}", device.backend))?;
+            Ok((backend, device.name.as_str()))
+        }
+        None => Ok((CAST_BACKENDS[0].as_ref(), DEFAULT_DEVICE)),
+    }
+}
+
+// Get ready to control a device ahead of the first command
+pub async fn connect_device(device: &CastDevice) -> Result<()> {
+    let (backend, name) = resolve(Some(device))?;
+    backend.connect(name).await
+}
+
+// The backend and name of the speaker group playing along, if any
+fn resolve_audio_group(target: &CastTarget) -> Option<(&'static dyn CastBackend, &str)> {
+    let group = target.audio_group.as_ref()?;
+    match resolve(Some(group)) {
+        Ok(resolved) => Some(resolved),
+        Err(e) => {
+            warn!("Ignoring speaker group {}: {}", group.name, e);
+            None
+        }
+    }
+}
+
+// Ask a device what it's playing
+pub async fn get_media_status(device: Option<&CastDevice>) -> Result<MediaStatus> {
+    let (backend, name) = resolve(device)?;
+    backend.media_status(name).await
+}
+
+// Ask a device how loud it is
+pub async fn get_volume(device: Option<&CastDevice>) -> Result<Option<VolumeStatus>> {
+    let (backend, name) = resolve(device)?;
+    backend.volume(name).await
+}
+
+// Update the singer and up next list shown with the current video
+pub async fn update_now_playing(
+    device: Option<&CastDevice>,
+    now_playing: &NowPlaying,
+) -> Result<()> {
+    let (backend, name) = resolve(device)?;
+    backend.update_now_playing(name, now_playing).await
+}
+
+// Show a message on a device's screen, e.g. who's up next. Returns false if it can't.
+pub async fn show_message(device: Option<&CastDevice>, text: &str) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    info!("Showing \"{}\" on {}", text, name);
+    backend.show_message(name, text).await
+}
+
+// Show the session's join details on a device while nothing is playing. Returns false if it can't.
+pub async fn show_idle_card(device: Option<&CastDevice>, card: &IdleCard) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    info!("Showing the idle screen for {} on {}", card.code, name);
+    backend.show_idle(name, card).await
+}
+
+// Have a device buffer the next song while the current one plays. Returns false if it can't.
+pub async fn preload_video(device: Option<&CastDevice>, video_info: &VideoInfo) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    backend.preload(name, video_info).await
+}
+
+// Play a spoken announcement on a device. Returns false if it can't.
+pub async fn play_announcement(device: Option<&CastDevice>, audio_url: &str) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    info!("Playing announcement on {}", name);
+    backend.play_announcement(name, audio_url).await
+}
+
+// Send a video to a cast device
+pub async fn cast_video(
+    video_info: &VideoInfo,
+    now_playing: &NowPlaying,
+    target: &CastTarget,
+) -> Result<()> {
+    let (backend, name) = resolve(target.device.as_ref())?;
+    info!("Casting video {} to {}", video_info.id, name);
+
+    // No device could play it, so it's skipped rather than retried
     if video_info.id.is_empty() {
-        return Err(anyhow!("Invalid video ID"));
+        return Err(LoadFailed {
+            reason: "The video has no ID".to_string(),
+        }
+        .into());
+    }
+
+    backend.play(name, video_info, now_playing).await?;
+
+    // The speaker group is a bonus, the song still plays on the TV if it fails
+    if let Some((group_backend, group)) = resolve_audio_group(target) {
+        info!("Also playing the audio of {} on {}", video_info.id, group);
+        if let Err(e) = group_backend.play(group, video_info, now_playing).await {
+            warn!("Failed to play on speaker group {}: {}", group, e);
+        }
+    }
+
+    Ok(())
+}
+
+// What one backend found while diagnosing casting
+pub struct BackendDiagnostic {
+    pub label: &'static str,
+    pub devices: Result<Vec<DeviceDiagnostic>>, // Err if discovery itself failed
+}
+
+// How a discovered device responded while diagnosing casting
+pub struct DeviceDiagnostic {
+    pub device: CastDevice,
+    pub audio_only: bool,
+    pub connection: Result<()>,
+    pub status: Option<Result<MediaStatus>>, // Only asked once connected
+}
+
+// Run discovery on every backend, then try to connect to each device found and
+// ask it what it's doing, to work out why casting isn't working
+pub async fn diagnose() -> Vec<BackendDiagnostic> {
+    let searches: Vec<_> = CAST_BACKENDS
+        .iter()
+        .map(|backend| {
+            let backend: &'static dyn CastBackend = backend.as_ref();
+            (backend, tokio::spawn(backend.discover()))
+        })
+        .collect();
+
+    let mut report = Vec::new();
+
+    for (backend, search) in searches {
+        let found = match search.await {
+            Ok(found) => found,
+            Err(e) => Err(anyhow!(e)),
+        };
+
+        let devices = match found {
+            Ok(found) => {
+                let mut devices = Vec::new();
+                for found in found {
+                    let connection = backend.connect(&found.name).await;
+                    let status = match connection {
+                        Ok(()) => Some(backend.media_status(&found.name).await),
+                        Err(_) => None,
+                    };
+
+                    devices.push(DeviceDiagnostic {
+                        device: CastDevice {
+                            backend: backend.id().to_string(),
+                            name: found.name,
+                        },
+                        audio_only: found.audio_only,
+                        connection,
+                        status,
+                    });
+                }
+                Ok(devices)
+            }
+            Err(e) => Err(e),
+        };
+
+        report.push(BackendDiagnostic {
+            label: backend.label(),
+            devices,
+        });
+    }
+
+    report
+}
+
+// Get the cast devices available on the network, from every backend
+pub async fn get_available_devices() -> Result<Vec<AvailableDevice>> {
+    // Each backend listens for a few seconds, so they all search at once
+    let searches: Vec<_> = CAST_BACKENDS
+        .iter()
+        .map(|backend| {
+            let backend: &'static dyn CastBackend = backend.as_ref();
+            (backend, tokio::spawn(backend.discover()))
+        })
+        .collect();
+
+    let mut devices = Vec::new();
+    let mut errors = Vec::new();
+
+    for (backend, search) in searches {
+        match search.await {
+            Ok(Ok(found)) => devices.extend(found.into_iter().map(|found| AvailableDevice {
+                device: CastDevice {
+                    backend: backend.id().to_string(),
+                    name: found.name,
+                },
+                audio_only: found.audio_only,
+            })),
+            Ok(Err(e)) => {
+                error!("{} discovery failed: {}", backend.label(), e);
+                errors.push(e);
+            }
+            Err(e) => {
+                error!("{} discovery failed: {}", backend.label(), e);
+                errors.push(anyhow!(e));
+            }
+        }
+    }
+
+    // Only an error if no backend could search at all
+    if errors.len() == CAST_BACKENDS.len() {
+        if let Some(e) = errors.pop() {
+            return Err(e);
+        }
     }
 
-    // Return success
+    Ok(devices)
+}
+
+// Pause the video playing on a device
+pub async fn pause_casting(target: &CastTarget) -> Result<bool> {
+    let (backend, name) = resolve(target.device.as_ref())?;
+    info!("Pausing playback on {}", name);
+    backend.pause(name).await?;
+
+    if let Some((group_backend, group)) = resolve_audio_group(target) {
+        if let Err(e) = group_backend.pause(group).await {
+            warn!("Failed to pause speaker group {}: {}", group, e);
+        }
+    }
     Ok(true)
 }
 
-// Get a list of available cast devices
-// This is a placeholder that would be replaced with actual device discovery
-pub async fn get_available_devices() -> Result<Vec<String>> {
-    // In a real implementation, this would discover Chromecast devices on the network
-    // For now, we'll return a dummy list
-    Ok(vec![
-        "Living Room TV".to_string(),
-        "Bedroom Chromecast".to_string(),
-    ])
+// Resume a paused video on a device
+pub async fn resume_casting(target: &CastTarget) -> Result<bool> {
+    let (backend, name) = resolve(target.device.as_ref())?;
+    info!("Resuming playback on {}", name);
+    backend.resume(name).await?;
+
+    if let Some((group_backend, group)) = resolve_audio_group(target) {
+        if let Err(e) = group_backend.resume(group).await {
+            warn!("Failed to resume speaker group {}: {}", group, e);
+        }
+    }
+    Ok(true)
+}
+
+// Jump to a position, in seconds, within the video playing on a device
+pub async fn seek_to(target: &CastTarget, position: u64) -> Result<bool> {
+    let (backend, name) = resolve(target.device.as_ref())?;
+    info!("Seeking to {}s on {}", position, name);
+    backend.seek(name, position).await?;
+
+    if let Some((group_backend, group)) = resolve_audio_group(target) {
+        if let Err(e) = group_backend.seek(group, position).await {
+            warn!("Failed to seek on speaker group {}: {}", group, e);
+        }
+    }
+    Ok(true)
+}
+
+// Set the receiver volume of a device, from 0 to 100
+pub async fn set_volume(device: Option<&CastDevice>, level: u8) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    if level > 100 {
+        return Err(anyhow!("Volume must be between 0 and 100"));
+    }
+    info!("Setting volume on {} to {}%", name, level);
+    backend.set_volume(name, level).await?;
+    Ok(true)
+}
+
+// Mute or unmute the receiver of a device
+pub async fn set_muted(device: Option<&CastDevice>, muted: bool) -> Result<bool> {
+    let (backend, name) = resolve(device)?;
+    info!("{} {}", if muted { "Muting" } else { "Unmuting" }, name);
+    backend.set_muted(name, muted).await?;
+    Ok(true)
 }
 
 // Stop any currently playing video
-pub async fn stop_casting(device_name: Option<&str>) -> Result<bool> {
-    let device = device_name.unwrap_or("default device");
-    info!("Stopping casting on {}", device);
+pub async fn stop_casting(target: &CastTarget) -> Result<bool> {
+    let (backend, name) = resolve(target.device.as_ref())?;
+    info!("Stopping casting on {}", name);
+    backend.stop(name).await?;
 
-    // In a real implementation, this would stop the current cast
+    if let Some((group_backend, group)) = resolve_audio_group(target) {
+        if let Err(e) = group_backend.stop(group).await {
+            warn!("Failed to stop speaker group {}: {}", group, e);
+        }
+    }
     Ok(true)
 }
@@ -0,0 +1,332 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use rand::Rng;
+use reqwest::Method;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use super::{CastBackend, DiscoveredDevice, LoadFailed, MediaStatus, NowPlaying, PlayerState};
+use crate::youtube::VideoInfo;
+use crate::ytdlp;
+
+// mDNS service type Apple TVs advertise themselves under
+const AIRPLAY_SERVICE: &str = "_airplay._tcp.local.";
+
+// How long to listen for mDNS responses when discovering devices
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long to wait for an Apple TV to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bit in the TXT "features" entry that's set on devices that can play video
+const VIDEO_FEATURE: u64 = 1;
+
+// An Apple TV found with Bonjour
+#[derive(Debug, Clone)]
+struct AppleTv {
+    name: String,
+    address: SocketAddr,
+}
+
+// Apple TVs, controlled with the AirPlay HTTP video API
+pub struct AirPlay {
+    client: reqwest::Client,
+    session_id: String, // Sent with every request so status calls see our playback
+    devices: Mutex<HashMap<String, AppleTv>>, // Found Apple TVs keyed by name
+    started: Mutex<HashSet<String>>, // Apple TVs we told to play and haven't stopped
+}
+
+impl AirPlay {
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let session_id = format!(
+            "{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+            rng.gen::<u32>(),
+            rng.gen::<u16>(),
+            rng.gen::<u16>(),
+            rng.gen::<u16>(),
+            rng.gen::<u64>() & 0xFFFF_FFFF_FFFF
+        );
+
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            session_id,
+            devices: Mutex::new(HashMap::new()),
+            started: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Search the network for Apple TVs and remember them for later commands
+    async fn discover_devices(&self) -> Result<Vec<AppleTv>> {
+        let mdns =
+            ServiceDaemon::new().map_err(|e| anyhow!("Failed to start mDNS daemon: {}", e))?;
+        let receiver = mdns
+            .browse(AIRPLAY_SERVICE)
+            .map_err(|e| anyhow!("Failed to browse for Apple TVs: {}", e))?;
+
+        let mut devices: Vec<AppleTv> = Vec::new();
+        let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+
+        while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+
+            // HomePods and AirPlay speakers advertise the same service but can't show video
+            if !plays_video(&info) {
+                continue;
+            }
+
+            let Some(device) = device_from_service_info(&info) else {
+                continue;
+            };
+
+            if !devices.iter().any(|known| known.name == device.name) {
+                info!("Discovered Apple TV {} at {}", device.name, device.address);
+                devices.push(device);
+            }
+        }
+
+        if let Err(e) = mdns.shutdown() {
+            error!("Failed to shut down mDNS daemon: {}", e);
+        }
+
+        let mut known = self.devices.lock().await;
+        for device in &devices {
+            known.insert(device.name.clone(), device.clone());
+        }
+
+        Ok(devices)
+    }
+
+    // Find an Apple TV by name, searching the network again if it isn't known yet
+    async fn device(&self, device: &str) -> Result<AppleTv> {
+        if let Some(apple_tv) = self.devices.lock().await.get(device) {
+            return Ok(apple_tv.clone());
+        }
+
+        self.discover_devices()
+            .await?
+            .into_iter()
+            .find(|apple_tv| apple_tv.name.eq_ignore_ascii_case(device))
+            .ok_or_else(|| anyhow!("Apple TV {} wasn't found on the network", device))
+    }
+
+    // Send a request to an Apple TV and return the response body
+    async fn request(
+        &self,
+        device: &str,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<String> {
+        let apple_tv = self.device(device).await?;
+        let url = format!("http://{}{}", apple_tv.address, path);
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("X-Apple-Session-ID", &self.session_id)
+            .header("User-Agent", "MediaControl/1.0");
+        if let Some(body) = body {
+            request = request.header("Content-Type", "text/parameters").body(body);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        // Newer Apple TVs only accept paired devices unless AirPlay is open to everyone
+        if status == reqwest::StatusCode::FORBIDDEN || status.as_u16() == 470 {
+            return Err(anyhow!(
+                "{} refused the connection. Allow AirPlay for \"Everyone\" in its settings",
+                device
+            ));
+        }
+        if !status.is_success() {
+            return Err(anyhow!("{} {} failed: {}", device, path, status));
+        }
+
+        Ok(response.text().await?)
+    }
+
+    // Set the playback speed, 0 pauses and 1 plays
+    async fn set_rate(&self, device: &str, rate: f64) -> Result<()> {
+        self.request(
+            device,
+            Method::POST,
+            &format!("/rate?value={:.6}", rate),
+            None,
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[async_trait]
+impl CastBackend for AirPlay {
+    fn id(&self) -> &'static str {
+        "airplay"
+    }
+
+    fn label(&self) -> &'static str {
+        "AirPlay"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        let devices = self.discover_devices().await?;
+        Ok(devices
+            .into_iter()
+            .map(|device| DiscoveredDevice {
+                name: device.name,
+                audio_only: false,
+            })
+            .collect())
+    }
+
+    async fn connect(&self, device: &str) -> Result<()> {
+        self.device(device).await.map(|_| ())
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        _now_playing: &NowPlaying,
+    ) -> Result<()> {
+        // Apple TVs play the video file itself, not the page it's on
+        let stream_url = ytdlp::stream_url(&video_info.url)
+            .await
+            .map_err(|e| LoadFailed {
+                reason: format!("Couldn't find a stream the Apple TV can play: {}", e),
+            })?;
+
+        // AirPlay takes where to start as a fraction of the video, so it needs the length
+        let start_position = match (video_info.start_time, video_info.duration) {
+            (Some(start), Some(duration)) if start < duration => start as f64 / duration as f64,
+            _ => 0.0,
+        };
+
+        self.request(
+            device,
+            Method::POST,
+            "/play",
+            Some(format!(
+                "Content-Location: {}\nStart-Position: {}\n",
+                stream_url, start_position
+            )),
+        )
+        .await?;
+
+        self.started.lock().await.insert(device.to_string());
+        Ok(())
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        self.set_rate(device, 0.0).await
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.set_rate(device, 1.0).await
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        self.request(
+            device,
+            Method::POST,
+            &format!("/scrub?position={}", position),
+            None,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    // The AirPlay video API has no volume control, it follows the TV's own remote
+    async fn set_volume(&self, device: &str, _level: u8) -> Result<()> {
+        Err(anyhow!(
+            "{} doesn't support changing the volume over AirPlay",
+            device
+        ))
+    }
+
+    async fn set_muted(&self, device: &str, _muted: bool) -> Result<()> {
+        Err(anyhow!("{} doesn't support muting over AirPlay", device))
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        self.started.lock().await.remove(device);
+        self.request(device, Method::POST, "/stop", None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        let info = self
+            .request(device, Method::GET, "/playback-info", None)
+            .await?;
+        let info = roxmltree::Document::parse(&info)?;
+
+        let duration = plist_number(&info, "duration").filter(|duration| *duration > 0.0);
+        let position = plist_number(&info, "position").unwrap_or(0.0);
+        let rate = plist_number(&info, "rate").unwrap_or(0.0);
+
+        // The Apple TV drops back to an empty status once the video is over
+        let player_state = match duration {
+            None => PlayerState::Idle,
+            Some(_) if rate == 0.0 => PlayerState::Paused,
+            Some(_) => PlayerState::Playing,
+        };
+        let finished =
+            player_state == PlayerState::Idle && self.started.lock().await.contains(device);
+
+        Ok(MediaStatus {
+            player_state,
+            position: position.max(0.0) as u64,
+            duration: duration.map(|duration| duration as u64),
+            finished,
+            error: None,
+        })
+    }
+}
+
+// Whether an AirPlay device can show video, from the TXT "features" bitfield, e.g. "0x5A7FFFF7,0x1E"
+fn plays_video(info: &ServiceInfo) -> bool {
+    info.get_property_val_str("features")
+        .and_then(|features| features.split(',').next())
+        .and_then(|features| u64::from_str_radix(features.trim_start_matches("0x"), 16).ok())
+        .map(|features| features & VIDEO_FEATURE != 0)
+        .unwrap_or(false)
+}
+
+// Build device info from an mDNS record, using the instance name as the friendly name
+fn device_from_service_info(info: &ServiceInfo) -> Option<AppleTv> {
+    let name = info
+        .get_fullname()
+        .trim_end_matches(AIRPLAY_SERVICE)
+        .trim_end_matches('.')
+        .to_string();
+
+    let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+
+    addresses.first().map(|address| AppleTv {
+        name,
+        address: SocketAddr::new(*address, info.get_port()),
+    })
+}
+
+// Read a number from a playback-info property list, where each <key> is followed by its value
+fn plist_number(document: &roxmltree::Document, key: &str) -> Option<f64> {
+    document
+        .descendants()
+        .find(|node| node.has_tag_name("key") && node.text() == Some(key))
+        .and_then(|node| node.next_sibling_element())
+        .and_then(|value| value.text())
+        .and_then(|value| value.trim().parse().ok())
+}
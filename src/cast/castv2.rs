@@ -0,0 +1,409 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_native_tls::TlsStream;
+
+// The Google Cast protocol: JSON messages wrapped in a small protobuf envelope,
+// each prefixed with its length, over TLS to port 8009
+
+pub const CONNECTION_NAMESPACE: &str = "urn:x-cast:com.google.cast.tp.connection";
+pub const HEARTBEAT_NAMESPACE: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+pub const RECEIVER_NAMESPACE: &str = "urn:x-cast:com.google.cast.receiver";
+pub const MEDIA_NAMESPACE: &str = "urn:x-cast:com.google.cast.media";
+
+// Who messages go to when they're for the device itself rather than an app
+pub const RECEIVER_ID: &str = "receiver-0";
+
+// Who we are in every message we send
+const SENDER_ID: &str = "sender-0";
+
+// How long to wait when opening a connection to a device
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long to wait for a device to answer a request. Loading a video can take a while.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Biggest message we accept, the protocol's own limit
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// One message, with the fields of the protobuf CastMessage we use
+#[derive(Debug)]
+struct CastMessage {
+    source: String,
+    destination: String,
+    namespace: String,
+    payload: String,
+}
+
+impl CastMessage {
+    // Encode as the protobuf CastMessage: protocol version 0 (CASTV2_1_0) and a UTF-8 payload
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0x08, 0x00];
+        put_string(&mut bytes, 2, &self.source);
+        put_string(&mut bytes, 3, &self.destination);
+        put_string(&mut bytes, 4, &self.namespace);
+        bytes.extend_from_slice(&[0x28, 0x00]);
+        put_string(&mut bytes, 6, &self.payload);
+        bytes
+    }
+
+    // Decode a protobuf CastMessage, skipping fields we don't use such as binary payloads
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut message = CastMessage {
+            source: String::new(),
+            destination: String::new(),
+            namespace: String::new(),
+            payload: String::new(),
+        };
+
+        while !bytes.is_empty() {
+            let tag = take_varint(&mut bytes)?;
+            match tag & 0x7 {
+                0 => {
+                    take_varint(&mut bytes)?;
+                }
+                1 => {
+                    bytes = bytes
+                        .get(8..)
+                        .ok_or_else(|| anyhow!("Truncated cast message"))?
+                }
+                2 => {
+                    let length = take_varint(&mut bytes)? as usize;
+                    let value = bytes
+                        .get(..length)
+                        .ok_or_else(|| anyhow!("Truncated cast message"))?;
+                    let text = || String::from_utf8_lossy(value).to_string();
+                    match tag >> 3 {
+                        2 => message.source = text(),
+                        3 => message.destination = text(),
+                        4 => message.namespace = text(),
+                        6 => message.payload = text(),
+                        _ => {}
+                    }
+                    bytes = &bytes[length..];
+                }
+                5 => {
+                    bytes = bytes
+                        .get(4..)
+                        .ok_or_else(|| anyhow!("Truncated cast message"))?
+                }
+                wire_type => return Err(anyhow!("Unknown protobuf wire type {}", wire_type)),
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+// Append a length-delimited protobuf field
+fn put_string(bytes: &mut Vec<u8>, field: u64, value: &str) {
+    put_varint(bytes, (field << 3) | 2);
+    put_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn take_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated cast message"))?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Malformed varint in cast message"))
+}
+
+// A live connection to a cast device. A background task reads everything the
+// device sends, answering its heartbeats and handing replies to whoever asked.
+pub struct CastChannel {
+    pub address: SocketAddr,
+    pub connected_at: Instant,
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: Mutex<HashMap<u32, oneshot::Sender<Value>>>, // Replies waited for, by requestId
+    next_request_id: AtomicU32,
+    last_message: Mutex<Instant>, // When the device last sent anything
+    closed: AtomicBool,
+    connected_apps: Mutex<HashSet<String>>, // Transport ids of apps we've connected to
+    reader: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl CastChannel {
+    // Connect to a device and open the virtual connection to its receiver
+    pub async fn open(address: SocketAddr) -> Result<Arc<Self>> {
+        let tcp = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to {}", address))??;
+
+        // Cast devices present a certificate signed by Google for the device, not a
+        // hostname, so there's nothing to check it against here
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()?;
+        let tls = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            tokio_native_tls::TlsConnector::from(connector).connect(&address.ip().to_string(), tcp),
+        )
+        .await
+        .map_err(|_| anyhow!("Timed out starting TLS with {}", address))??;
+
+        let (reader, writer) = tokio::io::split(tls);
+        let now = Instant::now();
+        let channel = Arc::new(Self {
+            address,
+            connected_at: now,
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            next_request_id: AtomicU32::new(1),
+            last_message: Mutex::new(now),
+            closed: AtomicBool::new(false),
+            connected_apps: Mutex::new(HashSet::new()),
+            reader: Mutex::new(None),
+        });
+
+        let task = tokio::spawn(read_messages(Arc::downgrade(&channel), reader));
+        if let Ok(mut handle) = channel.reader.lock() {
+            *handle = Some(task);
+        }
+
+        channel
+            .send(
+                CONNECTION_NAMESPACE,
+                RECEIVER_ID,
+                json!({ "type": "CONNECT" }),
+            )
+            .await?;
+        Ok(channel)
+    }
+
+    // Whether the device is still there: the connection is open and it sent
+    // something, at least its own heartbeat, within `timeout`
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        !self.closed.load(Ordering::SeqCst)
+            && self
+                .last_message
+                .lock()
+                .is_ok_and(|last| last.elapsed() < timeout)
+    }
+
+    // How long ago the device last sent anything
+    pub fn silent_for(&self) -> Duration {
+        self.last_message
+            .lock()
+            .map(|last| last.elapsed())
+            .unwrap_or_default()
+    }
+
+    // Send a heartbeat, which the device answers with PONG
+    pub async fn ping(&self) -> Result<()> {
+        self.send(HEARTBEAT_NAMESPACE, RECEIVER_ID, json!({ "type": "PING" }))
+            .await
+    }
+
+    // Send a message without waiting for an answer
+    pub async fn send(&self, namespace: &str, destination: &str, payload: Value) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(anyhow!("The connection to {} is closed", self.address));
+        }
+
+        let bytes = CastMessage {
+            source: SENDER_ID.to_string(),
+            destination: destination.to_string(),
+            namespace: namespace.to_string(),
+            payload: payload.to_string(),
+        }
+        .encode();
+
+        let mut writer = self.writer.lock().await;
+        let result = async {
+            writer
+                .write_all(&(bytes.len() as u32).to_be_bytes())
+                .await?;
+            writer.write_all(&bytes).await?;
+            writer.flush().await
+        }
+        .await;
+
+        if let Err(e) = result {
+            self.close();
+            return Err(anyhow!("Failed to send to {}: {}", self.address, e));
+        }
+        Ok(())
+    }
+
+    // Send a request and wait for the device's reply to it
+    pub async fn request(
+        &self,
+        namespace: &str,
+        destination: &str,
+        payload: Value,
+    ) -> Result<Value> {
+        let Value::Object(mut payload) = payload else {
+            return Err(anyhow!("Cast requests must be JSON objects"));
+        };
+        let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        payload.insert("requestId".to_string(), Value::from(request_id));
+
+        let (reply_sender, reply) = oneshot::channel();
+        self.pending
+            .lock()
+            .map_err(|_| anyhow!("Cast connection lock poisoned"))?
+            .insert(request_id, reply_sender);
+
+        let result = async {
+            self.send(namespace, destination, Value::Object(payload))
+                .await?;
+            match tokio::time::timeout(REQUEST_TIMEOUT, reply).await {
+                Ok(Ok(reply)) => Ok(reply),
+                Ok(Err(_)) => Err(anyhow!("Lost the connection to {}", self.address)),
+                Err(_) => Err(anyhow!("{} didn't answer in time", self.address)),
+            }
+        }
+        .await;
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&request_id);
+        }
+        result
+    }
+
+    // Open the virtual connection to a running app, needed before sending it media commands
+    pub async fn connect_app(&self, transport_id: &str) -> Result<()> {
+        let connected = self
+            .connected_apps
+            .lock()
+            .is_ok_and(|apps| apps.contains(transport_id));
+        if connected {
+            return Ok(());
+        }
+
+        self.send(
+            CONNECTION_NAMESPACE,
+            transport_id,
+            json!({ "type": "CONNECT" }),
+        )
+        .await?;
+        if let Ok(mut apps) = self.connected_apps.lock() {
+            apps.insert(transport_id.to_string());
+        }
+        Ok(())
+    }
+
+    // Mark the connection as gone and fail every request still waiting
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.clear();
+        }
+    }
+
+    // Handle one message from the device
+    async fn receive(&self, message: CastMessage) {
+        if let Ok(mut last) = self.last_message.lock() {
+            *last = Instant::now();
+        }
+
+        let Ok(payload) = serde_json::from_str::<Value>(&message.payload) else {
+            warn!(
+                "Ignoring a message from {} that isn't JSON on {}",
+                self.address, message.namespace
+            );
+            return;
+        };
+        let kind = payload["type"].as_str().unwrap_or_default();
+
+        match (message.namespace.as_str(), kind) {
+            (HEARTBEAT_NAMESPACE, "PING") => {
+                let pong = self
+                    .send(
+                        HEARTBEAT_NAMESPACE,
+                        &message.source,
+                        json!({ "type": "PONG" }),
+                    )
+                    .await;
+                if let Err(e) = pong {
+                    warn!("Failed to answer a heartbeat from {}: {}", self.address, e);
+                }
+            }
+            (CONNECTION_NAMESPACE, "CLOSE") => {
+                if message.source == RECEIVER_ID {
+                    info!("{} closed the connection", self.address);
+                    self.close();
+                } else if let Ok(mut apps) = self.connected_apps.lock() {
+                    // The app stopped, so a new one needs connecting to
+                    apps.remove(&message.source);
+                }
+            }
+            _ => {
+                let request_id = payload["requestId"].as_u64().unwrap_or(0) as u32;
+                let waiting = self
+                    .pending
+                    .lock()
+                    .ok()
+                    .and_then(|mut pending| pending.remove(&request_id));
+                if let Some(waiting) = waiting {
+                    let _ = waiting.send(payload);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CastChannel {
+    fn drop(&mut self) {
+        if let Ok(mut reader) = self.reader.lock() {
+            if let Some(reader) = reader.take() {
+                reader.abort();
+            }
+        }
+    }
+}
+
+// Read messages until the device hangs up or the connection is dropped
+async fn read_messages(channel: Weak<CastChannel>, mut reader: ReadHalf<TlsStream<TcpStream>>) {
+    let result: Result<()> = async {
+        loop {
+            let length = reader.read_u32().await? as usize;
+            if length > MAX_MESSAGE_SIZE {
+                return Err(anyhow!("Cast message of {} bytes is too big", length));
+            }
+            let mut bytes = vec![0; length];
+            reader.read_exact(&mut bytes).await?;
+            let message = CastMessage::decode(&bytes)?;
+
+            match channel.upgrade() {
+                Some(channel) => channel.receive(message).await,
+                None => return Ok(()),
+            }
+        }
+    }
+    .await;
+
+    if let Some(channel) = channel.upgrade() {
+        if let Err(e) = result {
+            warn!("Connection to {} dropped: {}", channel.address, e);
+        }
+        channel.close();
+    }
+}
@@ -0,0 +1,1065 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::castv2::{CastChannel, MEDIA_NAMESPACE, RECEIVER_ID, RECEIVER_NAMESPACE};
+use super::{
+    CastBackend, DiscoveredDevice, LoadFailed, MediaStatus, NowPlaying, PlayerState, VolumeStatus,
+    DEFAULT_DEVICE,
+};
+use crate::source::VideoSource;
+use crate::youtube::VideoInfo;
+use crate::ytdlp;
+
+// Chromecasts on the local network, controlled over the Cast protocol
+pub struct Chromecast;
+
+#[async_trait]
+impl CastBackend for Chromecast {
+    fn id(&self) -> &'static str {
+        "chromecast"
+    }
+
+    fn label(&self) -> &'static str {
+        "Chromecast"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        let devices = discover_chromecasts().await?;
+        Ok(devices
+            .into_iter()
+            .map(|device| DiscoveredDevice {
+                name: device.name,
+                audio_only: device.audio_only,
+            })
+            .collect())
+    }
+
+    async fn connect(&self, device: &str) -> Result<()> {
+        ensure_connected(device).await
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        now_playing: &NowPlaying,
+    ) -> Result<()> {
+        let channel = connection(device).await?;
+        let session = match play_queued(&channel, device, video_info).await {
+            Some(session) => session,
+            None => load_video(&channel, video_info, now_playing).await?,
+        };
+
+        if let Ok(mut loaded) = LOADED_MEDIA.lock() {
+            loaded.insert(
+                device.to_string(),
+                LoadedMedia {
+                    session,
+                    duration: video_info.duration,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        let channel = connection(device).await?;
+        media_command(&channel, json!({ "type": "PAUSE" })).await
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        let channel = connection(device).await?;
+        media_command(&channel, json!({ "type": "PLAY" })).await
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        let channel = connection(device).await?;
+        media_command(&channel, json!({ "type": "SEEK", "currentTime": position })).await
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        let channel = connection(device).await?;
+        set_receiver_volume(&channel, json!({ "level": f64::from(level) / 100.0 })).await
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        let channel = connection(device).await?;
+        set_receiver_volume(&channel, json!({ "muted": muted })).await
+    }
+
+    // Close the app playing the video, so the TV goes back to its home screen
+    async fn stop(&self, device: &str) -> Result<()> {
+        forget_loaded_media(device);
+
+        let channel = connection(device).await?;
+        let Some(app) = running_app(&receiver_status(&channel).await?) else {
+            return Ok(());
+        };
+
+        let reply = channel
+            .request(
+                RECEIVER_NAMESPACE,
+                RECEIVER_ID,
+                json!({ "type": "STOP", "sessionId": app.session_id }),
+            )
+            .await?;
+        match reply["type"].as_str() {
+            Some("RECEIVER_STATUS") => Ok(()),
+            kind => Err(anyhow!(
+                "The device wouldn't stop playing ({})",
+                kind.unwrap_or("no answer")
+            )),
+        }
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        let loaded = LOADED_MEDIA
+            .lock()
+            .map_err(|_| anyhow!("Cast state is unavailable"))?
+            .get(device)
+            .copied();
+        let channel = connection(device).await?;
+        let current = current_media(&channel).await?.map(|(_, status)| status);
+
+        Ok(match (loaded, current) {
+            // Nothing the bot loaded, e.g. before the first song
+            (None, _) => MediaStatus {
+                player_state: PlayerState::Idle,
+                position: 0,
+                duration: None,
+                finished: false,
+                error: None,
+            },
+            (Some(loaded), Some(status)) if loaded.is_session(&status) => {
+                media_status_from(&status, loaded.duration)
+            }
+            // The app closed or something else was loaded since, so the video is over
+            (Some(loaded), _) => MediaStatus {
+                player_state: PlayerState::Idle,
+                position: loaded.duration.unwrap_or(0),
+                duration: loaded.duration,
+                finished: true,
+                error: None,
+            },
+        })
+    }
+
+    // Add the song to the queue the default media receiver keeps, behind the one
+    // playing, so it buffers before its turn. The YouTube app plays one video at a
+    // time, so nothing is queued while it's playing.
+    async fn preload(&self, device: &str, video_info: &VideoInfo) -> Result<bool> {
+        let Some(loaded) = loaded_media(device) else {
+            return Ok(false);
+        };
+        let channel = connection(device).await?;
+        let Some((app, status)) = current_media(&channel).await? else {
+            return Ok(false);
+        };
+        // The song ended already, or it's playing in the YouTube app
+        if app.app_id != DEFAULT_MEDIA_APP_ID || !loaded.is_session(&status) {
+            return Ok(false);
+        }
+
+        let queued = queued_next(device);
+        if queued
+            .as_ref()
+            .is_some_and(|queued| queued.video_id == video_info.id)
+        {
+            return Ok(true);
+        }
+        // The next song changed since it was queued
+        if let Some(queued) = queued {
+            forget_queued_next(device);
+            media_request(
+                &channel,
+                &app.transport_id,
+                &status,
+                json!({ "type": "QUEUE_REMOVE", "itemIds": [queued.item_id] }),
+            )
+            .await?;
+        }
+
+        let watch_url = video_info.source.watch_url(&video_info.id);
+        let stream_url = ytdlp::stream_url(&watch_url).await?;
+        let reply = media_request(
+            &channel,
+            &app.transport_id,
+            &status,
+            json!({
+                "type": "QUEUE_INSERT",
+                "items": [{
+                    "media": {
+                        "contentId": stream_url,
+                        "contentType": "video/mp4",
+                        "streamType": "BUFFERED",
+                        "metadata": video_metadata(video_info),
+                    },
+                    "autoplay": true,
+                    "preloadTime": PRELOAD_TIME,
+                    "startTime": video_info.start_time.unwrap_or(0),
+                }],
+            }),
+        )
+        .await?;
+
+        // Item ids only go up, so the new one is the highest after the playing one
+        let current_item = status["currentItemId"].as_i64();
+        let item_id = reply["status"][0]["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item["itemId"].as_i64())
+            .filter(|item_id| Some(*item_id) != current_item)
+            .max()
+            .ok_or_else(|| anyhow!("The device didn't say where it queued the video"))?;
+
+        info!("Queued video {} next on {}", video_info.id, device);
+        if let Ok(mut queued) = QUEUED_NEXT.lock() {
+            queued.insert(
+                device.to_string(),
+                QueuedVideo {
+                    video_id: video_info.id.clone(),
+                    item_id,
+                },
+            );
+        }
+        Ok(true)
+    }
+
+    // Played in the default media receiver, which replaces the last video, so that
+    // one's end isn't watched for anymore
+    async fn play_announcement(&self, device: &str, audio_url: &str) -> Result<bool> {
+        forget_loaded_media(device);
+
+        let channel = connection(device).await?;
+        let transport_id = launch(&channel, DEFAULT_MEDIA_APP_ID).await?;
+        let media = json!({
+            "contentId": audio_url,
+            "contentType": "audio/mpeg",
+            "streamType": "BUFFERED",
+        });
+        load_media(&channel, &transport_id, media, 0).await?;
+
+        info!("Playing announcement on {}", device);
+        Ok(true)
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        let channel = connection(device).await?;
+        let status = receiver_status(&channel).await?;
+
+        let volume = &status["volume"];
+        let Some(level) = volume["level"].as_f64() else {
+            return Ok(None);
+        };
+        Ok(Some(VolumeStatus {
+            level: (level * 100.0).round().clamp(0.0, 100.0) as u8,
+            muted: volume["muted"].as_bool().unwrap_or(false),
+        }))
+    }
+}
+
+// Where a video plays on the device, None where the device didn't say
+#[derive(Debug, Clone, Copy)]
+struct MediaSession {
+    id: Option<i64>,
+    item_id: Option<i64>, // Its place in the receiver's queue
+}
+
+// The video the bot last loaded on a device
+#[derive(Debug, Clone, Copy)]
+struct LoadedMedia {
+    session: MediaSession,
+    duration: Option<u64>, // From the video info, for when the device doesn't know
+}
+
+impl LoadedMedia {
+    // Whether a media status from the device is about this video. Once the receiver
+    // moves on to a queued song the session stays the same but the item changes.
+    fn is_session(&self, status: &Value) -> bool {
+        let session_matches = self
+            .session
+            .id
+            .is_none_or(|id| status["mediaSessionId"].as_i64() == Some(id));
+        // Receivers without a queue don't report an item
+        let item_matches = match (self.session.item_id, status["currentItemId"].as_i64()) {
+            (Some(expected), Some(current)) => expected == current,
+            _ => true,
+        };
+        session_matches && item_matches
+    }
+}
+
+// A song queued on a device to play after the current one
+#[derive(Debug, Clone)]
+struct QueuedVideo {
+    video_id: String,
+    item_id: i64,
+}
+
+// How many seconds before the current song ends the receiver starts buffering the next
+const PRELOAD_TIME: u64 = 20;
+
+lazy_static! {
+    // Videos the bot loaded keyed by device name, so a video that ended can be
+    // told apart from one that hasn't started
+    static ref LOADED_MEDIA: Mutex<HashMap<String, LoadedMedia>> = Mutex::new(HashMap::new());
+
+    // Songs queued behind the current one keyed by device name
+    static ref QUEUED_NEXT: Mutex<HashMap<String, QueuedVideo>> = Mutex::new(HashMap::new());
+}
+
+fn loaded_media(device: &str) -> Option<LoadedMedia> {
+    LOADED_MEDIA.lock().ok()?.get(device).copied()
+}
+
+fn queued_next(device: &str) -> Option<QueuedVideo> {
+    QUEUED_NEXT.lock().ok()?.get(device).cloned()
+}
+
+fn forget_queued_next(device: &str) {
+    if let Ok(mut queued) = QUEUED_NEXT.lock() {
+        queued.remove(device);
+    }
+}
+
+// Stop watching for the end of the video loaded on a device, along with the song
+// queued behind it
+fn forget_loaded_media(device: &str) {
+    if let Ok(mut loaded) = LOADED_MEDIA.lock() {
+        loaded.remove(device);
+    }
+    forget_queued_next(device);
+}
+
+// Play the song queued behind the last one, if it's this video and still queued.
+// The receiver moves on to it by itself when the song before it ends.
+async fn play_queued(
+    channel: &CastChannel,
+    device: &str,
+    video_info: &VideoInfo,
+) -> Option<MediaSession> {
+    let queued = queued_next(device).filter(|queued| queued.video_id == video_info.id)?;
+    forget_queued_next(device);
+
+    match jump_to_item(channel, queued.item_id).await {
+        Ok(session) => {
+            info!("Playing queued video {} on {}", video_info.id, device);
+            Some(session)
+        }
+        Err(e) => {
+            warn!(
+                "Queued video {} is gone from {}, loading it again: {}",
+                video_info.id, device, e
+            );
+            None
+        }
+    }
+}
+
+// Make an item in the receiver's queue the one playing
+async fn jump_to_item(channel: &CastChannel, item_id: i64) -> Result<MediaSession> {
+    let (app, mut status) = current_media(channel)
+        .await?
+        .ok_or_else(|| anyhow!("Nothing is playing on the device"))?;
+
+    if status["currentItemId"].as_i64() != Some(item_id) {
+        let reply = media_request(
+            channel,
+            &app.transport_id,
+            &status,
+            json!({ "type": "QUEUE_UPDATE", "currentItemId": item_id }),
+        )
+        .await?;
+        status = reply["status"][0].clone();
+    }
+    if status["currentItemId"].as_i64() != Some(item_id) {
+        return Err(anyhow!("The device didn't move on to the queued video"));
+    }
+
+    Ok(MediaSession {
+        id: status["mediaSessionId"].as_i64(),
+        item_id: Some(item_id),
+    })
+}
+
+// Read a MEDIA_STATUS entry from the device
+fn media_status_from(status: &Value, duration: Option<u64>) -> MediaStatus {
+    let idle_reason = status["idleReason"].as_str();
+    let player_state = match status["playerState"].as_str() {
+        Some("PAUSED") => PlayerState::Paused,
+        Some("IDLE") => PlayerState::Idle,
+        _ => PlayerState::Playing, // PLAYING, BUFFERING and LOADING
+    };
+    let duration = status["media"]["duration"]
+        .as_f64()
+        .filter(|duration| *duration > 0.0)
+        .map(|duration| duration.round() as u64)
+        .or(duration);
+
+    MediaStatus {
+        player_state,
+        position: status["currentTime"].as_f64().unwrap_or(0.0).max(0.0) as u64,
+        duration,
+        // Stopped from the TV or replaced counts as over too, so the queue moves on
+        finished: player_state == PlayerState::Idle
+            && idle_reason.is_some_and(|reason| reason != "ERROR"),
+        error: (player_state == PlayerState::Idle && idle_reason == Some("ERROR"))
+            .then(|| "The device couldn't play the video".to_string()),
+    }
+}
+
+// Subtitle for the cast metadata, shown under the title on the TV
+fn metadata_subtitle(now_playing: &NowPlaying) -> String {
+    let mut subtitle = format!("Sung by {}", now_playing.singer);
+    if !now_playing.up_next.is_empty() {
+        subtitle.push_str(&format!(" | Up next: {}", now_playing.up_next.join(", ")));
+    }
+    subtitle
+}
+
+// App id of the YouTube receiver on Chromecasts
+const YOUTUBE_APP_ID: &str = "233637DE";
+
+// App id of the default media receiver
+const DEFAULT_MEDIA_APP_ID: &str = "CC1AD845";
+
+// Which receiver app plays videos on the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CastReceiver {
+    YouTube,      // The YouTube app, given the video id
+    DefaultMedia, // The default media receiver, given a link to the video file
+}
+
+// Receiver app from KARAOKE_CAST_RECEIVER, "youtube" (the default) or "default"
+fn cast_receiver() -> CastReceiver {
+    match env::var("KARAOKE_CAST_RECEIVER") {
+        Ok(receiver) if receiver.eq_ignore_ascii_case("default") => CastReceiver::DefaultMedia,
+        _ => CastReceiver::YouTube,
+    }
+}
+
+// How long a just-launched app gets to show up in the receiver status
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// An app running on a device
+struct RunningApp {
+    app_id: String,
+    session_id: String,   // Needed to stop it
+    transport_id: String, // Where its media commands go
+    plays_media: bool,    // Whether it takes media commands, unlike the home screen
+}
+
+// The device's receiver status: its running apps and volume
+async fn receiver_status(channel: &CastChannel) -> Result<Value> {
+    let reply = channel
+        .request(
+            RECEIVER_NAMESPACE,
+            RECEIVER_ID,
+            json!({ "type": "GET_STATUS" }),
+        )
+        .await?;
+    Ok(reply["status"].clone())
+}
+
+// The app running on the device, if any
+fn running_app(status: &Value) -> Option<RunningApp> {
+    let app = status["applications"].as_array()?.first()?;
+    Some(RunningApp {
+        app_id: app["appId"].as_str()?.to_string(),
+        session_id: app["sessionId"].as_str()?.to_string(),
+        transport_id: app["transportId"].as_str()?.to_string(),
+        plays_media: app["namespaces"].as_array().is_some_and(|namespaces| {
+            namespaces
+                .iter()
+                .any(|namespace| namespace["name"] == MEDIA_NAMESPACE)
+        }),
+    })
+}
+
+// The video playing on the device: the app playing it and the app's media status
+// for it. None if nothing is loaded.
+async fn current_media(channel: &CastChannel) -> Result<Option<(RunningApp, Value)>> {
+    let Some(app) = running_app(&receiver_status(channel).await?) else {
+        return Ok(None);
+    };
+    if !app.plays_media {
+        return Ok(None);
+    }
+
+    channel.connect_app(&app.transport_id).await?;
+    let reply = channel
+        .request(
+            MEDIA_NAMESPACE,
+            &app.transport_id,
+            json!({ "type": "GET_STATUS" }),
+        )
+        .await?;
+
+    let status = reply["status"]
+        .as_array()
+        .and_then(|statuses| statuses.first())
+        .cloned();
+    Ok(status.map(|status| (app, status)))
+}
+
+// Change the device's volume, which goes for whatever app is playing
+async fn set_receiver_volume(channel: &CastChannel, volume: Value) -> Result<()> {
+    let reply = channel
+        .request(
+            RECEIVER_NAMESPACE,
+            RECEIVER_ID,
+            json!({ "type": "SET_VOLUME", "volume": volume }),
+        )
+        .await?;
+    match reply["type"].as_str() {
+        Some("RECEIVER_STATUS") => Ok(()),
+        kind => Err(anyhow!(
+            "The device refused to change its volume ({})",
+            kind.unwrap_or("no answer")
+        )),
+    }
+}
+
+// Send a command like PAUSE to the video playing on the device
+async fn media_command(channel: &CastChannel, command: Value) -> Result<()> {
+    let (app, status) = current_media(channel)
+        .await?
+        .ok_or_else(|| anyhow!("Nothing is playing on the device"))?;
+    media_request(channel, &app.transport_id, &status, command).await?;
+    Ok(())
+}
+
+// Send a command for the media session in `status`, returning the device's
+// MEDIA_STATUS reply
+async fn media_request(
+    channel: &CastChannel,
+    transport_id: &str,
+    status: &Value,
+    mut command: Value,
+) -> Result<Value> {
+    command["mediaSessionId"] = status["mediaSessionId"].clone();
+
+    let reply = channel
+        .request(MEDIA_NAMESPACE, transport_id, command.clone())
+        .await?;
+    match reply["type"].as_str() {
+        Some("MEDIA_STATUS") => Ok(reply),
+        kind => Err(anyhow!(
+            "The device refused {} ({})",
+            command["type"].as_str().unwrap_or("the command"),
+            kind.unwrap_or("no answer")
+        )),
+    }
+}
+
+// Start a receiver app on the device, or use it if it's already running, and
+// connect to it. Returns where its media commands go.
+async fn launch(channel: &CastChannel, app_id: &str) -> Result<String> {
+    let running = running_app(&receiver_status(channel).await?);
+
+    let transport_id = match running {
+        Some(app) if app.app_id == app_id => app.transport_id,
+        _ => {
+            let reply = channel
+                .request(
+                    RECEIVER_NAMESPACE,
+                    RECEIVER_ID,
+                    json!({ "type": "LAUNCH", "appId": app_id }),
+                )
+                .await?;
+            if reply["type"] == "LAUNCH_ERROR" {
+                return Err(anyhow!(
+                    "The device couldn't start app {}: {}",
+                    app_id,
+                    reply["reason"].as_str().unwrap_or("no reason given")
+                ));
+            }
+
+            // The app can take a moment to show up after the launch is accepted
+            let deadline = Instant::now() + LAUNCH_TIMEOUT;
+            let mut status = reply["status"].clone();
+            loop {
+                match running_app(&status) {
+                    Some(app) if app.app_id == app_id => break app.transport_id,
+                    _ if Instant::now() >= deadline => {
+                        return Err(anyhow!("App {} didn't start on the device", app_id))
+                    }
+                    _ => {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        status = receiver_status(channel).await?;
+                    }
+                }
+            }
+        }
+    };
+
+    channel.connect_app(&transport_id).await?;
+    Ok(transport_id)
+}
+
+// LOAD media into a running app, returning the media session the device made for
+// it. A device that refuses it won't change its mind, so that's a LoadFailed.
+async fn load_media(
+    channel: &CastChannel,
+    transport_id: &str,
+    media: Value,
+    start: u64,
+) -> Result<MediaSession> {
+    let reply = channel
+        .request(
+            MEDIA_NAMESPACE,
+            transport_id,
+            json!({
+                "type": "LOAD",
+                "media": media,
+                "autoplay": true,
+                "currentTime": start,
+            }),
+        )
+        .await?;
+
+    match reply["type"].as_str() {
+        Some("MEDIA_STATUS") => Ok(MediaSession {
+            id: reply["status"][0]["mediaSessionId"].as_i64(),
+            item_id: reply["status"][0]["currentItemId"].as_i64(),
+        }),
+        Some(kind) => Err(LoadFailed {
+            reason: format!(
+                "The device refused the video ({}{})",
+                kind,
+                reply["reason"]
+                    .as_str()
+                    .map(|reason| format!(": {}", reason))
+                    .unwrap_or_default()
+            ),
+        }
+        .into()),
+        None => Err(anyhow!("The device sent an answer to LOAD without a type")),
+    }
+}
+
+// Title and thumbnail shown with the video on receivers that show metadata
+fn video_metadata(video_info: &VideoInfo) -> Value {
+    let mut metadata = json!({
+        "metadataType": 0,
+        "title": video_info.title.clone().unwrap_or_else(|| video_info.id.clone()),
+    });
+    if let Some(thumbnail) = &video_info.thumbnail {
+        metadata["images"] = json!([{ "url": thumbnail }]);
+    }
+    metadata
+}
+
+// The video's metadata with who's singing and who's next as its subtitle
+fn media_metadata(video_info: &VideoInfo, now_playing: &NowPlaying) -> Value {
+    let mut metadata = video_metadata(video_info);
+    metadata["subtitle"] = json!(metadata_subtitle(now_playing));
+    metadata
+}
+
+// Load a video on the device, falling back to the default media receiver if the
+// YouTube app won't play it. Videos from other sites always go to the default
+// media receiver.
+async fn load_video(
+    channel: &CastChannel,
+    video_info: &VideoInfo,
+    now_playing: &NowPlaying,
+) -> Result<MediaSession> {
+    match cast_receiver() {
+        CastReceiver::YouTube if video_info.source != VideoSource::YouTube => {
+            load_in_default_receiver(channel, video_info, now_playing).await
+        }
+        CastReceiver::YouTube => {
+            match load_in_youtube_app(channel, video_info, now_playing).await {
+                Ok(session) => Ok(session),
+                Err(e) => {
+                    warn!(
+                    "YouTube receiver failed on {}, falling back to the default media receiver: {}",
+                    channel.address, e
+                );
+                    load_in_default_receiver(channel, video_info, now_playing).await
+                }
+            }
+        }
+        CastReceiver::DefaultMedia => {
+            load_in_default_receiver(channel, video_info, now_playing).await
+        }
+    }
+}
+
+// Play a video through the YouTube receiver app, which takes the video id
+async fn load_in_youtube_app(
+    channel: &CastChannel,
+    video_info: &VideoInfo,
+    now_playing: &NowPlaying,
+) -> Result<MediaSession> {
+    info!(
+        "Casting video {} to {} with the YouTube receiver ({})",
+        video_info.id, channel.address, YOUTUBE_APP_ID
+    );
+
+    let transport_id = launch(channel, YOUTUBE_APP_ID).await?;
+    let media = json!({
+        "contentId": video_info.id,
+        "contentType": "x-youtube/video",
+        "streamType": "BUFFERED",
+        "metadata": media_metadata(video_info, now_playing),
+    });
+    load_media(
+        channel,
+        &transport_id,
+        media,
+        video_info.start_time.unwrap_or(0),
+    )
+    .await
+}
+
+// Play a video in the default media receiver, which needs a link to the video file
+// itself, found with yt-dlp
+async fn load_in_default_receiver(
+    channel: &CastChannel,
+    video_info: &VideoInfo,
+    now_playing: &NowPlaying,
+) -> Result<MediaSession> {
+    let watch_url = video_info.source.watch_url(&video_info.id);
+    let stream_url = ytdlp::stream_url(&watch_url)
+        .await
+        .map_err(|e| LoadFailed {
+            reason: format!("Couldn't find a stream the Chromecast can play: {}", e),
+        })?;
+
+    info!(
+        "Casting video {} to {} with the default media receiver ({})",
+        video_info.id, channel.address, DEFAULT_MEDIA_APP_ID
+    );
+
+    let transport_id = launch(channel, DEFAULT_MEDIA_APP_ID).await?;
+    let media = json!({
+        "contentId": stream_url,
+        "contentType": "video/mp4",
+        "streamType": "BUFFERED",
+        "metadata": media_metadata(video_info, now_playing),
+    });
+    load_media(
+        channel,
+        &transport_id,
+        media,
+        video_info.start_time.unwrap_or(0),
+    )
+    .await
+}
+
+// mDNS service type Chromecasts advertise themselves under
+const CHROMECAST_SERVICE: &str = "_googlecast._tcp.local.";
+
+// How long to listen for mDNS responses when discovering devices
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// A Chromecast found on the local network
+#[derive(Debug, Clone)]
+pub struct CastDeviceInfo {
+    pub name: String,           // Friendly name, e.g. "Living Room TV"
+    pub addresses: Vec<IpAddr>, // IPv4 addresses first, then IPv6
+    pub port: u16,
+    pub audio_only: bool, // Speakers and speaker groups without a screen
+}
+
+// Port Chromecasts accept Cast connections on
+const DEFAULT_CAST_PORT: u16 = 8009;
+
+// Discover Chromecasts on the local network using mDNS, or use the devices
+// listed in KARAOKE_CAST_DEVICES without searching if it's set
+pub async fn discover_chromecasts() -> Result<Vec<CastDeviceInfo>> {
+    if let Ok(setting) = env::var("KARAOKE_CAST_DEVICES") {
+        return configured_devices(&setting).await;
+    }
+
+    let mdns = ServiceDaemon::new().map_err(|e| anyhow!("Failed to start mDNS daemon: {}", e))?;
+    let receiver = mdns
+        .browse(CHROMECAST_SERVICE)
+        .map_err(|e| anyhow!("Failed to browse for Chromecasts: {}", e))?;
+
+    let mut devices: Vec<CastDeviceInfo> = Vec::new();
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+
+    // Collect every device that resolves before the deadline
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let device = device_from_service_info(&info);
+
+            if !devices.iter().any(|known| known.name == device.name) {
+                info!(
+                    "Discovered Chromecast {} at {:?} port {}",
+                    device.name, device.addresses, device.port
+                );
+                devices.push(device);
+            }
+        }
+    }
+
+    if let Err(e) = mdns.shutdown() {
+        error!("Failed to shut down mDNS daemon: {}", e);
+    }
+
+    Ok(devices)
+}
+
+// Devices from KARAOKE_CAST_DEVICES, for networks that block mDNS.
+// Entries are separated by commas, e.g. "Living Room TV=192.168.1.20, Bar=bar-tv.lan:8009"
+async fn configured_devices(setting: &str) -> Result<Vec<CastDeviceInfo>> {
+    let mut devices: Vec<CastDeviceInfo> = Vec::new();
+
+    for entry in setting
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let Some((name, host)) = entry.split_once('=') else {
+            error!(
+                "Ignoring KARAOKE_CAST_DEVICES entry {}, expected name=host[:port]",
+                entry
+            );
+            continue;
+        };
+        let (name, host) = (name.trim(), host.trim());
+
+        // IPv6 addresses have colons of their own, so they need brackets with a port
+        let (host, port) = match host.rsplit_once(':') {
+            Some((address, port)) if !address.contains(':') || address.ends_with(']') => {
+                match port.parse() {
+                    Ok(port) => (address.trim_matches(['[', ']']), port),
+                    Err(_) => {
+                        error!("Ignoring cast device {}, invalid port {}", name, port);
+                        continue;
+                    }
+                }
+            }
+            _ => (host.trim_matches(['[', ']']), DEFAULT_CAST_PORT),
+        };
+
+        let mut addresses: Vec<IpAddr> = match host.parse::<IpAddr>() {
+            Ok(address) => vec![address],
+            Err(_) => match tokio::net::lookup_host((host, port)).await {
+                Ok(resolved) => resolved.map(|address| address.ip()).collect(),
+                Err(e) => {
+                    error!("Couldn't resolve cast device {} at {}: {}", name, host, e);
+                    continue;
+                }
+            },
+        };
+        addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+
+        if !devices.iter().any(|known| known.name == name) {
+            devices.push(CastDeviceInfo {
+                name: name.to_string(),
+                addresses,
+                port,
+                audio_only: false,
+            });
+        }
+    }
+
+    Ok(devices)
+}
+
+// Build device info from an mDNS record, using the TXT "fn" entry as the friendly name
+fn device_from_service_info(info: &ServiceInfo) -> CastDeviceInfo {
+    let name = info
+        .get_property_val_str("fn")
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| {
+            // Fall back to the instance name, e.g. "Chromecast-abc123._googlecast._tcp.local."
+            info.get_fullname()
+                .trim_end_matches(CHROMECAST_SERVICE)
+                .trim_end_matches('.')
+                .to_string()
+        });
+
+    let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+
+    CastDeviceInfo {
+        name,
+        addresses,
+        port: info.get_port(),
+        audio_only: is_audio_only(info),
+    }
+}
+
+// Model name speaker groups advertise in the TXT "md" entry
+const CAST_GROUP_MODEL: &str = "Google Cast Group";
+
+// Bit in the TXT "ca" capabilities that's set on devices with video output
+const VIDEO_OUT_CAPABILITY: u32 = 1;
+
+// Whether a device only plays audio, like a Nest speaker or a speaker group
+fn is_audio_only(info: &ServiceInfo) -> bool {
+    if info.get_property_val_str("md") == Some(CAST_GROUP_MODEL) {
+        return true;
+    }
+
+    info.get_property_val_str("ca")
+        .and_then(|capabilities| capabilities.parse::<u32>().ok())
+        .map(|capabilities| capabilities & VIDEO_OUT_CAPABILITY == 0)
+        .unwrap_or(false)
+}
+
+// How often a heartbeat is sent on each connection
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long a device can stay silent, heartbeats included, before its connection counts as dropped
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+lazy_static! {
+    // Live connections keyed by device name
+    static ref CAST_CONNECTIONS: Mutex<HashMap<String, Arc<CastChannel>>> =
+        Mutex::new(HashMap::new());
+
+    // One lock per device, held while connecting so two commands don't both discover
+    // and connect to the same device, without holding up commands for other devices
+    static ref CONNECTING: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Make sure there is a live connection to a device, connecting if needed
+pub async fn ensure_connected(device_name: &str) -> Result<()> {
+    connection(device_name).await.map(|_| ())
+}
+
+// The live connection to a device, connecting if there isn't one
+async fn connection(device_name: &str) -> Result<Arc<CastChannel>> {
+    if let Some(channel) = live_connection(device_name) {
+        return Ok(channel);
+    }
+
+    let connecting = CONNECTING
+        .lock()
+        .map_err(|_| anyhow!("Cast connection lock poisoned"))?
+        .entry(device_name.to_string())
+        .or_default()
+        .clone();
+    let _connecting = connecting.lock().await;
+
+    // Someone else may have connected while we waited
+    if let Some(channel) = live_connection(device_name) {
+        return Ok(channel);
+    }
+
+    let channel = connect(device_name).await?;
+    if let Ok(mut connections) = CAST_CONNECTIONS.lock() {
+        connections.insert(device_name.to_string(), channel.clone());
+    }
+    Ok(channel)
+}
+
+// The connection to a device if it's still alive
+fn live_connection(device_name: &str) -> Option<Arc<CastChannel>> {
+    CAST_CONNECTIONS
+        .lock()
+        .ok()?
+        .get(device_name)
+        .filter(|channel| channel.is_alive(HEARTBEAT_TIMEOUT))
+        .cloned()
+}
+
+// Find a device by name and open a connection to it. The default device is the
+// first Chromecast found.
+async fn connect(device_name: &str) -> Result<Arc<CastChannel>> {
+    let devices = discover_chromecasts().await?;
+    let device = if device_name == DEFAULT_DEVICE {
+        devices.into_iter().next().ok_or_else(|| {
+            anyhow!("No Chromecast was found on the network, choose a device with /castto")
+        })?
+    } else {
+        devices
+            .into_iter()
+            .find(|device| device.name.eq_ignore_ascii_case(device_name))
+            .ok_or_else(|| anyhow!("Cast device {} wasn't found on the network", device_name))?
+    };
+
+    let mut last_error = anyhow!("Cast device {} has no addresses", device.name);
+    for address in &device.addresses {
+        let address = SocketAddr::new(*address, device.port);
+
+        // Something answering on the port isn't enough, it has to speak the Cast protocol
+        let opened = async {
+            let channel = CastChannel::open(address).await?;
+            channel
+                .request(
+                    RECEIVER_NAMESPACE,
+                    RECEIVER_ID,
+                    json!({ "type": "GET_STATUS" }),
+                )
+                .await?;
+            Ok::<_, anyhow::Error>(channel)
+        }
+        .await;
+
+        match opened {
+            Ok(channel) => {
+                info!("Connected to {} at {}", device.name, address);
+                return Ok(channel);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(anyhow!(
+        "Couldn't connect to cast device {}: {}",
+        device_name,
+        last_error
+    ))
+}
+
+// Send heartbeats on every connection, reconnecting to devices that stopped
+// answering. Runs for the lifetime of the bot.
+pub async fn run_heartbeat() {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Copied out so commands aren't blocked while heartbeats are sent
+        let connections: Vec<(String, Arc<CastChannel>)> = match CAST_CONNECTIONS.lock() {
+            Ok(connections) => connections
+                .iter()
+                .map(|(name, channel)| (name.clone(), channel.clone()))
+                .collect(),
+            Err(_) => continue,
+        };
+
+        for (name, channel) in connections {
+            if channel.is_alive(HEARTBEAT_TIMEOUT) && channel.ping().await.is_ok() {
+                continue;
+            }
+
+            warn!(
+                "Lost connection to {} at {} after {}s (last heard from {}s ago), reconnecting",
+                name,
+                channel.address,
+                channel.connected_at.elapsed().as_secs(),
+                channel.silent_for().as_secs()
+            );
+
+            // Dropped first so the reconnect doesn't find the dead connection
+            if let Ok(mut connections) = CAST_CONNECTIONS.lock() {
+                connections.remove(&name);
+            }
+
+            // The device may have come back on a different address
+            if let Err(e) = connection(&name).await {
+                error!("Failed to reconnect to {}: {}", name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+use super::{
+    CastBackend, DiscoveredDevice, LoadFailed, MediaStatus, NowPlaying, PlayerState, VolumeStatus,
+};
+use crate::youtube::VideoInfo;
+use crate::ytdlp;
+
+// Where SSDP searches are sent
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+
+// Device type UPnP renderers advertise
+const MEDIA_RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+const RENDERING_CONTROL: &str = "urn:schemas-upnp-org:service:RenderingControl:1";
+
+// How long to listen for SSDP responses when discovering devices
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long to wait for a renderer to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A UPnP MediaRenderer found with SSDP
+#[derive(Debug, Clone)]
+struct Renderer {
+    name: String,
+    av_transport_url: Url,
+    rendering_control_url: Option<Url>, // Not every renderer lets us change the volume
+}
+
+// Smart TVs and speakers that speak DLNA, controlled with UPnP SOAP requests
+pub struct Dlna {
+    client: reqwest::Client,
+    renderers: Mutex<HashMap<String, Renderer>>, // Found renderers keyed by name
+    started: Mutex<HashSet<String>>,             // Renderers we told to play and haven't stopped
+}
+
+impl Dlna {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            renderers: Mutex::new(HashMap::new()),
+            started: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Search the network for renderers and remember them for later commands
+    async fn discover_renderers(&self) -> Result<Vec<Renderer>> {
+        let mut renderers = Vec::new();
+
+        for location in ssdp_search().await? {
+            match self.describe(&location).await {
+                Ok(renderer) => {
+                    if !renderers
+                        .iter()
+                        .any(|known: &Renderer| known.name == renderer.name)
+                    {
+                        info!("Discovered DLNA renderer {} at {}", renderer.name, location);
+                        renderers.push(renderer);
+                    }
+                }
+                Err(e) => error!("Failed to read DLNA device at {}: {}", location, e),
+            }
+        }
+
+        let mut known = self.renderers.lock().await;
+        for renderer in &renderers {
+            known.insert(renderer.name.clone(), renderer.clone());
+        }
+
+        Ok(renderers)
+    }
+
+    // Read a renderer's name and control URLs from its device description
+    async fn describe(&self, location: &Url) -> Result<Renderer> {
+        let description = self
+            .client
+            .get(location.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let document = roxmltree::Document::parse(&description)?;
+        let name = element_text(&document, "friendlyName")
+            .ok_or_else(|| anyhow!("Device description has no friendlyName"))?;
+
+        let mut av_transport_url = None;
+        let mut rendering_control_url = None;
+
+        for service in document
+            .descendants()
+            .filter(|node| node.has_tag_name("service"))
+        {
+            let child_text = |tag: &str| {
+                service
+                    .children()
+                    .find(|node| node.has_tag_name(tag))
+                    .and_then(|node| node.text())
+                    .map(|text| text.trim().to_string())
+            };
+
+            let (Some(service_type), Some(control_url)) =
+                (child_text("serviceType"), child_text("controlURL"))
+            else {
+                continue;
+            };
+            let control_url = location.join(&control_url)?;
+
+            if service_type == AV_TRANSPORT {
+                av_transport_url = Some(control_url);
+            } else if service_type == RENDERING_CONTROL {
+                rendering_control_url = Some(control_url);
+            }
+        }
+
+        Ok(Renderer {
+            name,
+            av_transport_url: av_transport_url
+                .ok_or_else(|| anyhow!("{} has no AVTransport service", location))?,
+            rendering_control_url,
+        })
+    }
+
+    // Find a renderer by name, searching the network again if it isn't known yet
+    async fn renderer(&self, device: &str) -> Result<Renderer> {
+        if let Some(renderer) = self.renderers.lock().await.get(device) {
+            return Ok(renderer.clone());
+        }
+
+        self.discover_renderers()
+            .await?
+            .into_iter()
+            .find(|renderer| renderer.name.eq_ignore_ascii_case(device))
+            .ok_or_else(|| anyhow!("DLNA renderer {} wasn't found on the network", device))
+    }
+
+    // Call an AVTransport action on a renderer
+    async fn av_transport(
+        &self,
+        device: &str,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<String> {
+        let renderer = self.renderer(device).await?;
+        self.soap(&renderer.av_transport_url, AV_TRANSPORT, action, arguments)
+            .await
+    }
+
+    // Call a RenderingControl action on a renderer
+    async fn rendering_control(
+        &self,
+        device: &str,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<String> {
+        let renderer = self.renderer(device).await?;
+        let url = renderer
+            .rendering_control_url
+            .ok_or_else(|| anyhow!("{} doesn't support volume control", device))?;
+        self.soap(&url, RENDERING_CONTROL, action, arguments).await
+    }
+
+    // Send a SOAP request and return the response body
+    async fn soap(
+        &self,
+        url: &Url,
+        service: &str,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<String> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, escape_xml(value)))
+            .collect();
+
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>"
+        );
+
+        let response = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{}\"", service, action))
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            let reason = roxmltree::Document::parse(&text)
+                .ok()
+                .and_then(|document| element_text(&document, "errorDescription"))
+                .unwrap_or_else(|| status.to_string());
+            return Err(anyhow!("{} failed: {}", action, reason));
+        }
+
+        Ok(text)
+    }
+}
+
+#[async_trait]
+impl CastBackend for Dlna {
+    fn id(&self) -> &'static str {
+        "dlna"
+    }
+
+    fn label(&self) -> &'static str {
+        "DLNA"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        let renderers = self.discover_renderers().await?;
+        Ok(renderers
+            .into_iter()
+            .map(|renderer| DiscoveredDevice {
+                name: renderer.name,
+                audio_only: false,
+            })
+            .collect())
+    }
+
+    async fn connect(&self, device: &str) -> Result<()> {
+        self.renderer(device).await.map(|_| ())
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        _now_playing: &NowPlaying,
+    ) -> Result<()> {
+        // Renderers play the video file itself, not the page it's on
+        let stream_url = ytdlp::stream_url(&video_info.url)
+            .await
+            .map_err(|e| LoadFailed {
+                reason: format!("Couldn't find a stream the TV can play: {}", e),
+            })?;
+
+        self.av_transport(
+            device,
+            "SetAVTransportURI",
+            &[
+                ("InstanceID", "0"),
+                ("CurrentURI", &stream_url),
+                ("CurrentURIMetaData", &didl_lite(video_info, &stream_url)),
+            ],
+        )
+        .await?;
+        self.av_transport(device, "Play", &[("InstanceID", "0"), ("Speed", "1")])
+            .await?;
+
+        // Renderers can't be told where to start, so jump there once it's playing.
+        // Not every renderer can seek, and the song is better from the start than not at all.
+        if let Some(start) = video_info.start_time {
+            if let Err(e) = self.seek(device, start).await {
+                warn!("Failed to start {} at {}s: {}", video_info.id, start, e);
+            }
+        }
+
+        self.started.lock().await.insert(device.to_string());
+        Ok(())
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        self.av_transport(device, "Pause", &[("InstanceID", "0")])
+            .await
+            .map(|_| ())
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.av_transport(device, "Play", &[("InstanceID", "0"), ("Speed", "1")])
+            .await
+            .map(|_| ())
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        let target = format!(
+            "{}:{:02}:{:02}",
+            position / 3600,
+            (position % 3600) / 60,
+            position % 60
+        );
+
+        self.av_transport(
+            device,
+            "Seek",
+            &[
+                ("InstanceID", "0"),
+                ("Unit", "REL_TIME"),
+                ("Target", &target),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        self.rendering_control(
+            device,
+            "SetVolume",
+            &[
+                ("InstanceID", "0"),
+                ("Channel", "Master"),
+                ("DesiredVolume", &level.to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        self.rendering_control(
+            device,
+            "SetMute",
+            &[
+                ("InstanceID", "0"),
+                ("Channel", "Master"),
+                ("DesiredMute", if muted { "1" } else { "0" }),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        self.started.lock().await.remove(device);
+        self.av_transport(device, "Stop", &[("InstanceID", "0")])
+            .await
+            .map(|_| ())
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        let transport = self
+            .av_transport(device, "GetTransportInfo", &[("InstanceID", "0")])
+            .await?;
+        let position = self
+            .av_transport(device, "GetPositionInfo", &[("InstanceID", "0")])
+            .await?;
+
+        let transport = roxmltree::Document::parse(&transport)?;
+        let position = roxmltree::Document::parse(&position)?;
+
+        // Renderers that can't open a URL stop with an error status instead of playing
+        let error = (element_text(&transport, "CurrentTransportStatus").as_deref()
+            == Some("ERROR_OCCURRED"))
+        .then(|| format!("{} couldn't play the video", device));
+
+        let player_state = match element_text(&transport, "CurrentTransportState").as_deref() {
+            Some("PLAYING") | Some("TRANSITIONING") => PlayerState::Playing,
+            Some("PAUSED_PLAYBACK") => PlayerState::Paused,
+            _ => PlayerState::Idle,
+        };
+
+        // Renderers don't say why they stopped, so a stop we didn't ask for means the video ended
+        let finished = player_state == PlayerState::Idle
+            && error.is_none()
+            && self.started.lock().await.contains(device);
+
+        Ok(MediaStatus {
+            player_state,
+            position: element_text(&position, "RelTime")
+                .and_then(|time| parse_time(&time))
+                .unwrap_or(0),
+            duration: element_text(&position, "TrackDuration").and_then(|time| parse_time(&time)),
+            finished,
+            error,
+        })
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        let arguments = [("InstanceID", "0"), ("Channel", "Master")];
+        let volume = self
+            .rendering_control(device, "GetVolume", &arguments)
+            .await?;
+        let mute = self
+            .rendering_control(device, "GetMute", &arguments)
+            .await?;
+
+        let level = element_text(&roxmltree::Document::parse(&volume)?, "CurrentVolume")
+            .and_then(|level| level.parse::<u8>().ok())
+            .ok_or_else(|| anyhow!("{} didn't report its volume", device))?;
+        let muted = element_text(&roxmltree::Document::parse(&mute)?, "CurrentMute")
+            .map(|muted| muted == "1" || muted.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        Ok(Some(VolumeStatus {
+            level: level.min(100),
+            muted,
+        }))
+    }
+}
+
+// Send an SSDP M-SEARCH for media renderers and collect the description URLs that answer
+async fn ssdp_search() -> Result<Vec<Url>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: {}\r\n\r\n",
+        SSDP_ADDRESS, MEDIA_RENDERER
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS).await?;
+
+    let mut locations: Vec<Url> = Vec::new();
+    let mut buffer = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+
+    // Collect every response that arrives before the deadline
+    while let Ok(Ok((length, _))) =
+        tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+    {
+        let response = String::from_utf8_lossy(&buffer[..length]);
+        let location = response.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+
+        if let Some(Ok(location)) = location.map(|location| Url::parse(&location)) {
+            if !locations.contains(&location) {
+                locations.push(location);
+            }
+        }
+    }
+
+    Ok(locations)
+}
+
+// Text of the first element with the given name, ignoring namespaces
+fn element_text(document: &roxmltree::Document, tag: &str) -> Option<String> {
+    document
+        .descendants()
+        .find(|node| node.tag_name().name() == tag)
+        .and_then(|node| node.text())
+        .map(|text| text.trim().to_string())
+}
+
+// Parse a UPnP time like "0:03:25" into seconds
+fn parse_time(time: &str) -> Option<u64> {
+    // Some renderers add fractions of a second, e.g. "0:03:25.500"
+    let time = time.split('.').next()?;
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+
+    let hours = parts.next()??;
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+// DIDL-Lite describing the video, since many TVs won't take a URI without it
+fn didl_lite(video_info: &VideoInfo, stream_url: &str) -> String {
+    let title = video_info.title.as_deref().unwrap_or(&video_info.id);
+    format!(
+        concat!(
+            r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" "#,
+            r#"xmlns:dc="http://purl.org/dc/elements/1.1/" "#,
+            r#"xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/">"#,
+            r#"<item id="0" parentID="-1" restricted="1">"#,
+            "<dc:title>{}</dc:title>",
+            "<upnp:class>object.item.videoItem</upnp:class>",
+            r#"<res protocolInfo="http-get:*:video/mp4:*">{}</res>"#,
+            "</item></DIDL-Lite>"
+        ),
+        escape_xml(title),
+        escape_xml(stream_url)
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
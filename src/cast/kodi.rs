@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, info};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use super::{CastBackend, DiscoveredDevice, MediaStatus, NowPlaying, PlayerState, VolumeStatus};
+use crate::source::VideoSource;
+use crate::youtube::VideoInfo;
+
+// Name shown in /devices when KARAOKE_KODI_NAME isn't set
+const DEFAULT_NAME: &str = "Kodi";
+
+// Port Kodi's web server listens on by default
+const DEFAULT_PORT: u16 = 8080;
+
+// How long to wait for Kodi to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long messages between songs stay on screen, in milliseconds
+const MESSAGE_DISPLAY_TIME: u64 = 10_000;
+
+// Where Kodi is and how to log in, from KARAOKE_KODI_HOST and friends
+#[derive(Debug, Clone)]
+struct KodiConfig {
+    name: String,
+    url: String, // JSON-RPC endpoint, e.g. http://192.168.1.30:8080/jsonrpc
+    user: Option<String>,
+    password: Option<String>,
+}
+
+// A Kodi media center, e.g. on a Raspberry Pi, playing videos through its YouTube,
+// Vimeo and Dailymotion addons
+pub struct Kodi {
+    client: reqwest::Client,
+    config: Option<KodiConfig>,      // None when no Kodi is configured
+    started: Mutex<HashSet<String>>, // Devices we told to play and haven't stopped
+}
+
+impl Kodi {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            config: kodi_config(),
+            started: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // The configured Kodi, if it's the device asked for
+    fn config(&self, device: &str) -> Result<&KodiConfig> {
+        match &self.config {
+            Some(config) if config.name.eq_ignore_ascii_case(device) => Ok(config),
+            Some(_) => Err(anyhow!("Unknown Kodi device {}", device)),
+            None => Err(anyhow!("No Kodi is configured, set KARAOKE_KODI_HOST")),
+        }
+    }
+
+    // Call a JSON-RPC method and return its result
+    async fn call(&self, device: &str, method: &str, params: Value) -> Result<Value> {
+        let config = self.config(device)?;
+
+        let mut request = self.client.post(&config.url).json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        }));
+        if let Some(user) = &config.user {
+            request = request.basic_auth(user, config.password.as_ref());
+        }
+
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(anyhow!(
+                "{} rejected the login, check KARAOKE_KODI_USER and KARAOKE_KODI_PASSWORD",
+                device
+            ));
+        }
+
+        let mut reply: Value = response.error_for_status()?.json().await?;
+        if let Some(e) = reply.get("error") {
+            return Err(anyhow!(
+                "{} {} failed: {}",
+                device,
+                method,
+                e["message"].as_str().unwrap_or("unknown error")
+            ));
+        }
+
+        Ok(reply["result"].take())
+    }
+
+    // The id of the player showing a video, if anything is playing
+    async fn video_player(&self, device: &str) -> Result<Option<u64>> {
+        let players = self
+            .call(device, "Player.GetActivePlayers", json!({}))
+            .await?;
+
+        Ok(players.as_array().and_then(|players| {
+            players
+                .iter()
+                .find(|player| player["type"] == "video")
+                .and_then(|player| player["playerid"].as_u64())
+        }))
+    }
+
+    // Call a method on the video player, failing if nothing is playing
+    async fn player_call(&self, device: &str, method: &str, mut params: Value) -> Result<Value> {
+        let player_id = self
+            .video_player(device)
+            .await?
+            .ok_or_else(|| anyhow!("Nothing is playing on {}", device))?;
+        params["playerid"] = json!(player_id);
+
+        self.call(device, method, params).await
+    }
+}
+
+#[async_trait]
+impl CastBackend for Kodi {
+    fn id(&self) -> &'static str {
+        "kodi"
+    }
+
+    fn label(&self) -> &'static str {
+        "Kodi"
+    }
+
+    // Kodi isn't searched for, it's offered when KARAOKE_KODI_HOST is set
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        Ok(self
+            .config
+            .iter()
+            .map(|config| DiscoveredDevice {
+                name: config.name.clone(),
+                audio_only: false,
+            })
+            .collect())
+    }
+
+    async fn connect(&self, device: &str) -> Result<()> {
+        self.call(device, "JSONRPC.Ping", json!({}))
+            .await
+            .map(|_| ())
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        _now_playing: &NowPlaying,
+    ) -> Result<()> {
+        let mut params = json!({ "item": { "file": plugin_url(video_info) } });
+        if let Some(start) = video_info.start_time {
+            params["options"] = json!({
+                "resume": {
+                    "hours": start / 3600,
+                    "minutes": start % 3600 / 60,
+                    "seconds": start % 60,
+                    "milliseconds": 0,
+                }
+            });
+        }
+
+        self.call(device, "Player.Open", params).await?;
+
+        self.started.lock().await.insert(device.to_string());
+        Ok(())
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        self.player_call(device, "Player.PlayPause", json!({ "play": false }))
+            .await
+            .map(|_| ())
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.player_call(device, "Player.PlayPause", json!({ "play": true }))
+            .await
+            .map(|_| ())
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        self.player_call(
+            device,
+            "Player.Seek",
+            json!({
+                "value": {
+                    "time": {
+                        "hours": position / 3600,
+                        "minutes": position % 3600 / 60,
+                        "seconds": position % 60,
+                        "milliseconds": 0,
+                    }
+                }
+            }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        self.call(device, "Application.SetVolume", json!({ "volume": level }))
+            .await
+            .map(|_| ())
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        self.call(device, "Application.SetMute", json!({ "mute": muted }))
+            .await
+            .map(|_| ())
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        self.started.lock().await.remove(device);
+
+        match self.video_player(device).await? {
+            Some(player_id) => self
+                .call(device, "Player.Stop", json!({ "playerid": player_id }))
+                .await
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        // Kodi closes the player once the video is over
+        let Some(player_id) = self.video_player(device).await? else {
+            return Ok(MediaStatus {
+                player_state: PlayerState::Idle,
+                position: 0,
+                duration: None,
+                finished: self.started.lock().await.contains(device),
+                error: None,
+            });
+        };
+
+        let properties = self
+            .call(
+                device,
+                "Player.GetProperties",
+                json!({
+                    "playerid": player_id,
+                    "properties": ["time", "totaltime", "speed"],
+                }),
+            )
+            .await?;
+
+        let player_state = if properties["speed"].as_i64() == Some(0) {
+            PlayerState::Paused
+        } else {
+            PlayerState::Playing
+        };
+
+        Ok(MediaStatus {
+            player_state,
+            position: seconds(&properties["time"]),
+            duration: Some(seconds(&properties["totaltime"])).filter(|duration| *duration > 0),
+            finished: false,
+            error: None,
+        })
+    }
+
+    async fn show_message(&self, device: &str, text: &str) -> Result<bool> {
+        self.call(
+            device,
+            "GUI.ShowNotification",
+            json!({
+                "title": "Karaoke",
+                "message": text,
+                "displaytime": MESSAGE_DISPLAY_TIME,
+            }),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        let properties = self
+            .call(
+                device,
+                "Application.GetProperties",
+                json!({ "properties": ["volume", "muted"] }),
+            )
+            .await?;
+
+        Ok(properties["volume"].as_u64().map(|level| VolumeStatus {
+            level: level.min(100) as u8,
+            muted: properties["muted"].as_bool().unwrap_or(false),
+        }))
+    }
+}
+
+// Kodi's connection details from KARAOKE_KODI_HOST, KARAOKE_KODI_PORT,
+// KARAOKE_KODI_USER, KARAOKE_KODI_PASSWORD and KARAOKE_KODI_NAME
+fn kodi_config() -> Option<KodiConfig> {
+    let host = env::var("KARAOKE_KODI_HOST").ok()?;
+    let host = host.trim();
+    if host.is_empty() {
+        return None;
+    }
+
+    let port = match env::var("KARAOKE_KODI_PORT") {
+        Ok(port) => match port.trim().parse() {
+            Ok(port) => port,
+            Err(_) => {
+                error!("Invalid KARAOKE_KODI_PORT {}, using {}", port, DEFAULT_PORT);
+                DEFAULT_PORT
+            }
+        },
+        Err(_) => DEFAULT_PORT,
+    };
+
+    // IPv6 addresses need brackets in a URL
+    let url = if host.contains(':') && !host.starts_with('[') {
+        format!("http://[{}]:{}/jsonrpc", host, port)
+    } else {
+        format!("http://{}:{}/jsonrpc", host, port)
+    };
+
+    let name = env::var("KARAOKE_KODI_NAME")
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_NAME.to_string());
+    info!("Using Kodi {} at {}", name, url);
+
+    Some(KodiConfig {
+        name,
+        url,
+        user: env::var("KARAOKE_KODI_USER").ok(),
+        password: env::var("KARAOKE_KODI_PASSWORD").ok(),
+    })
+}
+
+// URL that has the Kodi addon for the video's site play it
+fn plugin_url(video_info: &VideoInfo) -> String {
+    match video_info.source {
+        VideoSource::YouTube => format!(
+            "plugin://plugin.video.youtube/play/?video_id={}",
+            video_info.id
+        ),
+        VideoSource::Vimeo => format!(
+            "plugin://plugin.video.vimeo/play/?video_id={}",
+            video_info.id
+        ),
+        VideoSource::Dailymotion => format!(
+            "plugin://plugin.video.dailymotion_com/?mode=playVideo&url={}",
+            video_info.id
+        ),
+    }
+}
+
+// Seconds in a Kodi time object, e.g. {"hours": 0, "minutes": 3, "seconds": 20}
+fn seconds(time: &Value) -> u64 {
+    let part = |name: &str| time[name].as_u64().unwrap_or(0);
+    part("hours") * 3600 + part("minutes") * 60 + part("seconds")
+}
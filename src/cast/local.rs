@@ -0,0 +1,459 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use serde_json::{json, Value};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+
+use super::{CastBackend, DiscoveredDevice, MediaStatus, NowPlaying, PlayerState, VolumeStatus};
+use crate::youtube::VideoInfo;
+
+// Name of the local player in /devices and /castto
+const LOCAL_DEVICE: &str = "local";
+
+// Player used when KARAOKE_LOCAL_PLAYER isn't set
+const DEFAULT_PLAYER: &str = "mpv";
+
+// Port VLC's remote control interface listens on
+const VLC_RC_PORT: u16 = 4212;
+
+// How long to wait for the player to answer a command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlayerKind {
+    Mpv, // Controlled over its JSON IPC socket
+    Vlc, // Controlled over its remote control interface
+}
+
+// A video playing in the local player
+struct Playback {
+    child: Child,
+    kind: PlayerKind,
+    volume: u8,    // Last volume set, restored when VLC is unmuted
+    stopped: bool, // Closed with /stop rather than played to the end
+}
+
+// mpv or VLC on the machine running the bot, for setups where it's plugged into the TV
+pub struct LocalPlayer {
+    playback: Mutex<Option<Playback>>,
+}
+
+impl LocalPlayer {
+    pub fn new() -> Self {
+        Self {
+            playback: Mutex::new(None),
+        }
+    }
+
+    // Send a command to the running player
+    async fn command(&self, device: &str, mpv: Value, vlc: &str) -> Result<String> {
+        check_device(device)?;
+        let kind = self
+            .playback
+            .lock()
+            .await
+            .as_ref()
+            .map(|playback| playback.kind)
+            .ok_or_else(|| anyhow!("Nothing is playing on the local player"))?;
+
+        match kind {
+            PlayerKind::Mpv => mpv_command(mpv).await.map(|data| data.to_string()),
+            PlayerKind::Vlc => vlc_command(vlc).await,
+        }
+    }
+}
+
+#[async_trait]
+impl CastBackend for LocalPlayer {
+    fn id(&self) -> &'static str {
+        "local"
+    }
+
+    fn label(&self) -> &'static str {
+        "this computer"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        // Only offered when the player is actually installed
+        if find_player().is_some() {
+            Ok(vec![DiscoveredDevice {
+                name: LOCAL_DEVICE.to_string(),
+                audio_only: false,
+            }])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        _now_playing: &NowPlaying,
+    ) -> Result<()> {
+        check_device(device)?;
+        let player = find_player()
+            .ok_or_else(|| anyhow!("Local player {} isn't installed", player_setting()))?;
+        let kind = player_kind(&player);
+
+        let mut playback = self.playback.lock().await;
+
+        // Close the previous video first, players don't share the control socket
+        if let Some(previous) = playback.as_mut() {
+            previous.stopped = true;
+            let _ = previous.child.kill().await;
+        }
+
+        let mut command = Command::new(&player);
+        match kind {
+            PlayerKind::Mpv => {
+                command
+                    .arg("--fullscreen")
+                    .arg("--force-window=immediate")
+                    .arg(format!("--input-ipc-server={}", mpv_socket().display()));
+            }
+            PlayerKind::Vlc => {
+                command
+                    .arg("--fullscreen")
+                    .arg("--play-and-exit")
+                    .arg("--extraintf=rc")
+                    .arg(format!("--rc-host=127.0.0.1:{}", VLC_RC_PORT));
+            }
+        }
+
+        if let Some(start) = video_info.start_time {
+            command.arg(match kind {
+                PlayerKind::Mpv => format!("--start={}", start),
+                PlayerKind::Vlc => format!("--start-time={}", start),
+            });
+        }
+
+        info!("Starting {} for {}", player.display(), video_info.url);
+        let child = command
+            .arg(&video_info.url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start {}: {}", player.display(), e))?;
+
+        *playback = Some(Playback {
+            child,
+            kind,
+            volume: 100,
+            stopped: false,
+        });
+
+        Ok(())
+    }
+
+    async fn play_announcement(&self, device: &str, audio_url: &str) -> Result<bool> {
+        check_device(device)?;
+        let Some(player) = find_player() else {
+            return Ok(false);
+        };
+
+        // A separate audio-only player, so it doesn't take over the video's control socket
+        let mut command = Command::new(&player);
+        match player_kind(&player) {
+            PlayerKind::Mpv => command.arg("--no-video"),
+            PlayerKind::Vlc => command.arg("--intf=dummy").arg("--play-and-exit"),
+        };
+
+        let mut child = command
+            .arg(audio_url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start {}: {}", player.display(), e))?;
+
+        // Reap the player once it's done speaking
+        tokio::spawn(async move {
+            let _ = child.wait().await;
+        });
+        Ok(true)
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        // VLC's "pause" toggles, so only send it while playing
+        let paused = self.command(device, json!(["get_property", "pause"]), "is_playing");
+        match paused.await?.trim() {
+            "true" => return Ok(()),
+            vlc_reply if vlc_reply.ends_with('0') => return Ok(()),
+            _ => {}
+        }
+
+        self.command(device, json!(["set_property", "pause", true]), "pause")
+            .await
+            .map(|_| ())
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.command(device, json!(["set_property", "pause", false]), "play")
+            .await
+            .map(|_| ())
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        self.command(
+            device,
+            json!(["seek", position, "absolute"]),
+            &format!("seek {}", position),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        // VLC's volume goes from 0 to 256 for 100%
+        self.command(
+            device,
+            json!(["set_property", "volume", level]),
+            &format!("volume {}", level as u32 * 256 / 100),
+        )
+        .await?;
+
+        if let Some(playback) = self.playback.lock().await.as_mut() {
+            playback.volume = level;
+        }
+        Ok(())
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        let volume = self
+            .playback
+            .lock()
+            .await
+            .as_ref()
+            .map(|playback| playback.volume)
+            .unwrap_or(100);
+
+        // VLC's remote control has no mute, so the volume is dropped to zero instead
+        let vlc_volume = if muted { 0 } else { volume as u32 * 256 / 100 };
+        self.command(
+            device,
+            json!(["set_property", "mute", muted]),
+            &format!("volume {}", vlc_volume),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        check_device(device)?;
+
+        if let Some(playback) = self.playback.lock().await.as_mut() {
+            playback.stopped = true;
+            let _ = playback.child.kill().await;
+        }
+        Ok(())
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        check_device(device)?;
+
+        let kind = {
+            let mut playback = self.playback.lock().await;
+            let running = match playback.as_mut() {
+                Some(playback) => match playback.child.try_wait()? {
+                    Some(exit) => Err((playback.stopped, exit.success())),
+                    None => Ok(playback.kind),
+                },
+                None => Err((true, true)),
+            };
+
+            match running {
+                Ok(kind) => kind,
+                // The player exits at the end of the video, unless we closed it.
+                // It exits with an error when it couldn't open the video at all.
+                Err((stopped, success)) => {
+                    return Ok(MediaStatus {
+                        player_state: PlayerState::Idle,
+                        position: 0,
+                        duration: None,
+                        finished: !stopped && success,
+                        error: (!stopped && !success)
+                            .then(|| "The local player couldn't open the video".to_string()),
+                    })
+                }
+            }
+        };
+
+        let (paused, position, duration) = match kind {
+            PlayerKind::Mpv => (
+                mpv_command(json!(["get_property", "pause"]))
+                    .await?
+                    .as_bool()
+                    == Some(true),
+                mpv_command(json!(["get_property", "time-pos"]))
+                    .await?
+                    .as_f64(),
+                mpv_command(json!(["get_property", "duration"]))
+                    .await?
+                    .as_f64(),
+            ),
+            PlayerKind::Vlc => (
+                vlc_command("is_playing").await?.trim().ends_with('0'),
+                last_number(&vlc_command("get_time").await?),
+                last_number(&vlc_command("get_length").await?),
+            ),
+        };
+
+        Ok(MediaStatus {
+            player_state: if paused {
+                PlayerState::Paused
+            } else {
+                PlayerState::Playing
+            },
+            position: position.unwrap_or(0.0) as u64,
+            duration: duration
+                .filter(|duration| *duration > 0.0)
+                .map(|duration| duration as u64),
+            finished: false,
+            error: None,
+        })
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        check_device(device)?;
+        let kind = match self.playback.lock().await.as_ref() {
+            Some(playback) => playback.kind,
+            None => return Ok(None),
+        };
+
+        let volume = match kind {
+            PlayerKind::Mpv => VolumeStatus {
+                level: mpv_command(json!(["get_property", "volume"]))
+                    .await?
+                    .as_f64()
+                    .unwrap_or(100.0)
+                    .clamp(0.0, 100.0) as u8,
+                muted: mpv_command(json!(["get_property", "mute"]))
+                    .await?
+                    .as_bool()
+                    == Some(true),
+            },
+            // Muting VLC drops the volume to zero, see set_muted
+            PlayerKind::Vlc => {
+                let level = last_number(&vlc_command("volume").await?).unwrap_or(256.0);
+                let level = (level * 100.0 / 256.0).clamp(0.0, 100.0) as u8;
+                VolumeStatus {
+                    level,
+                    muted: level == 0,
+                }
+            }
+        };
+
+        Ok(Some(volume))
+    }
+}
+
+fn check_device(device: &str) -> Result<()> {
+    if device == LOCAL_DEVICE {
+        Ok(())
+    } else {
+        Err(anyhow!("Unknown local device {}", device))
+    }
+}
+
+// Player binary from KARAOKE_LOCAL_PLAYER, e.g. "vlc" or "/usr/bin/mpv"
+fn player_setting() -> String {
+    env::var("KARAOKE_LOCAL_PLAYER").unwrap_or_else(|_| DEFAULT_PLAYER.to_string())
+}
+
+// Full path of the player binary, if it's installed
+fn find_player() -> Option<PathBuf> {
+    let player = PathBuf::from(player_setting());
+    if player.components().count() > 1 {
+        return player.exists().then_some(player);
+    }
+
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(&player))
+            .find(|path| path.is_file())
+    })
+}
+
+fn player_kind(player: &Path) -> PlayerKind {
+    let name = player
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if name.contains("vlc") {
+        PlayerKind::Vlc
+    } else {
+        PlayerKind::Mpv
+    }
+}
+
+fn mpv_socket() -> PathBuf {
+    env::temp_dir().join("karaoke-mpv.sock")
+}
+
+// Send a command over mpv's JSON IPC socket and return its data
+async fn mpv_command(command: Value) -> Result<Value> {
+    let request = async {
+        let mut stream = UnixStream::connect(mpv_socket()).await?;
+        stream
+            .write_all(format!("{}\n", json!({ "command": command })).as_bytes())
+            .await?;
+
+        // Events can arrive before the reply, which is the line with an "error" field
+        let mut lines = BufReader::new(stream).lines();
+        while let Some(line) = lines.next_line().await? {
+            let reply: Value = serde_json::from_str(&line)?;
+            match reply.get("error").and_then(Value::as_str) {
+                Some("success") => return Ok(reply.get("data").cloned().unwrap_or(Value::Null)),
+                Some(error) => return Err(anyhow!("mpv: {}", error)),
+                None => continue,
+            }
+        }
+
+        Err(anyhow!("mpv closed the connection"))
+    };
+
+    tokio::time::timeout(COMMAND_TIMEOUT, request)
+        .await
+        .map_err(|_| anyhow!("mpv didn't respond"))?
+}
+
+// Send a command to VLC's remote control interface and return what it printed
+async fn vlc_command(command: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", VLC_RC_PORT)).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    // The interface doesn't end its replies, so read until it goes quiet
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while let Ok(Ok(length)) =
+        tokio::time::timeout(Duration::from_millis(200), stream.read(&mut buffer)).await
+    {
+        if length == 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[..length]);
+    }
+
+    Ok(String::from_utf8_lossy(&output).to_string())
+}
+
+// The last number VLC printed, e.g. the seconds in a get_time reply
+fn last_number(output: &str) -> Option<f64> {
+    output
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().trim_start_matches('>').trim().parse().ok())
+}
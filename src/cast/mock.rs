@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::{CastBackend, DiscoveredDevice, MediaStatus, NowPlaying, PlayerState, VolumeStatus};
+use crate::youtube::VideoInfo;
+
+// Name of the pretend TV in /devices and /castto
+const MOCK_DEVICE: &str = "mock TV";
+
+// A command the bot sent to a device
+#[derive(Clone)]
+enum MockCall {
+    Play {
+        video_id: String,
+        duration: Option<u64>,
+        start: u64,
+    },
+    Pause,
+    Resume,
+    Seek(u64),
+    SetVolume(u8),
+    SetMuted(bool),
+    Stop,
+}
+
+impl fmt::Display for MockCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MockCall::Play {
+                video_id, start: 0, ..
+            } => write!(f, "play {}", video_id),
+            MockCall::Play {
+                video_id, start, ..
+            } => write!(f, "play {} from {}s", video_id, start),
+            MockCall::Pause => write!(f, "pause"),
+            MockCall::Resume => write!(f, "resume"),
+            MockCall::Seek(position) => write!(f, "seek to {}s", position),
+            MockCall::SetVolume(level) => write!(f, "set volume to {}%", level),
+            MockCall::SetMuted(muted) => write!(f, "set muted to {}", muted),
+            MockCall::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+// Records what the bot asks devices to do instead of touching the network,
+// for dry runs without a Chromecast. Enabled with KARAOKE_CAST=mock.
+pub struct MockCastBackend {
+    calls: Mutex<Vec<(String, Instant, MockCall)>>, // Device, when and what, oldest first
+}
+
+impl MockCastBackend {
+    pub fn new() -> Self {
+        Self {
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, device: &str, call: MockCall) -> Result<()> {
+        info!("Mock cast to {}: {}", device, call);
+        self.calls
+            .lock()
+            .map_err(|_| anyhow!("Mock cast state is unavailable"))?
+            .push((device.to_string(), Instant::now(), call));
+        Ok(())
+    }
+
+    // The calls sent to a device since it was last told to play
+    fn since_last_play(&self, device: &str) -> Result<Vec<(Instant, MockCall)>> {
+        let calls = self
+            .calls
+            .lock()
+            .map_err(|_| anyhow!("Mock cast state is unavailable"))?;
+
+        let mut playback = Vec::new();
+        for (call_device, at, call) in calls.iter() {
+            if call_device != device {
+                continue;
+            }
+            if matches!(call, MockCall::Play { .. }) {
+                playback.clear();
+            }
+            playback.push((*at, call.clone()));
+        }
+        Ok(playback)
+    }
+}
+
+#[async_trait]
+impl CastBackend for MockCastBackend {
+    fn id(&self) -> &'static str {
+        "mock"
+    }
+
+    fn label(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        Ok(vec![DiscoveredDevice {
+            name: MOCK_DEVICE.to_string(),
+            audio_only: false,
+        }])
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        _now_playing: &NowPlaying,
+    ) -> Result<()> {
+        self.record(
+            device,
+            MockCall::Play {
+                video_id: video_info.id.clone(),
+                duration: video_info.duration,
+                start: video_info.start_time.unwrap_or(0),
+            },
+        )
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        self.record(device, MockCall::Pause)
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.record(device, MockCall::Resume)
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        self.record(device, MockCall::Seek(position))
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        self.record(device, MockCall::SetVolume(level))
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        self.record(device, MockCall::SetMuted(muted))
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        self.record(device, MockCall::Stop)
+    }
+
+    // Replay the calls since the last play to work out where the video would be
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        let mut duration = None;
+        let mut position = 0;
+        let mut resumed_at = None;
+        let mut stopped = true;
+
+        for (at, call) in self.since_last_play(device)? {
+            let elapsed = |since: Option<Instant>| {
+                since
+                    .map(|since| at.duration_since(since).as_secs())
+                    .unwrap_or(0)
+            };
+
+            match call {
+                MockCall::Play {
+                    duration: video_duration,
+                    start,
+                    ..
+                } => {
+                    duration = video_duration;
+                    position = start;
+                    resumed_at = Some(at);
+                    stopped = false;
+                }
+                MockCall::Pause => {
+                    position += elapsed(resumed_at);
+                    resumed_at = None;
+                }
+                MockCall::Resume if resumed_at.is_none() => resumed_at = Some(at),
+                MockCall::Seek(target) => {
+                    position = target;
+                    resumed_at = resumed_at.map(|_| at);
+                }
+                MockCall::Stop => stopped = true,
+                _ => {}
+            }
+        }
+
+        if stopped {
+            return Ok(MediaStatus {
+                player_state: PlayerState::Idle,
+                position: 0,
+                duration: None,
+                finished: false,
+                error: None,
+            });
+        }
+
+        let position = position
+            + resumed_at
+                .map(|resumed_at| resumed_at.elapsed().as_secs())
+                .unwrap_or(0);
+        let finished = duration.is_some_and(|duration| position >= duration);
+
+        Ok(MediaStatus {
+            player_state: if finished {
+                PlayerState::Idle
+            } else if resumed_at.is_some() {
+                PlayerState::Playing
+            } else {
+                PlayerState::Paused
+            },
+            position: duration.map_or(position, |duration| position.min(duration)),
+            duration,
+            finished,
+            error: None,
+        })
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        let calls = self
+            .calls
+            .lock()
+            .map_err(|_| anyhow!("Mock cast state is unavailable"))?;
+
+        let mut volume = VolumeStatus {
+            level: 100,
+            muted: false,
+        };
+        for (call_device, _, call) in calls.iter() {
+            match call {
+                MockCall::SetVolume(level) if call_device == device => volume.level = *level,
+                MockCall::SetMuted(muted) if call_device == device => volume.muted = *muted,
+                _ => {}
+            }
+        }
+
+        Ok(Some(volume))
+    }
+}
@@ -0,0 +1,579 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Html;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use lazy_static::lazy_static;
+use log::{error, info};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+use super::{
+    CastBackend, DiscoveredDevice, IdleCard, LoadFailed, MediaStatus, NowPlaying, PlayerState,
+    VolumeStatus,
+};
+use crate::source::VideoSource;
+use crate::youtube::VideoInfo;
+
+// Name of the browser screen in /devices and /castto
+const WEB_DEVICE: &str = "web player";
+
+// Port the page is served on when KARAOKE_WEB_PORT isn't set
+const DEFAULT_WEB_PORT: u16 = 8080;
+
+// What the page should do, sent to it as server-sent events
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ScreenEvent {
+    Load {
+        video_id: String,
+        title: Option<String>,
+        position: u64, // Where to start, so a refreshed page picks up where it was
+        now_playing: NowPlaying,
+    },
+    NowPlaying {
+        now_playing: NowPlaying,
+    },
+    Pause,
+    Resume,
+    Seek {
+        position: u64,
+    },
+    Volume {
+        level: u8,
+    },
+    Mute {
+        muted: bool,
+    },
+    Message {
+        text: String,
+    },
+    Announce {
+        url: String,
+    },
+    Idle {
+        title: String,
+        code: String,
+        join_link: Option<String>,
+        qr_code: Option<String>, // SVG of the join link
+    },
+    Stop,
+}
+
+// Playback state the page reports back every few seconds
+#[derive(Debug, Deserialize)]
+struct PageStatus {
+    state: String, // "playing", "paused", "ended" or "idle"
+    position: f64,
+    duration: Option<f64>,
+    volume: Option<f64>, // 0-100
+    muted: Option<bool>,
+    error: Option<u32>, // YouTube player error code, if the video wouldn't play
+}
+
+// The video on the screen and what the page last said about it
+struct Screen {
+    video: Option<VideoInfo>,
+    status: MediaStatus,
+    volume: Option<VolumeStatus>, // None until a page has reported
+    message: Option<String>,      // Shown over the player until the next video starts
+    now_playing: NowPlaying,
+    idle: Option<ScreenEvent>, // Join details shown while nothing is playing
+}
+
+lazy_static! {
+    static ref SCREEN: Mutex<Screen> = Mutex::new(Screen {
+        video: None,
+        status: MediaStatus {
+            player_state: PlayerState::Idle,
+            position: 0,
+            duration: None,
+            finished: false,
+            error: None,
+        },
+        volume: None,
+        message: None,
+        now_playing: NowPlaying::default(),
+        idle: None,
+    });
+    static ref SCREEN_EVENTS: broadcast::Sender<ScreenEvent> = broadcast::channel(16).0;
+}
+
+// Whether the page is being served, so the screen is only offered when it can be opened
+static SERVER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Any browser, e.g. on a smart TV, showing the page served by run_web_player
+pub struct WebPlayer;
+
+impl WebPlayer {
+    fn send(&self, device: &str, event: ScreenEvent) -> Result<()> {
+        check_device(device)?;
+
+        // Nobody has the page open yet, the next page to connect gets the current video
+        let _ = SCREEN_EVENTS.send(event);
+        Ok(())
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut MediaStatus)) {
+        if let Ok(mut screen) = SCREEN.lock() {
+            update(&mut screen.status);
+        }
+    }
+}
+
+#[async_trait]
+impl CastBackend for WebPlayer {
+    fn id(&self) -> &'static str {
+        "web"
+    }
+
+    fn label(&self) -> &'static str {
+        "browser"
+    }
+
+    async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        if SERVER_RUNNING.load(Ordering::Relaxed) {
+            Ok(vec![DiscoveredDevice {
+                name: WEB_DEVICE.to_string(),
+                audio_only: false,
+            }])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    async fn play(
+        &self,
+        device: &str,
+        video_info: &VideoInfo,
+        now_playing: &NowPlaying,
+    ) -> Result<()> {
+        check_device(device)?;
+
+        // The page plays videos with the YouTube player
+        if video_info.source != VideoSource::YouTube {
+            return Err(LoadFailed {
+                reason: format!(
+                    "The web player can only play YouTube videos, not {} ones",
+                    video_info.source.name()
+                ),
+            }
+            .into());
+        }
+
+        if let Ok(mut screen) = SCREEN.lock() {
+            screen.video = Some(video_info.clone());
+            screen.message = None;
+            screen.idle = None;
+            screen.now_playing = now_playing.clone();
+            screen.status = MediaStatus {
+                player_state: PlayerState::Playing,
+                position: video_info.start_time.unwrap_or(0),
+                duration: video_info.duration,
+                finished: false,
+                error: None,
+            };
+        }
+
+        self.send(
+            device,
+            ScreenEvent::Load {
+                video_id: video_info.id.clone(),
+                title: video_info.title.clone(),
+                position: video_info.start_time.unwrap_or(0),
+                now_playing: now_playing.clone(),
+            },
+        )
+    }
+
+    async fn update_now_playing(&self, device: &str, now_playing: &NowPlaying) -> Result<()> {
+        self.send(
+            device,
+            ScreenEvent::NowPlaying {
+                now_playing: now_playing.clone(),
+            },
+        )?;
+
+        if let Ok(mut screen) = SCREEN.lock() {
+            screen.now_playing = now_playing.clone();
+        }
+        Ok(())
+    }
+
+    async fn pause(&self, device: &str) -> Result<()> {
+        self.send(device, ScreenEvent::Pause)?;
+        self.update_status(|status| status.player_state = PlayerState::Paused);
+        Ok(())
+    }
+
+    async fn resume(&self, device: &str) -> Result<()> {
+        self.send(device, ScreenEvent::Resume)?;
+        self.update_status(|status| status.player_state = PlayerState::Playing);
+        Ok(())
+    }
+
+    async fn seek(&self, device: &str, position: u64) -> Result<()> {
+        self.send(device, ScreenEvent::Seek { position })?;
+        self.update_status(|status| status.position = position);
+        Ok(())
+    }
+
+    async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        self.send(device, ScreenEvent::Volume { level })
+    }
+
+    async fn set_muted(&self, device: &str, muted: bool) -> Result<()> {
+        self.send(device, ScreenEvent::Mute { muted })
+    }
+
+    async fn stop(&self, device: &str) -> Result<()> {
+        self.send(device, ScreenEvent::Stop)?;
+
+        if let Ok(mut screen) = SCREEN.lock() {
+            screen.video = None;
+            screen.message = None;
+            screen.now_playing = NowPlaying::default();
+            screen.idle = None;
+            screen.status.player_state = PlayerState::Idle;
+            screen.status.finished = false;
+        }
+        Ok(())
+    }
+
+    async fn media_status(&self, device: &str) -> Result<MediaStatus> {
+        check_device(device)?;
+
+        SCREEN
+            .lock()
+            .map(|screen| screen.status.clone())
+            .map_err(|_| anyhow!("Web player state is unavailable"))
+    }
+
+    async fn play_announcement(&self, device: &str, audio_url: &str) -> Result<bool> {
+        self.send(
+            device,
+            ScreenEvent::Announce {
+                url: audio_url.to_string(),
+            },
+        )?;
+        Ok(true)
+    }
+
+    async fn show_idle(&self, device: &str, card: &IdleCard) -> Result<bool> {
+        let event = ScreenEvent::Idle {
+            title: card.title.clone(),
+            code: card.code.clone(),
+            join_link: card.join_link.clone(),
+            qr_code: card.join_link.as_deref().and_then(qr_code_svg),
+        };
+        self.send(device, event.clone())?;
+
+        if let Ok(mut screen) = SCREEN.lock() {
+            screen.idle = Some(event);
+        }
+        Ok(true)
+    }
+
+    async fn show_message(&self, device: &str, text: &str) -> Result<bool> {
+        self.send(
+            device,
+            ScreenEvent::Message {
+                text: text.to_string(),
+            },
+        )?;
+
+        if let Ok(mut screen) = SCREEN.lock() {
+            screen.message = Some(text.to_string());
+        }
+        Ok(true)
+    }
+
+    async fn volume(&self, device: &str) -> Result<Option<VolumeStatus>> {
+        check_device(device)?;
+
+        SCREEN
+            .lock()
+            .map(|screen| screen.volume)
+            .map_err(|_| anyhow!("Web player state is unavailable"))
+    }
+}
+
+fn check_device(device: &str) -> Result<()> {
+    if device == WEB_DEVICE {
+        Ok(())
+    } else {
+        Err(anyhow!("Unknown web player {}", device))
+    }
+}
+
+// Serve the player page on KARAOKE_WEB_PORT (8080 by default), or not at all if it's "off".
+// Runs for the lifetime of the bot.
+pub async fn run_web_player() {
+    let port = match env::var("KARAOKE_WEB_PORT") {
+        Ok(port) if port.eq_ignore_ascii_case("off") => return,
+        Ok(port) => match port.parse() {
+            Ok(port) => port,
+            Err(_) => {
+                error!(
+                    "KARAOKE_WEB_PORT must be a port number or \"off\", got {}",
+                    port
+                );
+                return;
+            }
+        },
+        Err(_) => DEFAULT_WEB_PORT,
+    };
+
+    let app = Router::new()
+        .route("/", get(|| async { Html(PLAYER_PAGE) }))
+        .route("/events", get(screen_events))
+        .route("/status", post(report_status));
+
+    let listener = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to start the web player on port {}: {}", port, e);
+            return;
+        }
+    };
+
+    info!("Web player available at http://<this machine>:{}/", port);
+    SERVER_RUNNING.store(true, Ordering::Relaxed);
+
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Web player stopped: {}", e);
+    }
+    SERVER_RUNNING.store(false, Ordering::Relaxed);
+}
+
+// Stream screen events to a page, starting with the video already playing
+async fn screen_events() -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    // Subscribe before reading the current video so no event is missed in between
+    let updates = BroadcastStream::new(SCREEN_EVENTS.subscribe()).filter_map(|event| event.ok());
+
+    let mut current = Vec::new();
+    if let Ok(screen) = SCREEN.lock() {
+        if let Some(video) = &screen.video {
+            current.push(ScreenEvent::Load {
+                video_id: video.id.clone(),
+                title: video.title.clone(),
+                position: screen.status.position,
+                now_playing: screen.now_playing.clone(),
+            });
+        }
+        if let Some(text) = &screen.message {
+            current.push(ScreenEvent::Message { text: text.clone() });
+        }
+        if let Some(idle) = &screen.idle {
+            current.push(idle.clone());
+        }
+    }
+
+    let events = tokio_stream::iter(current)
+        .chain(updates)
+        .map(|event| Event::default().json_data(event));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+// Draw a link as a QR code for the idle screen
+fn qr_code_svg(link: &str) -> Option<String> {
+    let code = QrCode::new(link.as_bytes()).ok()?;
+    Some(
+        code.render::<svg::Color>()
+            .min_dimensions(300, 300)
+            .dark_color(svg::Color("#000000"))
+            .light_color(svg::Color("#ffffff"))
+            .build(),
+    )
+}
+
+// Explain a YouTube player error code
+fn describe_player_error(code: u32) -> String {
+    match code {
+        2 => "YouTube says the video link is invalid".to_string(),
+        5 => "the video can't be played in the browser".to_string(),
+        100 => "the video was removed or is private".to_string(),
+        101 | 150 => {
+            "the uploader doesn't allow the video to be played outside YouTube".to_string()
+        }
+        _ => format!("YouTube player error {}", code),
+    }
+}
+
+async fn report_status(Json(report): Json<PageStatus>) {
+    let Ok(mut screen) = SCREEN.lock() else {
+        return;
+    };
+
+    // Late reports about a video that was stopped shouldn't bring it back
+    if screen.video.is_none() {
+        return;
+    }
+
+    screen.status = MediaStatus {
+        player_state: match report.state.as_str() {
+            "playing" => PlayerState::Playing,
+            "paused" => PlayerState::Paused,
+            _ => PlayerState::Idle,
+        },
+        position: report.position.max(0.0) as u64,
+        duration: report
+            .duration
+            .filter(|duration| *duration > 0.0)
+            .map(|duration| duration as u64)
+            .or(screen.status.duration),
+        finished: report.state == "ended",
+        error: report.error.map(describe_player_error),
+    };
+
+    if let Some(level) = report.volume {
+        screen.volume = Some(VolumeStatus {
+            level: level.clamp(0.0, 100.0) as u8,
+            muted: report.muted.unwrap_or(false),
+        });
+    }
+}
+
+// The page a browser opens to act as the screen, playing videos with the YouTube iframe API
+const PLAYER_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Karaoke Queue</title>
+<style>
+  html, body { margin: 0; height: 100%; background: #000; color: #fff; font-family: sans-serif; overflow: hidden; }
+  #player { width: 100%; height: 100%; }
+  #waiting, #message { position: absolute; top: 50%; width: 100%; text-align: center; font-size: 3em; transform: translateY(-50%); }
+  #message { display: none; padding: 1em 0; background: rgba(0, 0, 0, 0.85); }
+  #now-playing { display: none; position: absolute; right: 1em; bottom: 1em; max-width: 40%; padding: 0.75em 1em; border-radius: 0.5em; background: rgba(0, 0, 0, 0.6); font-size: 1.5em; }
+  #now-playing ol { margin: 0.25em 0 0; padding-left: 1.25em; }
+  #idle { display: none; position: absolute; inset: 0; background: #111; text-align: center; padding-top: 8vh; }
+  #idle h1 { font-size: 4em; margin: 0 0 0.25em; }
+  #idle p { font-size: 2em; margin: 0.5em 0; }
+  #idle-qr svg { width: 40vh; height: 40vh; border-radius: 0.5em; }
+</style>
+</head>
+<body>
+<div id="waiting">Waiting for the next song...</div>
+<div id="player"></div>
+<div id="message"></div>
+<div id="now-playing"><div id="singer"></div><div id="up-next"></div></div>
+<div id="idle"><h1 id="idle-title"></h1><p id="idle-code"></p><div id="idle-qr"></div><p id="idle-hint"></p></div>
+<script src="https://www.youtube.com/iframe_api"></script>
+<script>
+  let player = null;
+  let ready = false;
+  let pending = [];
+  let playerError = null;
+
+  function onYouTubeIframeAPIReady() {
+    player = new YT.Player("player", {
+      playerVars: { autoplay: 1, controls: 0, rel: 0 },
+      events: {
+        onReady: () => { ready = true; pending.forEach(handle); pending = []; },
+        onStateChange: report,
+        onError: (error) => { playerError = error.data; report(); },
+      },
+    });
+  }
+
+  function showWaiting(show) {
+    document.getElementById("waiting").style.display = show ? "block" : "none";
+  }
+
+  function showMessage(text) {
+    const message = document.getElementById("message");
+    message.textContent = text || "";
+    message.style.display = text ? "block" : "none";
+  }
+
+  function showIdle(card) {
+    const idle = document.getElementById("idle");
+    if (!card) { idle.style.display = "none"; return; }
+
+    document.getElementById("idle-title").textContent = card.title;
+    document.getElementById("idle-code").textContent = "Join with code " + card.code;
+    document.getElementById("idle-qr").innerHTML = card.qr_code || "";
+    document.getElementById("idle-hint").textContent = card.join_link
+      ? "Scan to join on Telegram"
+      : "Send /join " + card.code + " to the bot on Telegram";
+    idle.style.display = "block";
+  }
+
+  function showNowPlaying(nowPlaying) {
+    const box = document.getElementById("now-playing");
+    if (!nowPlaying || !nowPlaying.singer) { box.style.display = "none"; return; }
+
+    document.getElementById("singer").textContent = "Now singing: " + nowPlaying.singer;
+    const upNext = document.getElementById("up-next");
+    upNext.textContent = "";
+    if (nowPlaying.up_next.length > 0) {
+      upNext.textContent = "Up next:";
+      const list = document.createElement("ol");
+      nowPlaying.up_next.forEach((song) => {
+        const item = document.createElement("li");
+        item.textContent = song;
+        list.appendChild(item);
+      });
+      upNext.appendChild(list);
+    }
+    box.style.display = "block";
+  }
+
+  function handle(event) {
+    if (!ready) { pending.push(event); return; }
+    switch (event.type) {
+      case "load":
+        showWaiting(false);
+        showMessage(null);
+        showIdle(null);
+        showNowPlaying(event.now_playing);
+        document.title = event.title || "Karaoke Queue";
+        playerError = null;
+        player.loadVideoById({ videoId: event.video_id, startSeconds: event.position });
+        break;
+      case "pause": player.pauseVideo(); break;
+      case "resume": player.playVideo(); break;
+      case "seek": player.seekTo(event.position, true); break;
+      case "volume": player.setVolume(event.level); break;
+      case "mute": event.muted ? player.mute() : player.unMute(); break;
+      case "now_playing": showNowPlaying(event.now_playing); break;
+      case "message": showWaiting(false); showMessage(event.text); break;
+      case "announce": new Audio(event.url).play().catch(() => {}); break;
+      case "idle": player.stopVideo(); showMessage(null); showNowPlaying(null); showWaiting(false); showIdle(event); break;
+      case "stop": player.stopVideo(); showMessage(null); showNowPlaying(null); showIdle(null); showWaiting(true); break;
+    }
+  }
+
+  function report() {
+    if (!ready) return;
+    const states = { 0: "ended", 1: "playing", 2: "paused", 3: "playing" };
+    fetch("/status", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({
+        state: states[player.getPlayerState()] || "idle",
+        position: player.getCurrentTime() || 0,
+        duration: player.getDuration() || null,
+        volume: player.getVolume(),
+        muted: player.isMuted(),
+        error: playerError,
+      }),
+    }).catch(() => {});
+  }
+
+  setInterval(report, 2000);
+  new EventSource("/events").onmessage = (message) => handle(JSON.parse(message.data));
+</script>
+</body>
+</html>
+"#;
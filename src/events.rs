@@ -0,0 +1,66 @@
+use lazy_static::lazy_static;
+use log::warn;
+use teloxide::types::ChatId;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+use crate::session::QueueItem;
+
+// How many events a slow subscriber can fall behind before it starts missing some
+const CHANNEL_CAPACITY: usize = 64;
+
+// Something that happened to a session's playback
+#[derive(Clone)]
+pub enum PlaybackEvent {
+    // A song was loaded on the session's device and is playing
+    SongStarted {
+        session_code: String,
+        item: Box<QueueItem>, // Boxed so the other events stay small
+    },
+    // The device reached the end of the current song, or gave up on it with an error.
+    // The next song is announced in `chat_id`.
+    SongFinished {
+        session_code: String,
+        chat_id: ChatId,
+        error: Option<String>,
+    },
+    // A song couldn't be cast, even after retrying
+    CastError {
+        session_code: String,
+        error: String,
+    },
+    // Songs were added to the session's queue
+    QueueUpdated {
+        session_code: String,
+    },
+}
+
+lazy_static! {
+    static ref EVENTS: Sender<PlaybackEvent> = broadcast::channel(CHANNEL_CAPACITY).0;
+}
+
+// Let every subscriber know something happened
+pub fn publish(event: PlaybackEvent) {
+    // No subscribers yet isn't an error, the event just goes nowhere
+    let _ = EVENTS.send(event);
+}
+
+// Start receiving every event published from now on
+pub fn subscribe() -> Receiver<PlaybackEvent> {
+    EVENTS.subscribe()
+}
+
+// Wait for the next event, skipping past any the subscriber fell too far behind to see
+pub async fn next_event(events: &mut Receiver<PlaybackEvent>) -> Option<PlaybackEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "A playback subscriber fell behind and missed {} events",
+                    missed
+                )
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::time::Duration;
+
+// LRCLIB's search endpoint, which needs no API key
+const LRCLIB_SEARCH_URL: &str = "https://lrclib.net/api/search";
+
+// How long to wait for the lyrics provider to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest message Telegram accepts, with some room to spare for the page header
+const PAGE_CHARS: usize = 4000;
+
+lazy_static! {
+    // Bits of video titles that aren't part of the song's name, e.g. "(Karaoke Version)"
+    // or "[Official Video]", and the words karaoke channels add around it
+    static ref TITLE_NOISE_REGEX: Regex = Regex::new(
+        r"(?i)[(\[][^)\]]*[)\]]|\b(karaoke|instrumental|lyrics?)\b"
+    )
+    .expect("Invalid title noise regex pattern");
+}
+
+// A song's lyrics as found by a provider
+pub struct Lyrics {
+    pub artist: String,
+    pub track: String,
+    pub text: String,
+}
+
+// Finds the lyrics of a song from a video's title
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    // Name shown in the logs
+    fn name(&self) -> &'static str;
+
+    // Lyrics of the song the title is most likely for, if any were found
+    async fn find_lyrics(&self, title: &str) -> Result<Option<Lyrics>>;
+}
+
+// LRCLIB, a free lyrics database at lrclib.net
+pub struct Lrclib {
+    client: reqwest::Client,
+}
+
+// A song in LRCLIB's search results
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LrclibTrack {
+    track_name: String,
+    artist_name: String,
+    #[serde(default)]
+    instrumental: bool,
+    plain_lyrics: Option<String>,
+}
+
+#[async_trait]
+impl LyricsProvider for Lrclib {
+    fn name(&self) -> &'static str {
+        "LRCLIB"
+    }
+
+    async fn find_lyrics(&self, title: &str) -> Result<Option<Lyrics>> {
+        let response = self
+            .client
+            .get(LRCLIB_SEARCH_URL)
+            .query(&[("q", title)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let tracks: Vec<LrclibTrack> = response.json().await?;
+
+        // The first result with words to sing is the best match
+        Ok(tracks
+            .into_iter()
+            .filter(|track| !track.instrumental)
+            .find_map(|track| {
+                let text = track.plain_lyrics.filter(|text| !text.trim().is_empty())?;
+                Some(Lyrics {
+                    artist: track.artist_name,
+                    track: track.track_name,
+                    text,
+                })
+            }))
+    }
+}
+
+lazy_static! {
+    static ref LYRICS_PROVIDER: Box<dyn LyricsProvider> = Box::new(Lrclib {
+        client: reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default(),
+    });
+}
+
+// Look up the lyrics for a video's title with the configured provider
+pub async fn find_lyrics(video_title: &str) -> Result<Option<Lyrics>> {
+    let query = song_name(video_title);
+    if query.is_empty() {
+        return Ok(None);
+    }
+
+    LYRICS_PROVIDER.find_lyrics(&query).await.map_err(|e| {
+        anyhow!(
+            "{} couldn't look up the lyrics: {}",
+            LYRICS_PROVIDER.name(),
+            e
+        )
+    })
+}
+
+// The artist and song in a video title, without the extras karaoke channels add,
+// e.g. "Queen - Bohemian Rhapsody (Karaoke Version)" becomes "Queen - Bohemian Rhapsody"
+fn song_name(video_title: &str) -> String {
+    let cleaned = TITLE_NOISE_REGEX.replace_all(video_title, " ");
+
+    cleaned
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c == '-' || c == '|' || c.is_whitespace())
+        .to_string()
+}
+
+// Split lyrics into messages short enough for Telegram, breaking between lines
+pub fn paginate(text: &str) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+
+    for line in text.lines() {
+        // A single line longer than a page is cut at a character boundary
+        let line: String = line.chars().take(PAGE_CHARS).collect();
+
+        if !page.is_empty() && page.chars().count() + line.chars().count() + 1 > PAGE_CHARS {
+            pages.push(std::mem::take(&mut page));
+        }
+        page.push_str(&line);
+        page.push('\n');
+    }
+
+    if !page.trim().is_empty() {
+        pages.push(page);
+    }
+    pages
+}
@@ -1,17 +1,56 @@
+mod archive;
+mod backup;
 mod cast;
+mod events;
+mod lyrics;
+mod metadata;
+mod migrations;
+mod playback;
+mod scheduler;
 mod session;
+mod source;
+mod store;
+mod streaming;
+mod tts;
+mod updates;
 mod youtube;
+mod youtube_api;
+mod ytdlp;
 
 use anyhow::Result;
 use dotenv::dotenv;
-use log::{error, info, warn};
+use log::{error, info};
 use std::env;
 use std::sync::Arc;
-use teloxide::{dispatching::UpdateHandler, prelude::*, utils::command::BotCommands};
+use teloxide::{
+    prelude::*,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup},
+    utils::command::BotCommands,
+};
 use tokio::sync::Mutex;
 
-use cast::cast_video;
-use session::{is_valid_youtube_url, SessionState};
+use backup::{create_backup, list_backups, load_backup};
+use cast::{
+    diagnose, get_available_devices, get_media_status, get_volume, pause_casting, resume_casting,
+    seek_to, set_muted, set_volume, stop_casting, MediaStatus, PlayerState, DEFAULT_DEVICE,
+};
+use events::PlaybackEvent;
+use playback::{
+    clear_ended_session, play_or_skip, send_with_thumbnail, show_idle_screen, skip_unplayable,
+    spawn_auto_advance,
+};
+use session::{
+    format_duration, format_timestamp, is_valid_video_url, item_video_title,
+    normalize_session_code, AddResult, JoinResult, LeaveResult, MergeResult, OwnerChange,
+    PlaylistImport, QueueItem, SessionState,
+};
+use source::{refetch_video_infos, VideoSource};
+use streaming::{parse_track_link, resolve_track, StreamingService};
+use youtube::{
+    create_video_info, extract_playlist_id, extract_video_id, fetch_playlist_video_ids,
+    fetch_video_infos, get_watch_url, looks_like_karaoke, search_available, search_karaoke_videos,
+    VideoInfo,
+};
 
 // Bot commands
 #[derive(BotCommands, Clone)]
@@ -20,32 +59,123 @@ enum Command {
     #[command(description = "Display this help message")]
     Help,
     #[command(description = "Display help information")]
-    Start,
-    #[command(description = "Start a new karaoke session")]
-    StartSession,
+    Start(String),
+    #[command(
+        description = "Start a new karaoke session (optionally --preset [name] or --from [old session])"
+    )]
+    StartSession(String),
+    #[command(
+        description = "Schedule a new session, e.g. /schedule-session 2024-06-01 20:00 Europe/Berlin"
+    )]
+    ScheduleSession(String),
     #[command(description = "Join an existing session with code")]
     Join(String),
     #[command(description = "Add a YouTube link to the queue (with optional note)")]
     Add(String),
+    #[command(description = "Add the videos of a YouTube playlist to the queue")]
+    AddPlaylist(String),
+    #[command(description = "Search YouTube for a karaoke video, e.g. /search bohemian rhapsody")]
+    Search(String),
     #[command(description = "View current queue")]
     Queue,
+    #[command(
+        description = "Look up songs whose titles couldn't be found when they were added (session owner only)"
+    )]
+    RefreshTitles,
     #[command(description = "Leave current session")]
     Leave,
     #[command(description = "Play the next video in the queue (session owner only)")]
     Next,
+    #[command(description = "List the cast devices available on the network")]
+    Devices,
+    #[command(description = "Choose the device to play videos on (session owner only)")]
+    CastTo(String),
+    #[command(description = "Pause the video on the cast device (session owner only)")]
+    Pause,
+    #[command(description = "Resume the paused video (session owner only)")]
+    Resume,
+    #[command(description = "Stop playback on the cast device (session owner only)")]
+    Stop,
+    #[command(
+        description = "Jump to a point in the current song, e.g. /seek 1:30 (session owner only)"
+    )]
+    Seek(String),
+    #[command(
+        description = "Set the cast device volume: /volume [0-100], /volume mute or /volume unmute (session owner only)"
+    )]
+    Volume(String),
+    #[command(description = "Show what the cast device is doing right now")]
+    CastInfo,
+    #[command(
+        description = "Check which cast devices can be found and reached (session owner only)"
+    )]
+    CastDiag,
     #[command(description = "Display the currently playing video")]
     Current,
+    #[command(description = "Get the lyrics of the song playing now, sent to you privately")]
+    Lyrics,
     #[command(description = "View history of played videos")]
     History,
     #[command(description = "Get your current session ID")]
     Id,
     #[command(description = "Get detailed session information")]
     Session,
+    #[command(description = "Set your display name in this session, e.g. /nickname Anna")]
+    Nickname(String),
+    #[command(
+        description = "Stop a member from adding songs, e.g. /mute @anna 30 (session owner only)"
+    )]
+    Mute(String),
+    #[command(description = "Let a muted member add songs again (session owner only)")]
+    Unmute(String),
+    #[command(
+        description = "Pick who takes over if you leave, e.g. /backup @anna (session owner only)"
+    )]
+    Backup(String),
+    #[command(
+        description = "Merge another session into yours, e.g. /merge TIGER-42 (session owner only)"
+    )]
+    Merge(String),
+    #[command(description = "Keep an idle session from being closed")]
+    KeepAlive,
+    #[command(description = "Save the current session settings as a named preset")]
+    SavePreset(String),
+    #[command(description = "Browse public sessions you can join")]
+    Browse,
+    #[command(
+        description = "Review your previous sessions, or one in detail with /pastsessions [number]"
+    )]
+    PastSessions(String),
+    #[command(description = "View statistics for the current session")]
+    Stats,
+    #[command(description = "View or change session settings, e.g. /settings maxusers 6")]
+    Settings(String),
+    #[command(
+        description = "List the hourly backups, or roll every session back to one with /restorebackup [number] (bot admins only)"
+    )]
+    RestoreBackup(String),
 }
 
 // State shared between command handlers
 type SharedState = Arc<Mutex<SessionState>>;
 
+// How many videos /search offers to choose from
+const SEARCH_RESULTS: usize = 5;
+
+// Sites whose links in a plain message are looked at for songs to add
+const LINK_SITES: [&str; 7] = [
+    "youtube",
+    "youtu.be",
+    "vimeo.com",
+    "dailymotion.com",
+    "dai.ly",
+    "open.spotify.com",
+    "music.apple.com",
+];
+
+// Most songs one person can add from a playlist at once, so nobody takes over the night
+const PLAYLIST_IMPORT_LIMIT: usize = 25;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
@@ -56,29 +186,68 @@ async fn main() -> Result<()> {
         .map_err(|_| anyhow::anyhow!("TELEGRAM_BOT_TOKEN must be set"))?;
     let bot = Bot::new(bot_token);
 
-    let state = Arc::new(Mutex::new(SessionState::new()));
+    // The idle screen links to the bot so people can join by scanning a QR code
+    match bot.get_me().await {
+        Ok(me) => playback::set_bot_username(me.username()),
+        Err(e) => error!("Failed to look up the bot's username: {}", e),
+    }
+
+    store::create_data_dir()?;
+    let state = Arc::new(Mutex::new(SessionState::new()?));
+    updates::catch_up(&bot).await;
+
+    tokio::spawn(store::run_saver(state.clone()));
+    tokio::spawn(backup::run_backups(state.clone()));
+    tokio::spawn(scheduler::run_scheduler(bot.clone(), state.clone()));
+    tokio::spawn(scheduler::run_title_refresher(state.clone()));
+    tokio::spawn(cast::run_heartbeat());
+    tokio::spawn(cast::run_web_player());
+    // Reconnecting discovers devices, which shouldn't hold up startup
+    let restore_state = state.clone();
+    tokio::spawn(async move { playback::restore_cast_connections(&restore_state).await });
+    playback::spawn_subscribers(bot.clone(), state.clone());
+    playback::resume_auto_advance(&state).await;
 
-    let handler = Update::filter_message()
+    let handler = dptree::entry()
+        // Don't handle updates Telegram sends again after a crash a second time
+        .filter(|update: Update| updates::first_delivery(&update))
         .branch(
-            dptree::entry()
-                .filter_command::<Command>()
-                .endpoint(handle_command),
+            Update::filter_message()
+                .branch(dptree::entry().filter_command::<Command>().endpoint(
+                    |bot, msg, cmd, state, update| {
+                        updates::handle(update, handle_command(bot, msg, cmd, state))
+                    },
+                ))
+                .branch(
+                    dptree::filter(|msg: Message| {
+                        msg.text()
+                            .is_some_and(|text| LINK_SITES.iter().any(|site| text.contains(site)))
+                    })
+                    .endpoint(|bot, msg, state, update| {
+                        updates::handle(update, handle_youtube_message(bot, msg, state))
+                    }),
+                ),
         )
         .branch(
-            dptree::filter(|msg: Message| {
-                msg.text().is_some() && msg.text().unwrap().contains("youtube")
-                    || msg.text().is_some() && msg.text().unwrap().contains("youtu.be")
-            })
-            .endpoint(handle_youtube_message),
+            Update::filter_callback_query().endpoint(|bot, q, state, update| {
+                updates::handle(update, handle_callback_query(bot, q, state))
+            }),
         );
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![state])
+        .dependencies(dptree::deps![state.clone()])
+        // Updates no handler takes, e.g. other messages, are done with as they come
+        .default_handler(|update| async move { updates::finished(update.id) })
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
 
+    // Don't lose the last second of changes on the way out
+    if let Err(e) = store::save_pending(&state).await {
+        error!("Failed to save everything before stopping: {}", e);
+    }
+
     Ok(())
 }
 
@@ -102,40 +271,123 @@ async fn handle_command(
             )
         });
 
+        state.lock().await.touch_user(&user_id);
+
         match cmd {
-            Command::Help | Command::Start => {
+            // Scanning the QR code on the idle screen opens /start with the session code
+            Command::Start(code) if !code.trim().is_empty() => {
+                join_session(&bot, msg.chat.id, &state, user_id, username, &code).await?;
+            }
+            Command::Help | Command::Start(_) => {
                 bot.send_message(msg.chat.id, Command::descriptions().to_string())
                     .await?;
             }
-            Command::StartSession => {
+            Command::StartSession(args) => {
+                const USAGE: &str =
+                    "Usage: /start-session [--preset name] [--from old-code-or-archive-number [--all]]";
+
+                let mut preset_name = None;
+                let mut from = None;
+                let mut copy_full_queue = false;
+
+                let mut args = args.split_whitespace();
+                while let Some(arg) = args.next() {
+                    let value = match arg {
+                        "--preset" | "--from" => args.next(),
+                        _ => None,
+                    };
+
+                    match (arg, value) {
+                        ("--preset", Some(name)) => preset_name = Some(name),
+                        ("--from", Some(source)) => from = Some(source),
+                        ("--all", None) => copy_full_queue = true,
+                        _ => {
+                            bot.send_message(msg.chat.id, USAGE).await?;
+                            return Ok(());
+                        }
+                    }
+                }
+
+                let mut state_guard = state.lock().await;
+
+                if let Some(source) = from {
+                    if state_guard.archive.find(&user_id, source).is_none() {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "You don't have a past session matching {}. See /pastsessions",
+                                source
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                }
+
+                let session_code = match preset_name {
+                    None => state_guard.create_session(user_id, username.clone()),
+                    Some(preset_name) => {
+                        match state_guard.create_session_from_preset(
+                            user_id,
+                            username.clone(),
+                            preset_name,
+                        ) {
+                            Ok(session_code) => session_code,
+                            Err(e) => {
+                                bot.send_message(msg.chat.id, e.to_string()).await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                };
+
+                let mut reply = format!("Created new karaoke session with code: {}\nShare this code with friends to let them join!", session_code);
+
+                if let Some(source) = from {
+                    match state_guard.copy_archived_queue(&user_id, source, copy_full_queue) {
+                        Ok(copied) => {
+                            reply.push_str(&format!(
+                                "\n\nCopied {} songs from your past session.",
+                                copied
+                            ));
+                        }
+                        Err(e) => {
+                            error!("Error copying archived queue: {}", e);
+                            reply.push_str("\n\nCouldn't copy the queue from your past session.");
+                        }
+                    }
+                }
+
+                bot.send_message(msg.chat.id, reply).await?;
+            }
+            Command::ScheduleSession(start) => {
                 let mut state_guard = state.lock().await;
-                let session_code = state_guard.create_session(user_id, username.clone());
+                let (session_code, starts_at) =
+                    match state_guard.schedule_session(user_id, username.clone(), &start) {
+                        Ok(scheduled) => scheduled,
+                        Err(e) => {
+                            bot.send_message(msg.chat.id, e.to_string()).await?;
+                            return Ok(());
+                        }
+                    };
 
                 bot.send_message(
                     msg.chat.id,
-                    format!("Created new karaoke session with code: {}\nShare this code with friends to let them join!", session_code)
-                ).await?;
+                    format!(
+                        "Scheduled karaoke session with code: {}\nIt starts at {}. Friends can join and queue songs now, and everyone will get a reminder 15 minutes before.",
+                        session_code,
+                        state_guard.format_time(&user_id, starts_at)
+                    ),
+                )
+                .await?;
             }
             Command::Join(code) => {
-                let code = code.trim();
-                let mut state_guard = state.lock().await;
-
-                if state_guard.join_session(user_id, username.clone(), code) {
-                    bot.send_message(msg.chat.id, format!("You've joined session: {}", code))
-                        .await?;
-                } else {
-                    bot.send_message(
-                        msg.chat.id,
-                        "Invalid session code. Please check and try again.",
-                    )
-                    .await?;
-                }
+                join_session(&bot, msg.chat.id, &state, user_id, username, &code).await?;
             }
             Command::Add(input) => {
                 let input_cloned = input.clone();
-                let mut state_guard = state.lock().await;
 
-                if state_guard.is_in_session(&user_id) {
+                if state.lock().await.is_in_session(&user_id) {
                     // Extract YouTube URL from input
                     let input_parts: Vec<&str> = input_cloned.split_whitespace().collect();
 
@@ -157,35 +409,12 @@ async fn handle_command(
                         None
                     };
 
-                    if is_valid_youtube_url(&url) {
-                        match state_guard.add_to_queue(user_id, url, username, note).await {
-                            Ok(true) => {
-                                bot.send_message(
-                                    msg.chat.id,
-                                    "Added to queue! Type /queue to see current lineup.",
-                                )
-                                .await?;
-                            }
-                            Ok(false) => {
-                                // This case won't happen anymore since we removed duplicate check
-                                // but leaving the pattern match for future flexibility
-                                bot.send_message(
-                                    msg.chat.id,
-                                    "Added to queue! Type /queue to see current lineup.",
-                                )
-                                .await?;
-                            }
-                            Err(e) => {
-                                error!("Error adding to queue: {}", e);
-                                bot.send_message(
-                                    msg.chat.id,
-                                    "There was an error adding your video to the queue.",
-                                )
-                                .await?;
-                            }
-                        }
+                    if let Some(service) = parse_track_link(&url) {
+                        offer_track_matches(&bot, msg.chat.id, service, &url).await?;
+                    } else if is_valid_video_url(&url) {
+                        add_video(&bot, msg.chat.id, &state, user_id, username, &url, note).await?;
                     } else {
-                        bot.send_message(msg.chat.id, "Please provide a valid YouTube URL.")
+                        bot.send_message(msg.chat.id, invalid_url_reply(&url))
                             .await?;
                     }
                 } else {
@@ -195,6 +424,93 @@ async fn handle_command(
                     ).await?;
                 }
             }
+            Command::AddPlaylist(url) => {
+                let Some(playlist_id) = extract_playlist_id(url.trim()) else {
+                    bot.send_message(msg.chat.id, "Usage: /addplaylist [youtube playlist url]")
+                        .await?;
+                    return Ok(());
+                };
+
+                if !state.lock().await.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                // Ask for one more than the limit to know if any were left out
+                let mut video_ids =
+                    match fetch_playlist_video_ids(&playlist_id, PLAYLIST_IMPORT_LIMIT + 1).await {
+                        Ok(video_ids) => video_ids,
+                        Err(e) => {
+                            error!("Error reading playlist {}: {}", playlist_id, e);
+                            bot.send_message(
+                                msg.chat.id,
+                                format!("Couldn't read that playlist: {}", e),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    };
+
+                let over_limit = video_ids.len() > PLAYLIST_IMPORT_LIMIT;
+                video_ids.truncate(PLAYLIST_IMPORT_LIMIT);
+
+                // Look the videos up without holding the lock
+                let videos = fetch_video_infos(&video_ids).await;
+
+                let mut state_guard = state.lock().await;
+
+                match state_guard.add_playlist_videos(user_id, username, videos) {
+                    Ok(import) => {
+                        if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
+                            events::publish(PlaybackEvent::QueueUpdated {
+                                session_code: session_code.clone(),
+                            });
+                        }
+                        drop(state_guard);
+
+                        let mut reply = bulk_add_reply(&import, " from the playlist");
+                        if over_limit {
+                            reply.push_str(&format!(
+                                "\nOnly the first {} songs of a playlist can be added at once.",
+                                PLAYLIST_IMPORT_LIMIT
+                            ));
+                        }
+
+                        bot.send_message(msg.chat.id, reply).await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::Search(query) => {
+                let query = query.trim();
+
+                if query.is_empty() {
+                    bot.send_message(msg.chat.id, "Usage: /search [song name]")
+                        .await?;
+                    return Ok(());
+                }
+
+                if !state.lock().await.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                send_karaoke_matches(
+                    &bot,
+                    msg.chat.id,
+                    query,
+                    "Tap a video to add it to the queue:",
+                )
+                .await?;
+            }
             Command::Queue => {
                 let state_guard = state.lock().await;
 
@@ -208,6 +524,8 @@ async fn handle_command(
                             .await?;
                         } else {
                             let mut queue_text = "Current queue:\n".to_string();
+                            let wait_times =
+                                state_guard.get_wait_times(&user_id).unwrap_or_default();
 
                             for (i, item) in queue_items.iter().enumerate() {
                                 let note_text = match &item.note {
@@ -221,17 +539,31 @@ async fn handle_command(
                                     None => format!("Video ID: {}", item.video_info.id),
                                 };
 
-                                // Get the username or use a default
-                                let user_identifier = match &item.username {
-                                    Some(name) => name.clone(),
-                                    None => format!("User {}", item.added_by.0),
+                                let mut length = match video_label(&item.video_info) {
+                                    Some(label) => format!(" [{}]", label),
+                                    None => String::new(),
+                                };
+                                if item.video_info.age_restricted {
+                                    length.push_str(" [age-restricted]");
+                                }
+
+                                // Get the submitter's display name
+                                let user_identifier = state_guard.display_name(&user_id, item);
+
+                                let starts_in = match wait_times.get(i).copied().flatten() {
+                                    Some(wait) if wait > 0 => {
+                                        format!(" - in ~{}", format_duration(wait))
+                                    }
+                                    _ => String::new(),
                                 };
 
                                 queue_text.push_str(&format!(
-                                    "{}. {} (added by {}){}  \n",
+                                    "{}. {}{} (added by {}){}{}  \n",
                                     i + 1,
                                     video_name,
+                                    length,
                                     user_identifier,
+                                    starts_in,
                                     note_text
                                 ));
                             }
@@ -248,13 +580,40 @@ async fn handle_command(
             }
             Command::Leave => {
                 let mut state_guard = state.lock().await;
+                let session_code = state_guard.user_sessions.get(&user_id).cloned();
 
-                if state_guard.leave_session(&user_id) {
-                    bot.send_message(msg.chat.id, "You've left the session.")
-                        .await?;
-                } else {
-                    bot.send_message(msg.chat.id, "You're not in a session.")
+                match state_guard.leave_session(&user_id) {
+                    LeaveResult::Left => {
+                        bot.send_message(msg.chat.id, "You've left the session.")
+                            .await?;
+                    }
+                    LeaveResult::OwnerChanged(change) => {
+                        drop(state_guard);
+
+                        bot.send_message(msg.chat.id, "You've left the session.")
+                            .await?;
+                        announce_owner_change(&bot, &change, "the previous owner left").await;
+                    }
+                    LeaveResult::SessionEnded(stats, cast_target) => {
+                        drop(state_guard);
+
+                        if let (Some(session_code), Some(target)) = (&session_code, &cast_target) {
+                            clear_ended_session(session_code, target).await;
+                        }
+
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "You've left the session. You were the last one, so it has ended.\n\n{}",
+                                stats
+                            ),
+                        )
                         .await?;
+                    }
+                    LeaveResult::NotInSession => {
+                        bot.send_message(msg.chat.id, "You're not in a session.")
+                            .await?;
+                    }
                 }
             }
             Command::Next => {
@@ -274,49 +633,51 @@ async fn handle_command(
                     return Ok(());
                 }
 
-                match state_guard.next_in_queue(&user_id) {
-                    Some(next_item) => {
-                        // Get video title
-                        let video_title =
-                            next_item.video_info.title.clone().unwrap_or_else(|| {
-                                format!("Video ID: {}", next_item.video_info.id)
-                            });
-
-                        // Get username
-                        let user_name = next_item
-                            .username
-                            .clone()
-                            .unwrap_or_else(|| format!("User {}", next_item.added_by.0));
+                if let Some(starts_at) = state_guard.pending_start(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "This session hasn't started yet. Playback opens at {}.",
+                            state_guard.format_time(&user_id, starts_at)
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
 
-                        // Try to cast the video
-                        let video_info = next_item.video_info.clone();
+                let session_code = state_guard.user_sessions.get(&user_id).cloned();
 
+                match (state_guard.next_in_queue(&user_id), session_code) {
+                    (Some(next_item), Some(session_code)) => {
                         // Drop the mutex guard before the next await point to avoid deadlocks
                         drop(state_guard);
 
-                        // Try to cast the video
-                        match cast_video(&video_info, None).await {
-                            Ok(_) => {
-                                bot.send_message(
-                                    msg.chat.id,
-                                    format!(
-                                        "Now playing: {} (added by {})",
-                                        video_title, user_name
-                                    ),
-                                )
-                                .await?;
-                            }
-                            Err(e) => {
-                                error!("Error casting video: {}", e);
-                                bot.send_message(
-                                    msg.chat.id,
-                                    format!("Error casting video: {}", e),
-                                )
-                                .await?;
-                            }
+                        // Age-restricted videos rarely play on cast devices, so check first
+                        if next_item.video_info.age_restricted {
+                            bot.send_message(
+                                msg.chat.id,
+                                format!(
+                                    "{} is age-restricted on YouTube, so it probably won't play on the TV. Play it anyway or skip it?",
+                                    item_video_title(&next_item)
+                                ),
+                            )
+                            .reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                                InlineKeyboardButton::callback(
+                                    "Play anyway",
+                                    format!("age:play:{}", next_item.added_at),
+                                ),
+                                InlineKeyboardButton::callback(
+                                    "Skip it",
+                                    format!("age:skip:{}", next_item.added_at),
+                                ),
+                            ]]))
+                            .await?;
+                            return Ok(());
                         }
+
+                        start_song(&bot, &state, &session_code, msg.chat.id, next_item).await?;
                     }
-                    None => {
+                    _ => {
                         bot.send_message(
                             msg.chat.id,
                             "No more videos in the queue. Add videos with /add [youtube_url]",
@@ -325,105 +686,1176 @@ async fn handle_command(
                     }
                 }
             }
-            Command::Current => {
-                let state_guard = state.lock().await;
+            Command::Devices => {
+                let target = state.lock().await.get_cast_target(&user_id);
 
-                if !state_guard.is_in_session(&user_id) {
+                match get_available_devices().await {
+                    Ok(devices) if !devices.is_empty() => {
+                        let mut text = "Available cast devices:\n".to_string();
+                        for available in devices {
+                            let marker = if target.device.as_ref() == Some(&available.device)
+                                || target.audio_group.as_ref() == Some(&available.device)
+                            {
+                                " (selected)"
+                            } else {
+                                ""
+                            };
+                            let kind = if available.audio_only {
+                                ", audio only"
+                            } else {
+                                ""
+                            };
+                            text.push_str(&format!("- {}{}{}\n", available.device, kind, marker));
+                        }
+                        text.push_str(
+                            "\nChoose one with /castto [name]. Picking a speaker or speaker group plays the audio there too, while the video stays on the TV.",
+                        );
+
+                        bot.send_message(msg.chat.id, text).await?;
+                    }
+                    Ok(_) => {
+                        bot.send_message(msg.chat.id, "No cast devices found on the network.")
+                            .await?;
+                    }
+                    Err(e) => {
+                        error!("Error discovering cast devices: {}", e);
+                        bot.send_message(
+                            msg.chat.id,
+                            "There was an error looking for cast devices.",
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::CastTo(name) => {
+                let name = name.trim();
+
+                if name.is_empty() {
                     bot.send_message(
                         msg.chat.id,
-                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
-                    ).await?;
+                        "Usage: /castto [device name] or /castto audio off. See /devices",
+                    )
+                    .await?;
                     return Ok(());
                 }
 
-                match state_guard.get_current_video(&user_id) {
-                    Some(video) => {
-                        let video_title = video
-                            .title
-                            .clone()
-                            .unwrap_or_else(|| format!("Video ID: {}", video.id));
+                if !state.lock().await.is_session_owner(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "Only the session owner can choose the cast device.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                if name.eq_ignore_ascii_case("audio off") {
+                    state.lock().await.set_audio_group(&user_id, None);
+                    bot.send_message(msg.chat.id, "Audio will only play on the TV.")
+                        .await?;
+                    return Ok(());
+                }
+
+                // Look the device up without holding the lock, discovery takes a while
+                let device = match get_available_devices().await {
+                    Ok(devices) => devices.into_iter().find(|available| {
+                        available.device.name.eq_ignore_ascii_case(name)
+                            || available.device.to_string().eq_ignore_ascii_case(name)
+                    }),
+                    Err(e) => {
+                        error!("Error discovering cast devices: {}", e);
+                        None
+                    }
+                };
+
+                match device {
+                    Some(available) if available.audio_only => {
+                        state
+                            .lock()
+                            .await
+                            .set_audio_group(&user_id, Some(&available.device.name));
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Audio will also play on {}. Turn it off with /castto audio off",
+                                available.device
+                            ),
+                        )
+                        .await?;
+                    }
+                    Some(available) => {
+                        let idle_session = {
+                            let mut state_guard = state.lock().await;
+                            state_guard.set_cast_device(&user_id, &available.device);
+                            state_guard
+                                .get_current_video(&user_id)
+                                .is_none()
+                                .then(|| state_guard.user_sessions.get(&user_id).cloned())
+                                .flatten()
+                        };
 
                         bot.send_message(
                             msg.chat.id,
-                            format!("Currently playing: {}\nLink: {}", video_title, video.url),
+                            format!("Videos will now play on {}.", available.device),
                         )
                         .await?;
+
+                        // Show how to join until the first song starts
+                        if let Some(session_code) = idle_session {
+                            show_idle_screen(&state, &session_code).await;
+                        }
                     }
                     None => {
                         bot.send_message(
                             msg.chat.id,
-                            "No video is currently playing. Use /next to play the next video in queue."
-                        ).await?;
+                            format!("Couldn't find a device called {}. See /devices", name),
+                        )
+                        .await?;
                     }
                 }
             }
-            Command::History => {
+            cmd @ (Command::Pause | Command::Resume | Command::Stop) => {
                 let state_guard = state.lock().await;
 
-                if !state_guard.is_in_session(&user_id) {
-                    bot.send_message(
-                        msg.chat.id,
-                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
-                    ).await?;
+                if !state_guard.is_session_owner(&user_id) {
+                    bot.send_message(msg.chat.id, "Only the session owner can control playback.")
+                        .await?;
                     return Ok(());
                 }
 
-                match state_guard.get_history(&user_id) {
-                    Some(history_items) if !history_items.is_empty() => {
-                        let mut history_text = "Previously played videos:\n".to_string();
+                if state_guard.get_current_video(&user_id).is_none() {
+                    bot.send_message(msg.chat.id, "No video is currently playing.")
+                        .await?;
+                    return Ok(());
+                }
 
-                        for (i, item) in history_items.iter().enumerate() {
-                            let video_title = item
-                                .video_info
-                                .title
-                                .clone()
-                                .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+                let target = state_guard.get_cast_target(&user_id);
 
-                            let user_name = item
-                                .username
-                                .clone()
-                                .unwrap_or_else(|| format!("User {}", item.added_by.0));
+                // Drop the mutex guard while talking to the device
+                drop(state_guard);
 
-                            history_text.push_str(&format!(
-                                "{}. {} (added by {})\n",
-                                i + 1,
-                                video_title,
-                                user_name
-                            ));
+                let (result, reply) = match cmd {
+                    Command::Pause => (pause_casting(&target).await, "Paused."),
+                    Command::Resume => (resume_casting(&target).await, "Resumed."),
+                    _ => (stop_casting(&target).await, "Stopped playback."),
+                };
+
+                match result {
+                    Ok(_) => {
+                        let mut state_guard = state.lock().await;
+                        match cmd {
+                            Command::Pause => state_guard.set_playing(&user_id, false),
+                            Command::Resume => state_guard.set_playing(&user_id, true),
+                            _ => state_guard.stop_playback(&user_id),
                         }
+                        let session_code = state_guard.user_sessions.get(&user_id).cloned();
+                        drop(state_guard);
 
-                        bot.send_message(msg.chat.id, history_text).await?;
+                        bot.send_message(msg.chat.id, reply).await?;
+
+                        if let (Command::Stop, Some(session_code)) = (cmd, session_code) {
+                            show_idle_screen(&state, &session_code).await;
+                        }
                     }
-                    _ => {
-                        bot.send_message(
-                            msg.chat.id,
-                            "No videos have been played yet in this session.",
-                        )
-                        .await?;
+                    Err(e) => {
+                        error!("Error controlling playback: {}", e);
+                        bot.send_message(msg.chat.id, format!("Error controlling playback: {}", e))
+                            .await?;
                     }
                 }
             }
-            Command::Id => {
-                let state_guard = state.lock().await;
-                if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
-                    bot.send_message(msg.chat.id, format!("{}", session_code))
+            Command::Seek(position) => {
+                let Some(position) = parse_position(&position) else {
+                    bot.send_message(msg.chat.id, "Usage: /seek [mm:ss], e.g. /seek 1:30")
                         .await?;
-                } else {
+                    return Ok(());
+                };
+
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_session_owner(&user_id) {
+                    bot.send_message(msg.chat.id, "Only the session owner can control playback.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let Some(video) = state_guard.get_current_video(&user_id) else {
+                    bot.send_message(msg.chat.id, "No video is currently playing.")
+                        .await?;
+                    return Ok(());
+                };
+
+                if let Some(duration) = video.duration {
+                    if position >= duration {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "That's past the end of the song, which is only {} long.",
+                                format_duration(duration as i64)
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                }
+
+                let target = state_guard.get_cast_target(&user_id);
+
+                // Drop the mutex guard while talking to the device
+                drop(state_guard);
+
+                match seek_to(&target, position).await {
+                    Ok(_) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("Jumped to {}.", format_duration(position as i64)),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        error!("Error seeking: {}", e);
+                        bot.send_message(msg.chat.id, format!("Error seeking: {}", e))
+                            .await?;
+                    }
+                }
+            }
+            Command::Volume(level) => {
+                let level = level.trim().to_lowercase();
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                if level.is_empty() {
+                    let reply = match state_guard.get_cast_status(&user_id) {
+                        Some(status) if status.muted => "The cast device is muted.".to_string(),
+                        Some(status) => match status.volume {
+                            Some(volume) => format!("Volume is at {}%.", volume),
+                            None => "Volume hasn't been set yet. Use /volume [0-100]".to_string(),
+                        },
+                        None => String::new(),
+                    };
+                    bot.send_message(msg.chat.id, reply).await?;
+                    return Ok(());
+                }
+
+                if !state_guard.is_session_owner(&user_id) {
+                    bot.send_message(msg.chat.id, "Only the session owner can change the volume.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let cast_device = state_guard.get_cast_device(&user_id);
+
+                // Drop the mutex guard while talking to the device
+                drop(state_guard);
+
+                let (result, volume, muted, reply) = match level.as_str() {
+                    "mute" => (
+                        set_muted(cast_device.as_ref(), true).await,
+                        None,
+                        true,
+                        "Muted.".to_string(),
+                    ),
+                    "unmute" => (
+                        set_muted(cast_device.as_ref(), false).await,
+                        None,
+                        false,
+                        "Unmuted.".to_string(),
+                    ),
+                    level => match level.trim_end_matches('%').parse::<u8>() {
+                        Ok(volume) if volume <= 100 => (
+                            set_volume(cast_device.as_ref(), volume).await,
+                            Some(volume),
+                            false,
+                            format!("Volume set to {}%.", volume),
+                        ),
+                        _ => {
+                            bot.send_message(
+                                msg.chat.id,
+                                "Usage: /volume [0-100], /volume mute or /volume unmute",
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    },
+                };
+
+                match result {
+                    Ok(_) => {
+                        state.lock().await.set_volume(&user_id, volume, muted);
+                        bot.send_message(msg.chat.id, reply).await?;
+                    }
+                    Err(e) => {
+                        error!("Error changing volume: {}", e);
+                        bot.send_message(msg.chat.id, format!("Error changing volume: {}", e))
+                            .await?;
+                    }
+                }
+            }
+            Command::CastInfo => {
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                let cast_device = state_guard.get_cast_device(&user_id);
+                let current_video = state_guard.get_current_video(&user_id).cloned();
+
+                // Drop the mutex guard while talking to the device
+                drop(state_guard);
+
+                let status = match get_media_status(cast_device.as_ref()).await {
+                    Ok(status) => status,
+                    Err(e) => {
+                        error!("Error getting media status: {}", e);
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("Couldn't reach the cast device: {}", e),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                };
+
+                let mut text = format!(
+                    "Device: {}\nState: {}",
+                    cast_device
+                        .as_ref()
+                        .map(|device| device.to_string())
+                        .unwrap_or_else(|| DEFAULT_DEVICE.to_string()),
+                    describe_player_state(&status)
+                );
+
+                if let Some(video) = current_video {
+                    let video_title = video
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| format!("Video ID: {}", video.id));
+                    text.push_str(&format!("\nVideo: {}", video_title));
+                }
+
+                if status.player_state != PlayerState::Idle {
+                    let position = format_duration(status.position as i64);
+                    match status.duration {
+                        Some(duration) => text.push_str(&format!(
+                            "\nPosition: {} of {}",
+                            position,
+                            format_duration(duration as i64)
+                        )),
+                        None => text.push_str(&format!("\nPosition: {}", position)),
+                    }
+                }
+
+                match get_volume(cast_device.as_ref()).await {
+                    Ok(Some(volume)) if volume.muted => {
+                        text.push_str(&format!("\nVolume: {}% (muted)", volume.level))
+                    }
+                    Ok(Some(volume)) => text.push_str(&format!("\nVolume: {}%", volume.level)),
+                    Ok(None) => {}
+                    Err(e) => error!("Error getting volume: {}", e),
+                }
+
+                bot.send_message(msg.chat.id, text).await?;
+            }
+            Command::CastDiag => {
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                if !state_guard.is_session_owner(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "Only the session owner can run cast diagnostics.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let selected = state_guard.get_cast_device(&user_id);
+
+                // Drop the mutex guard while searching, discovery takes a few seconds
+                drop(state_guard);
+
+                bot.send_message(msg.chat.id, "Looking for cast devices...")
+                    .await?;
+
+                let report = diagnose().await;
+
+                let mut found = 0;
+                let mut connected = Vec::new();
+                let mut details = String::new();
+
+                for backend in &report {
+                    match &backend.devices {
+                        Ok(devices) if devices.is_empty() => {
+                            details.push_str(&format!("\n{}: no devices found\n", backend.label))
+                        }
+                        Ok(devices) => {
+                            details.push_str(&format!(
+                                "\n{}: found {}\n",
+                                backend.label,
+                                devices.len()
+                            ));
+
+                            for diagnostic in devices {
+                                found += 1;
+                                let kind = if diagnostic.audio_only {
+                                    " (audio only)"
+                                } else {
+                                    ""
+                                };
+
+                                let result = match (&diagnostic.connection, &diagnostic.status) {
+                                    (Err(e), _) => format!("couldn't connect: {}", e),
+                                    (Ok(()), Some(Ok(status))) => {
+                                        connected.push(diagnostic.device.name.clone());
+                                        format!(
+                                            "connected, receiver {}",
+                                            describe_player_state(status).to_lowercase()
+                                        )
+                                    }
+                                    (Ok(()), Some(Err(e))) => {
+                                        connected.push(diagnostic.device.name.clone());
+                                        format!("connected, but no receiver status: {}", e)
+                                    }
+                                    (Ok(()), None) => "connected".to_string(),
+                                };
+
+                                details.push_str(&format!(
+                                    "- {}{}: {}\n",
+                                    diagnostic.device.name, kind, result
+                                ));
+                            }
+                        }
+                        Err(e) => details
+                            .push_str(&format!("\n{}: discovery failed: {}\n", backend.label, e)),
+                    }
+                }
+
+                let mut text = if connected.is_empty() {
+                    format!(
+                        "Found {} device{}, couldn't connect to any.\n",
+                        found,
+                        if found == 1 { "" } else { "s" }
+                    )
+                } else {
+                    format!(
+                        "Found {} device{}, connected to {}.\n",
+                        found,
+                        if found == 1 { "" } else { "s" },
+                        connected.join(", ")
+                    )
+                };
+                text.push_str(&details);
+
+                match selected {
+                    Some(device) => {
+                        let discovered = report.iter().any(|backend| {
+                            backend.devices.as_ref().is_ok_and(|devices| {
+                                devices.iter().any(|diagnostic| diagnostic.device == device)
+                            })
+                        });
+                        text.push_str(&format!(
+                            "\nThis session casts to {}{}",
+                            device,
+                            if discovered {
+                                "."
+                            } else {
+                                ", which wasn't found. Choose another with /castto [name]"
+                            }
+                        ));
+                    }
+                    None => text.push_str(&format!(
+                        "\nThis session casts to the {}. Choose a device with /castto [name]",
+                        DEFAULT_DEVICE
+                    )),
+                }
+
+                bot.send_message(msg.chat.id, text).await?;
+            }
+            Command::Current => {
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                match state_guard.get_current_video(&user_id) {
+                    Some(video) => {
+                        let video_title = video
+                            .title
+                            .clone()
+                            .unwrap_or_else(|| format!("Video ID: {}", video.id));
+
+                        let length = match video_label(video) {
+                            Some(label) => format!(" ({})", label),
+                            None => String::new(),
+                        };
+
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Currently playing: {}{}\nLink: {}",
+                                video_title, length, video.url
+                            ),
+                        )
+                        .await?;
+                    }
+                    None => {
+                        bot.send_message(
+                            msg.chat.id,
+                            "No video is currently playing. Use /next to play the next video in queue."
+                        ).await?;
+                    }
+                }
+            }
+            Command::Lyrics => {
+                let video_title = {
+                    let state_guard = state.lock().await;
+
+                    if !state_guard.is_in_session(&user_id) {
+                        bot.send_message(
+                            msg.chat.id,
+                            "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                        ).await?;
+                        return Ok(());
+                    }
+
+                    state_guard
+                        .get_current_video(&user_id)
+                        .and_then(|video| video.title.clone())
+                };
+
+                let Some(video_title) = video_title else {
+                    bot.send_message(msg.chat.id, "No song is playing right now.")
+                        .await?;
+                    return Ok(());
+                };
+
+                match lyrics::find_lyrics(&video_title).await {
+                    Ok(Some(found)) => {
+                        let pages = lyrics::paginate(&found.text);
+                        let page_count = pages.len();
+
+                        // Lyrics are long, so they go to the user rather than the group
+                        let mut sent = true;
+                        for (i, page) in pages.into_iter().enumerate() {
+                            let header = if page_count > 1 {
+                                format!(
+                                    "{} - {} ({}/{})",
+                                    found.artist,
+                                    found.track,
+                                    i + 1,
+                                    page_count
+                                )
+                            } else {
+                                format!("{} - {}", found.artist, found.track)
+                            };
+                            if let Err(e) = bot
+                                .send_message(user_id, format!("{}\n\n{}", header, page))
+                                .await
+                            {
+                                error!("Failed to send lyrics to {}: {}", user_id, e);
+                                sent = false;
+                                break;
+                            }
+                        }
+
+                        if !sent {
+                            // Bots can't message people who haven't talked to them first
+                            bot.send_message(
+                                msg.chat.id,
+                                "I couldn't message you. Start a private chat with me first, then try /lyrics again.",
+                            )
+                            .await?;
+                        } else if !msg.chat.is_private() {
+                            bot.send_message(msg.chat.id, "I've sent you the lyrics privately.")
+                                .await?;
+                        }
+                    }
+                    Ok(None) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("Couldn't find lyrics for {}.", video_title),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        error!("Error looking up lyrics: {}", e);
+                        bot.send_message(msg.chat.id, "There was an error looking up the lyrics.")
+                            .await?;
+                    }
+                }
+            }
+            Command::History => {
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                match state_guard.get_history(&user_id) {
+                    Some(history_items) if !history_items.is_empty() => {
+                        let mut history_text = "Previously played videos:\n".to_string();
+
+                        for (i, item) in history_items.iter().enumerate() {
+                            let video_title = item
+                                .video_info
+                                .title
+                                .clone()
+                                .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+
+                            let user_name = state_guard.display_name(&user_id, item);
+
+                            let played_at = match item.played_at {
+                                Some(played_at) => {
+                                    format!(" at {}", state_guard.format_time(&user_id, played_at))
+                                }
+                                None => String::new(),
+                            };
+
+                            let failed = match &item.failed {
+                                Some(reason) => format!(" \u{2014} skipped: {}", reason),
+                                None => String::new(),
+                            };
+
+                            history_text.push_str(&format!(
+                                "{}. {} (added by {}){}{}\n",
+                                i + 1,
+                                video_title,
+                                user_name,
+                                played_at,
+                                failed
+                            ));
+                        }
+
+                        bot.send_message(msg.chat.id, history_text).await?;
+                    }
+                    _ => {
+                        bot.send_message(
+                            msg.chat.id,
+                            "No videos have been played yet in this session.",
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::Id => {
+                let state_guard = state.lock().await;
+                if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
+                    bot.send_message(msg.chat.id, session_code.to_string())
+                        .await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                }
+            }
+            Command::Session => {
+                let state_guard = state.lock().await;
+                if let Some(session_info) = state_guard.get_session_info(&user_id) {
+                    bot.send_message(msg.chat.id, session_info).await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                }
+            }
+            Command::Nickname(nickname) => {
+                let nickname = nickname.trim();
+
+                if nickname.is_empty() {
+                    bot.send_message(msg.chat.id, "Usage: /nickname [name]")
+                        .await?;
+                    return Ok(());
+                }
+
+                let mut state_guard = state.lock().await;
+
+                if state_guard.set_nickname(&user_id, nickname) {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("You'll now show up as {} in this session.", nickname),
+                    )
+                    .await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                }
+            }
+            Command::Mute(args) => {
+                let args = args.trim();
+                if args.is_empty() {
+                    bot.send_message(msg.chat.id, "Usage: /mute @user [minutes]")
+                        .await?;
+                    return Ok(());
+                }
+
+                // Display names can have spaces, so only a number at the end is the minutes
+                let (target, minutes) = match args.rsplit_once(char::is_whitespace) {
+                    Some((target, minutes)) => match minutes.parse::<i64>() {
+                        Ok(minutes) if minutes > 0 => (target.trim(), Some(minutes)),
+                        Ok(_) => {
+                            bot.send_message(msg.chat.id, "Minutes must be a positive number.")
+                                .await?;
+                            return Ok(());
+                        }
+                        Err(_) => (args, None),
+                    },
+                    None => (args, None),
+                };
+
+                let mut state_guard = state.lock().await;
+
+                match state_guard.mute_user(&user_id, target, minutes) {
+                    Ok(name) => {
+                        let duration = match minutes {
+                            Some(minutes) => format!("for {} minutes", minutes),
+                            None => "until you /unmute them".to_string(),
+                        };
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("{} can't add songs {}.", name, duration),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::Unmute(target) => {
+                let mut state_guard = state.lock().await;
+
+                match state_guard.unmute_user(&user_id, target.trim()) {
+                    Ok(name) => {
+                        bot.send_message(msg.chat.id, format!("{} can add songs again.", name))
+                            .await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::Backup(target) => {
+                let mut state_guard = state.lock().await;
+
+                match state_guard.set_backup_owner(&user_id, target.trim()) {
+                    Ok(name) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "{} will take over the session if you leave or go inactive.",
+                                name
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::Merge(other_code) => {
+                let other_code = normalize_session_code(&other_code);
+
+                if other_code.is_empty() {
+                    bot.send_message(msg.chat.id, "Usage: /merge [session code]")
+                        .await?;
+                    return Ok(());
+                }
+
+                let mut state_guard = state.lock().await;
+                let own_code = state_guard.user_sessions.get(&user_id).cloned();
+
+                match state_guard.merge_sessions(&user_id, &other_code) {
+                    Ok(MergeResult::Requested { other_owner }) => {
+                        drop(state_guard);
+
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Asked the owner of session {} to merge. The sessions will be combined once they agree.",
+                                other_code
+                            ),
+                        )
+                        .await?;
+
+                        if let Some(own_code) = own_code {
+                            bot.send_message(
+                                other_owner,
+                                format!(
+                                    "The owner of session {} wants to merge it with your session. Reply /merge {} to combine them.",
+                                    own_code, own_code
+                                ),
+                            )
+                            .await?;
+                        }
+                    }
+                    Ok(MergeResult::Merged {
+                        code,
+                        merged_code,
+                        members,
+                    }) => {
+                        drop(state_guard);
+
+                        for member in members {
+                            if let Err(e) = bot
+                                .send_message(
+                                    member,
+                                    format!(
+                                        "Sessions {} and {} have been merged. Your session code is now {}.",
+                                        code, merged_code, code
+                                    ),
+                                )
+                                .await
+                            {
+                                error!("Failed to announce merge to {}: {}", member, e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::RefreshTitles => {
+                let state_guard = state.lock().await;
+
+                if !state_guard.is_session_owner(&user_id) {
+                    bot.send_message(msg.chat.id, "Only the session owner can refresh titles.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let session_code = state_guard.user_sessions.get(&user_id).cloned();
+                let videos = state_guard.videos_needing_titles(session_code.as_deref());
+                // Look the videos up without holding the lock
+                drop(state_guard);
+
+                if videos.is_empty() {
+                    bot.send_message(msg.chat.id, "Every song in the queue has its title.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let refetched = refetch_video_infos(&videos).await;
+                let found = refetched
+                    .iter()
+                    .filter(|video| !video.needs_title())
+                    .count();
+                let updated_sessions = state.lock().await.update_video_details(&refetched);
+
+                for session_code in updated_sessions {
+                    events::publish(PlaybackEvent::QueueUpdated { session_code });
+                }
+
+                let reply = if found == videos.len() {
+                    format!(
+                        "Found the titles of {} song{}.",
+                        found,
+                        if found == 1 { "" } else { "s" }
+                    )
+                } else {
+                    format!(
+                        "Found the titles of {} of {} songs. The rest will be tried again in a while.",
+                        found,
+                        videos.len()
+                    )
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+            }
+            Command::KeepAlive => {
+                // Every command counts as activity, so the session is already kept alive here
+                let state_guard = state.lock().await;
+
+                if state_guard.is_in_session(&user_id) {
+                    bot.send_message(msg.chat.id, "Got it, the session will stay open.")
+                        .await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                }
+            }
+            Command::SavePreset(preset_name) => {
+                let preset_name = preset_name.trim();
+                let mut state_guard = state.lock().await;
+
+                if preset_name.is_empty() || preset_name.contains(char::is_whitespace) {
+                    let names = state_guard.get_preset_names(&user_id);
+                    let saved = if names.is_empty() {
+                        "You don't have any saved presets yet.".to_string()
+                    } else {
+                        format!("Your presets: {}", names.join(", "))
+                    };
+
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Usage: /savepreset [name] (a single word)\n{}", saved),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                if !state_guard.is_in_session(&user_id) {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                    return Ok(());
+                }
+
+                match state_guard.save_preset(&user_id, preset_name) {
+                    Ok(()) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Saved preset {}. Use it with /start-session --preset {}",
+                                preset_name.to_lowercase(),
+                                preset_name.to_lowercase()
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
+                }
+            }
+            Command::Browse => {
+                let state_guard = state.lock().await;
+                let public_sessions = state_guard.get_public_sessions();
+
+                if public_sessions.is_empty() {
+                    bot.send_message(
+                        msg.chat.id,
+                        "There are no public sessions open right now. Start your own with /start-session",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let buttons: Vec<Vec<InlineKeyboardButton>> = public_sessions
+                    .iter()
+                    .map(|session| {
+                        vec![InlineKeyboardButton::callback(
+                            format!("{} ({} singers)", session.title, session.members),
+                            format!("join:{}", session.code),
+                        )]
+                    })
+                    .collect();
+
+                bot.send_message(msg.chat.id, "Open sessions - tap one to join:")
+                    .reply_markup(InlineKeyboardMarkup::new(buttons))
+                    .await?;
+            }
+            Command::PastSessions(id) => {
+                let state_guard = state.lock().await;
+                let id = id.trim();
+
+                if id.is_empty() {
+                    let past_sessions = state_guard.archive.sessions_for(&user_id);
+
+                    if past_sessions.is_empty() {
+                        bot.send_message(msg.chat.id, "You don't have any past sessions.")
+                            .await?;
+                    } else {
+                        let mut text = "Your past sessions:\n".to_string();
+                        for archived in past_sessions {
+                            text.push_str(&format!("{}\n", archived.summary()));
+                        }
+                        text.push_str("\nSee one in detail with /pastsessions [number]");
+
+                        bot.send_message(msg.chat.id, text).await?;
+                    }
+                    return Ok(());
+                }
+
+                match id
+                    .trim_start_matches('#')
+                    .parse()
+                    .ok()
+                    .and_then(|id| state_guard.archive.get(&user_id, id))
+                {
+                    Some(archived) => {
+                        bot.send_message(msg.chat.id, archived.details()).await?;
+                    }
+                    None => {
+                        bot.send_message(msg.chat.id, "No past session with that number.")
+                            .await?;
+                    }
+                }
+            }
+            Command::Stats => {
+                let state_guard = state.lock().await;
+                if let Some(stats) = state_guard.get_stats(&user_id) {
+                    bot.send_message(msg.chat.id, stats).await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
+                    ).await?;
+                }
+            }
+            Command::RestoreBackup(args) => {
+                if !is_admin(&user_id) {
+                    bot.send_message(msg.chat.id, "Only the bot's admins can restore backups.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let backups = match list_backups() {
+                    Ok(backups) => backups,
+                    Err(e) => {
+                        error!("Error listing backups: {}", e);
+                        bot.send_message(msg.chat.id, "There was an error listing the backups.")
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                let args = args.trim();
+                if args.is_empty() {
+                    let reply = if backups.is_empty() {
+                        "There are no backups yet. One is taken every hour.".to_string()
+                    } else {
+                        let list: Vec<String> = backups
+                            .iter()
+                            .enumerate()
+                            .map(|(i, backup)| {
+                                format!("{}. {}", i + 1, format_timestamp(backup.taken_at, None))
+                            })
+                            .collect();
+                        format!(
+                            "Backups, newest first:\n{}\n\nRoll every session back to one with /restorebackup [number]",
+                            list.join("\n")
+                        )
+                    };
+                    bot.send_message(msg.chat.id, reply).await?;
+                    return Ok(());
+                }
+
+                let Some(backup) = args
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| number.checked_sub(1))
+                    .and_then(|index| backups.get(index))
+                else {
                     bot.send_message(
                         msg.chat.id,
-                        "You're not in a session. Join one with /join [code] or start your own with /start-session"
-                    ).await?;
+                        "Usage: /restorebackup [number], see /restorebackup for the list",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let restored = match load_backup(backup) {
+                    Ok(restored) => restored,
+                    Err(e) => {
+                        error!("Error loading backup: {}", e);
+                        bot.send_message(msg.chat.id, format!("Couldn't read that backup: {}", e))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                // Snapshot the sessions as they are first, so the restore can be undone
+                if let Err(e) = create_backup(&state).await {
+                    error!("Error backing up before restoring: {}", e);
+                    bot.send_message(
+                        msg.chat.id,
+                        "Couldn't back up the sessions as they are now, so nothing was restored.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let session_codes = state.lock().await.restore(restored);
+                info!(
+                    "Restored the backup from {} ({} sessions)",
+                    format_timestamp(backup.taken_at, None),
+                    session_codes.len()
+                );
+                for session_code in &session_codes {
+                    events::publish(PlaybackEvent::QueueUpdated {
+                        session_code: session_code.clone(),
+                    });
                 }
+
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Restored the backup from {}, with {} session{}. The sessions as they were before are now backup 1, in case you need them back.",
+                        format_timestamp(backup.taken_at, None),
+                        session_codes.len(),
+                        if session_codes.len() == 1 { "" } else { "s" }
+                    ),
+                )
+                .await?;
             }
-            Command::Session => {
-                let state_guard = state.lock().await;
-                if let Some(session_info) = state_guard.get_session_info(&user_id) {
-                    bot.send_message(msg.chat.id, session_info).await?;
-                } else {
+            Command::Settings(args) => {
+                let mut state_guard = state.lock().await;
+
+                if !state_guard.is_in_session(&user_id) {
                     bot.send_message(
                         msg.chat.id,
                         "You're not in a session. Join one with /join [code] or start your own with /start-session"
                     ).await?;
+                    return Ok(());
+                }
+
+                let args: Vec<&str> = args.split_whitespace().collect();
+
+                match args.as_slice() {
+                    [] => {
+                        if let Some(settings) = state_guard.get_settings(&user_id) {
+                            bot.send_message(msg.chat.id, settings).await?;
+                        }
+                    }
+                    [name, value @ ..] if !value.is_empty() => {
+                        match state_guard.update_setting(&user_id, name, &value.join(" ")) {
+                            Ok(message) => {
+                                bot.send_message(msg.chat.id, message).await?;
+                            }
+                            Err(e) => {
+                                bot.send_message(msg.chat.id, e.to_string()).await?;
+                            }
+                        }
+                    }
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /settings [name] [value]")
+                            .await?;
+                    }
                 }
             }
         }
@@ -435,6 +1867,613 @@ async fn handle_command(
     Ok(())
 }
 
+// Add the user to a session by its code, from /join or a join link
+async fn join_session(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+    user_id: UserId,
+    username: Option<String>,
+    code: &str,
+) -> ResponseResult<()> {
+    let code = normalize_session_code(code);
+    let mut state_guard = state.lock().await;
+
+    match state_guard.join_session(user_id, username, &code) {
+        JoinResult::Joined => {
+            bot.send_message(chat_id, format!("You've joined session: {}", code))
+                .await?;
+        }
+        JoinResult::SessionFull(max_users) => {
+            bot.send_message(
+                chat_id,
+                format!(
+                    "Sorry, session {} is full ({} users max). Ask the owner to make room.",
+                    code, max_users
+                ),
+            )
+            .await?;
+        }
+        JoinResult::NotFound => {
+            bot.send_message(chat_id, "Invalid session code. Please check and try again.")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Reply for a song added to the queue, with a rough idea of when it'll come up
+fn added_to_queue_reply(state: &SessionState, user_id: &UserId) -> String {
+    // The song just added is the last one in the queue
+    match state
+        .get_wait_times(user_id)
+        .and_then(|wait_times| wait_times.last().copied().flatten())
+    {
+        Some(wait) if wait > 0 => format!(
+            "Added to queue! It should come up in about {}. Type /queue to see current lineup.",
+            format_duration(wait)
+        ),
+        _ => "Added to queue! Type /queue to see current lineup.".to_string(),
+    }
+}
+
+// Play a song on the session's device and announce it in `chat_id`, then keep the
+// queue moving when it finishes
+async fn start_song(
+    bot: &Bot,
+    state: &SharedState,
+    session_code: &str,
+    chat_id: ChatId,
+    item: QueueItem,
+) -> ResponseResult<()> {
+    match play_or_skip(bot, state, session_code, item).await {
+        Ok(announcement) => {
+            let thumbnail = state
+                .lock()
+                .await
+                .current_item(session_code)
+                .and_then(|item| item.video_info.thumbnail);
+            send_with_thumbnail(bot, chat_id, announcement, thumbnail.as_deref()).await?;
+
+            // Keep the queue moving when this song finishes
+            spawn_auto_advance(state.clone(), session_code.to_string(), chat_id);
+        }
+        Err(e) => {
+            error!("Error casting video: {}", e);
+            bot.send_message(chat_id, format!("Error casting video: {}", e))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether a user is one of the bot's admins, listed by Telegram user ID in KARAOKE_ADMINS
+fn is_admin(user_id: &UserId) -> bool {
+    env::var("KARAOKE_ADMINS").is_ok_and(|admins| {
+        admins
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .any(|admin| admin.parse::<u64>() == Ok(user_id.0))
+    })
+}
+
+// Who uploaded a video, how long it is and where it starts if the link said,
+// e.g. "Sing King – 4m 12s, from 0m 30s"
+fn video_label(video: &VideoInfo) -> Option<String> {
+    let length = video
+        .duration
+        .map(|duration| format_duration(duration as i64));
+
+    let label = match (&video.channel_title, length) {
+        (Some(channel), Some(length)) => Some(format!("{} – {}", channel, length)),
+        (Some(channel), None) => Some(channel.clone()),
+        (None, Some(length)) => Some(length),
+        (None, None) => None,
+    };
+
+    match (label, video.start_time) {
+        (Some(label), Some(start)) => {
+            Some(format!("{}, from {}", label, format_duration(start as i64)))
+        }
+        (None, Some(start)) => Some(format!("from {}", format_duration(start as i64))),
+        (label, None) => label,
+    }
+}
+
+// Search YouTube for karaoke versions of a song and offer them as buttons to add
+async fn send_karaoke_matches(
+    bot: &Bot,
+    chat_id: ChatId,
+    query: &str,
+    prompt: &str,
+) -> ResponseResult<()> {
+    match search_karaoke_videos(query, SEARCH_RESULTS).await {
+        Ok(results) if !results.is_empty() => {
+            let buttons: Vec<Vec<InlineKeyboardButton>> = results
+                .into_iter()
+                .map(|result| {
+                    vec![InlineKeyboardButton::callback(
+                        result.title,
+                        format!("add:{}", result.id),
+                    )]
+                })
+                .collect();
+
+            bot.send_message(chat_id, prompt)
+                .reply_markup(InlineKeyboardMarkup::new(buttons))
+                .await?;
+        }
+        Ok(_) => {
+            bot.send_message(chat_id, format!("No karaoke videos found for {}.", query))
+                .await?;
+        }
+        Err(e) => {
+            error!("Error searching YouTube: {}", e);
+            bot.send_message(chat_id, "There was an error searching YouTube.")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Find out which song a Spotify or Apple Music link is for and offer karaoke
+// versions of it from YouTube
+async fn offer_track_matches(
+    bot: &Bot,
+    chat_id: ChatId,
+    service: StreamingService,
+    url: &str,
+) -> ResponseResult<()> {
+    if !search_available() {
+        bot.send_message(
+            chat_id,
+            format!(
+                "{} links can't be looked up on YouTube right now. Send a YouTube link to the karaoke version instead.",
+                service.name()
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    match resolve_track(service, url).await {
+        Ok(song) => {
+            let prompt = format!(
+                "Karaoke versions of {}, tap one to add it to the queue:",
+                song
+            );
+            send_karaoke_matches(bot, chat_id, &song, &prompt).await?;
+        }
+        Err(e) => {
+            error!("Error looking up {} link: {}", service.name(), e);
+            bot.send_message(
+                chat_id,
+                format!(
+                    "Couldn't find out which song that {} link is.",
+                    service.name()
+                ),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Look a video up and add it to the user's session, replying in `chat_id`. The
+// state is only locked to change the queue, so a slow lookup or reply doesn't
+// hold up everyone else.
+async fn add_video(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+    user_id: UserId,
+    username: Option<String>,
+    url: &str,
+    note: Option<String>,
+) -> ResponseResult<()> {
+    let video_info = match source::create_video_info(url).await {
+        Ok(video_info) => video_info,
+        Err(e) => {
+            error!("Error looking up {}: {}", url, e);
+            bot.send_message(
+                chat_id,
+                "There was an error adding your video to the queue.",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let mut state_guard = state.lock().await;
+    let added = match state_guard.add_to_queue(user_id, video_info, username, note) {
+        Ok(AddResult::Added) => {
+            if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
+                events::publish(PlaybackEvent::QueueUpdated {
+                    session_code: session_code.clone(),
+                });
+            }
+            Ok((
+                added_to_queue_reply(&state_guard, &user_id),
+                last_added_thumbnail(&state_guard, &user_id),
+                karaoke_offer(&state_guard, &user_id),
+            ))
+        }
+        Ok(AddResult::Rejected(reason)) => Err(reason),
+        Err(e) => {
+            error!("Error adding to queue: {}", e);
+            Err("There was an error adding your video to the queue.".to_string())
+        }
+    };
+    drop(state_guard);
+
+    match added {
+        Ok((reply, thumbnail, karaoke_offer)) => {
+            send_with_thumbnail(bot, chat_id, reply, thumbnail.as_deref()).await?;
+            if let Some(added_at) = karaoke_offer {
+                offer_karaoke_version(bot, chat_id, added_at).await?;
+            }
+        }
+        Err(reason) => {
+            bot.send_message(chat_id, reason).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// When the song a user just added is a YouTube video whose title doesn't say
+// it's a karaoke version, the time it was added, so one can be looked for
+fn karaoke_offer(state: &SessionState, user_id: &UserId) -> Option<i64> {
+    let item = state
+        .get_queue(user_id)
+        .and_then(|queue| queue.last().copied())?;
+
+    let title = item.video_info.title.as_deref().unwrap_or_default();
+    if item.video_info.source != VideoSource::YouTube
+        || looks_like_karaoke(title)
+        || !search_available()
+    {
+        return None;
+    }
+
+    Some(item.added_at)
+}
+
+// Offer to swap the song added at `added_at` for a karaoke version
+async fn offer_karaoke_version(bot: &Bot, chat_id: ChatId, added_at: i64) -> ResponseResult<()> {
+    bot.send_message(
+        chat_id,
+        "That doesn't look like a karaoke version. Want to swap it for one?",
+    )
+    .reply_markup(InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Find karaoke version", format!("karaoke:{}", added_at)),
+    ]]))
+    .await?;
+
+    Ok(())
+}
+
+// Reply for a link that isn't a YouTube video, pointing playlist links to /addplaylist
+fn invalid_url_reply(url: &str) -> &'static str {
+    if extract_playlist_id(url).is_some() {
+        "That's a playlist link. Use /addplaylist [playlist_url] to add its songs."
+    } else {
+        "Please provide a valid YouTube, Vimeo or Dailymotion URL."
+    }
+}
+
+// Reply for songs added several at a time, saying how many made it in and why
+// the rest didn't, e.g. "Added 3 songs from the playlist."
+fn bulk_add_reply(import: &PlaylistImport, source: &str) -> String {
+    let mut reply = format!(
+        "Added {} song{}{}.",
+        import.added,
+        if import.added == 1 { "" } else { "s" },
+        source
+    );
+    if import.duplicates > 0 {
+        reply.push_str(&format!(
+            "\nSkipped {} already in the queue.",
+            import.duplicates
+        ));
+    }
+    if import.too_long > 0 {
+        reply.push_str(&format!(
+            "\nSkipped {} longer than this session allows.",
+            import.too_long
+        ));
+    }
+    if import.wrong_channel > 0 {
+        reply.push_str(&format!(
+            "\nSkipped {} from channels this session doesn't take.",
+            import.wrong_channel
+        ));
+    }
+    if import.wrong_language > 0 {
+        reply.push_str(&format!(
+            "\nSkipped {} not in this session's language.",
+            import.wrong_language
+        ));
+    }
+    if import.unavailable > 0 {
+        reply.push_str(&format!(
+            "\nSkipped {} that can't be played here.",
+            import.unavailable
+        ));
+    }
+    reply
+}
+
+// Thumbnail of the song a user just added, the last one in their queue
+fn last_added_thumbnail(state: &SessionState, user_id: &UserId) -> Option<String> {
+    state.get_queue(user_id).and_then(|queue| {
+        queue
+            .last()
+            .and_then(|item| item.video_info.thumbnail.clone())
+    })
+}
+
+// How a device's player state reads in /castinfo and /castdiag
+fn describe_player_state(status: &MediaStatus) -> &'static str {
+    match status.player_state {
+        PlayerState::Playing => "Playing",
+        PlayerState::Paused => "Paused",
+        PlayerState::Idle if status.finished => "Finished",
+        PlayerState::Idle => "Idle",
+    }
+}
+
+// Parse a position like "90", "1:30" or "1:02:30" into seconds
+fn parse_position(position: &str) -> Option<u64> {
+    let parts: Vec<&str> = position.trim().split(':').collect();
+
+    if parts.len() > 3 || parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+
+    parts.iter().try_fold(0u64, |total, part| {
+        let value: u64 = part.parse().ok()?;
+        Some(total * 60 + value)
+    })
+}
+
+// Handle taps on inline keyboard buttons
+async fn handle_callback_query(
+    bot: Bot,
+    q: CallbackQuery,
+    state: SharedState,
+) -> ResponseResult<()> {
+    let user_id = q.from.id;
+    let username = q.from.username.clone().or_else(|| {
+        Some(
+            format!(
+                "{} {}",
+                q.from.first_name.clone(),
+                q.from.last_name.clone().unwrap_or_default()
+            )
+            .trim()
+            .to_string(),
+        )
+    });
+
+    if let Some(code) = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("join:"))
+    {
+        let code = normalize_session_code(code);
+        let mut state_guard = state.lock().await;
+
+        // The list may be old, and the button shouldn't let anyone into a session
+        // that has since been made private
+        let still_public = state_guard
+            .sessions
+            .get(&code)
+            .map(|session| session.settings.public);
+        let reply = match still_public {
+            None => "That session has ended.".to_string(),
+            Some(false) => format!(
+                "Session {} isn't open anymore. Ask its owner for the code, or /browse for open sessions.",
+                code
+            ),
+            Some(true) => match state_guard.join_session(user_id, username, &code) {
+                JoinResult::Joined => format!("You've joined session: {}", code),
+                JoinResult::SessionFull(max_users) => {
+                    format!("Sorry, session {} is full ({} users max).", code, max_users)
+                }
+                JoinResult::NotFound => "That session has ended.".to_string(),
+            },
+        };
+
+        drop(state_guard);
+
+        bot.answer_callback_query(q.id).await?;
+        bot.send_message(user_id, reply).await?;
+    } else if let Some(video_id) = q.data.as_deref().and_then(|data| data.strip_prefix("add:")) {
+        bot.answer_callback_query(q.id).await?;
+
+        if !state.lock().await.is_in_session(&user_id) {
+            bot.send_message(
+                user_id,
+                "You're not in a session. Join one with /join [code] or start your own with /start-session",
+            )
+            .await?;
+            return Ok(());
+        }
+
+        add_video(
+            &bot,
+            user_id.into(),
+            &state,
+            user_id,
+            username,
+            &get_watch_url(video_id),
+            None,
+        )
+        .await?;
+    } else if let Some((choice, added_at)) = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("age:"))
+        .and_then(|age| age.split_once(':'))
+        .and_then(|(choice, added_at)| Some((choice, added_at.parse::<i64>().ok()?)))
+    {
+        bot.answer_callback_query(q.id).await?;
+        let chat_id = q
+            .message
+            .as_ref()
+            .map(|message| message.chat.id)
+            .unwrap_or(user_id.into());
+
+        let mut state_guard = state.lock().await;
+        if !state_guard.is_session_owner(&user_id) {
+            drop(state_guard);
+            bot.send_message(chat_id, "Only the session owner can advance the queue.")
+                .await?;
+            return Ok(());
+        }
+
+        // The queue may have moved on since the question was asked
+        let session_code = state_guard.user_sessions.get(&user_id).cloned();
+        let next_item = state_guard
+            .next_in_queue(&user_id)
+            .filter(|item| item.added_at == added_at);
+        drop(state_guard);
+
+        let (Some(session_code), Some(next_item)) = (session_code, next_item) else {
+            bot.send_message(chat_id, "That song isn't next in the queue any more.")
+                .await?;
+            return Ok(());
+        };
+
+        if choice == "play" {
+            start_song(&bot, &state, &session_code, chat_id, next_item).await?;
+        } else {
+            let note = skip_unplayable(
+                &bot,
+                &state,
+                &session_code,
+                &next_item,
+                "it's age-restricted on YouTube",
+            )
+            .await;
+            bot.send_message(
+                chat_id,
+                format!("{}\nUse /next to play the next song.", note),
+            )
+            .await?;
+        }
+    } else if let Some(added_at) = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("karaoke:"))
+        .and_then(|added_at| added_at.parse::<i64>().ok())
+    {
+        bot.answer_callback_query(q.id).await?;
+
+        let title = state
+            .lock()
+            .await
+            .queued_item(&user_id, added_at)
+            .map(|item| item.video_info.title.clone().unwrap_or_default());
+
+        let Some(title) = title else {
+            bot.send_message(user_id, "That song isn't in the queue any more.")
+                .await?;
+            return Ok(());
+        };
+
+        match search_karaoke_videos(&title, SEARCH_RESULTS).await {
+            Ok(results) if !results.is_empty() => {
+                let buttons: Vec<Vec<InlineKeyboardButton>> = results
+                    .into_iter()
+                    .map(|result| {
+                        vec![InlineKeyboardButton::callback(
+                            result.title,
+                            format!("swap:{}:{}", added_at, result.id),
+                        )]
+                    })
+                    .collect();
+
+                bot.send_message(user_id, "Tap a video to put it in the queue instead:")
+                    .reply_markup(InlineKeyboardMarkup::new(buttons))
+                    .await?;
+            }
+            Ok(_) => {
+                bot.send_message(user_id, format!("No karaoke videos found for {}.", title))
+                    .await?;
+            }
+            Err(e) => {
+                error!("Error searching YouTube: {}", e);
+                bot.send_message(user_id, "There was an error searching YouTube.")
+                    .await?;
+            }
+        }
+    } else if let Some((added_at, video_id)) = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("swap:"))
+        .and_then(|swap| swap.split_once(':'))
+        .and_then(|(added_at, video_id)| Some((added_at.parse::<i64>().ok()?, video_id)))
+    {
+        bot.answer_callback_query(q.id).await?;
+
+        // Look the new video up without holding the lock
+        let video_info = match create_video_info(&get_watch_url(video_id)).await {
+            Ok(video_info) => video_info,
+            Err(e) => {
+                error!("Error looking up video {}: {}", video_id, e);
+                bot.send_message(user_id, "There was an error looking up that video.")
+                    .await?;
+                return Ok(());
+            }
+        };
+        let title = video_info.title.clone().unwrap_or_default();
+
+        let mut state_guard = state.lock().await;
+        let reply = match state_guard.replace_queued_video(&user_id, added_at, video_info) {
+            Ok(()) => {
+                if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
+                    events::publish(PlaybackEvent::QueueUpdated {
+                        session_code: session_code.clone(),
+                    });
+                }
+                format!("Swapped your song for {}.", title)
+            }
+            Err(e) => e.to_string(),
+        };
+        drop(state_guard);
+
+        bot.send_message(user_id, reply).await?;
+    }
+
+    Ok(())
+}
+
+// Let every member of a session know who the new owner is
+async fn announce_owner_change(bot: &Bot, change: &OwnerChange, reason: &str) {
+    for user_id in &change.members {
+        let text = if *user_id == change.new_owner {
+            format!(
+                "You're now the owner of session {} because {}. You can run /next.",
+                change.code, reason
+            )
+        } else {
+            format!(
+                "{} is now the owner of session {} because {}.",
+                change.new_owner_name, change.code, reason
+            )
+        };
+
+        if let Err(e) = bot.send_message(*user_id, text).await {
+            error!("Failed to announce owner change to {}: {}", user_id, e);
+        }
+    }
+}
+
 // New function to handle messages containing YouTube URLs
 async fn handle_youtube_message(bot: Bot, msg: Message, state: SharedState) -> ResponseResult<()> {
     if let (Some(text), Some(user)) = (msg.text(), msg.from()) {
@@ -452,8 +2491,11 @@ async fn handle_youtube_message(bot: Bot, msg: Message, state: SharedState) -> R
         });
 
         let mut state_guard = state.lock().await;
+        state_guard.touch_user(&user_id);
+        let in_session = state_guard.is_in_session(&user_id);
+        drop(state_guard);
 
-        if !state_guard.is_in_session(&user_id) {
+        if !in_session {
             bot.send_message(
                 msg.chat.id,
                 "You're not in a session. Join one with /join [code] or start your own with /start-session"
@@ -464,11 +2506,51 @@ async fn handle_youtube_message(bot: Bot, msg: Message, state: SharedState) -> R
         // Extract YouTube URL and note
         let words: Vec<&str> = text.split_whitespace().collect();
 
-        // Find the first YouTube URL in the message
-        if let Some(url_pos) = words
+        // Several YouTube links in one message are all added, without a note
+        let mut video_ids: Vec<String> = Vec::new();
+        for word in &words {
+            if let Some(video_id) = extract_video_id(word) {
+                if !video_ids.contains(&video_id) {
+                    video_ids.push(video_id);
+                }
+            }
+        }
+        if video_ids.len() > 1 {
+            // Look the videos up without holding the lock
+            video_ids.truncate(PLAYLIST_IMPORT_LIMIT);
+            let videos = fetch_video_infos(&video_ids).await;
+
+            let mut state_guard = state.lock().await;
+            let reply = match state_guard.add_playlist_videos(user_id, username, videos) {
+                Ok(import) => {
+                    if let Some(session_code) = state_guard.user_sessions.get(&user_id) {
+                        events::publish(PlaybackEvent::QueueUpdated {
+                            session_code: session_code.clone(),
+                        });
+                    }
+                    bulk_add_reply(&import, "")
+                }
+                Err(e) => e.to_string(),
+            };
+            drop(state_guard);
+
+            bot.send_message(msg.chat.id, reply).await?;
+            return Ok(());
+        }
+
+        // A song shared from Spotify or Apple Music is looked for on YouTube instead
+        if let Some((service, url)) = words
             .iter()
-            .position(|word| word.contains("youtube.com") || word.contains("youtu.be"))
+            .find_map(|word| parse_track_link(word).map(|service| (service, *word)))
         {
+            offer_track_matches(&bot, msg.chat.id, service, url).await?;
+            return Ok(());
+        }
+
+        // Find the first video URL in the message
+        if let Some(url_pos) = words.iter().position(|word| {
+            word.contains("youtube.com") || word.contains("youtu.be") || is_valid_video_url(word)
+        }) {
             let url = words[url_pos].to_string();
 
             // Everything before the URL goes into the note
@@ -492,35 +2574,10 @@ async fn handle_youtube_message(bot: Bot, msg: Message, state: SharedState) -> R
                 (None, None) => None,
             };
 
-            if is_valid_youtube_url(&url) {
-                match state_guard.add_to_queue(user_id, url, username, note).await {
-                    Ok(true) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            "Added to queue! Type /queue to see current lineup.",
-                        )
-                        .await?;
-                    }
-                    Ok(false) => {
-                        // This case won't happen anymore since we removed duplicate check
-                        // but leaving the pattern match for future flexibility
-                        bot.send_message(
-                            msg.chat.id,
-                            "Added to queue! Type /queue to see current lineup.",
-                        )
-                        .await?;
-                    }
-                    Err(e) => {
-                        error!("Error adding to queue: {}", e);
-                        bot.send_message(
-                            msg.chat.id,
-                            "There was an error adding your video to the queue.",
-                        )
-                        .await?;
-                    }
-                }
+            if is_valid_video_url(&url) {
+                add_video(&bot, msg.chat.id, &state, user_id, username, &url, note).await?;
             } else {
-                bot.send_message(msg.chat.id, "Please provide a valid YouTube URL.")
+                bot.send_message(msg.chat.id, invalid_url_reply(&url))
                     .await?;
             }
         } else {
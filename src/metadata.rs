@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::env;
+
+use crate::youtube::{self, VideoDetails};
+use crate::youtube_api;
+use crate::ytdlp;
+
+// Length given to every video by the mock provider, in seconds
+const MOCK_DURATION: u64 = 180;
+
+// Looks up YouTube videos' titles, lengths and the rest of what's shown in the queue
+#[async_trait]
+pub trait MetadataProvider: Send + Sync {
+    // Name shown in the logs
+    fn name(&self) -> &'static str;
+
+    // What's known about a video, or None if it's private or has been removed
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>>;
+
+    // Look up several videos, in the order given. Providers that can look up
+    // many videos in one request do so instead of one at a time.
+    async fn videos_details(&self, video_ids: &[String]) -> Vec<Result<Option<VideoDetails>>> {
+        let mut results = Vec::with_capacity(video_ids.len());
+        for video_id in video_ids {
+            results.push(self.video_details(video_id).await);
+        }
+        results
+    }
+}
+
+// The YouTube Data API, which needs YOUTUBE_API_KEY and knows the most
+pub struct YouTubeApi;
+
+#[async_trait]
+impl MetadataProvider for YouTubeApi {
+    fn name(&self) -> &'static str {
+        "YouTube API"
+    }
+
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>> {
+        let mut found = youtube::fetch_api_video_details(&[video_id.to_string()]).await?;
+        Ok(found.remove(video_id))
+    }
+
+    async fn videos_details(&self, video_ids: &[String]) -> Vec<Result<Option<VideoDetails>>> {
+        let mut results = Vec::with_capacity(video_ids.len());
+
+        for batch in video_ids.chunks(youtube::VIDEOS_PER_REQUEST) {
+            match youtube::fetch_api_video_details(batch).await {
+                Ok(mut found) => {
+                    results.extend(batch.iter().map(|video_id| Ok(found.remove(video_id))))
+                }
+                Err(e) => results.extend(batch.iter().map(|_| Err(anyhow!("{}", e)))),
+            }
+        }
+        results
+    }
+}
+
+// yt-dlp, which needs no key but is slower and has to be installed
+pub struct YtDlp;
+
+#[async_trait]
+impl MetadataProvider for YtDlp {
+    fn name(&self) -> &'static str {
+        "yt-dlp"
+    }
+
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>> {
+        ytdlp::fetch_video_details(video_id).await
+    }
+}
+
+// YouTube's oEmbed endpoint, which needs no key or quota but only knows the title
+pub struct OEmbed;
+
+#[async_trait]
+impl MetadataProvider for OEmbed {
+    fn name(&self) -> &'static str {
+        "oEmbed"
+    }
+
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>> {
+        let title = youtube::fetch_oembed_title(video_id).await?;
+        Ok(Some(VideoDetails {
+            title,
+            ..VideoDetails::default()
+        }))
+    }
+}
+
+// Made-up details without touching the network, for trying the bot offline
+pub struct MockMetadata;
+
+#[async_trait]
+impl MetadataProvider for MockMetadata {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>> {
+        Ok(Some(VideoDetails {
+            title: format!("Mock Karaoke Song {}", video_id),
+            channel_title: Some("Mock Karaoke Channel".to_string()),
+            category: Some("Music".to_string()),
+            duration: Some(MOCK_DURATION),
+            ..VideoDetails::default()
+        }))
+    }
+}
+
+// The API while it's usable, then yt-dlp. Used when KARAOKE_METADATA isn't set.
+pub struct Automatic;
+
+#[async_trait]
+impl MetadataProvider for Automatic {
+    fn name(&self) -> &'static str {
+        "automatic"
+    }
+
+    async fn video_details(&self, video_id: &str) -> Result<Option<VideoDetails>> {
+        if youtube_api::available() {
+            match YouTubeApi.video_details(video_id).await {
+                // The quota just ran out or the API stopped answering, so use yt-dlp this time
+                Err(e) if !youtube_api::available() => {
+                    warn!("Failed to fetch video details: {}", e)
+                }
+                result => return result,
+            }
+        }
+
+        // Without the API, try yt-dlp instead
+        let reason = youtube_api::unavailable_reason().unwrap_or_default();
+        YtDlp
+            .video_details(video_id)
+            .await
+            .map_err(|e| anyhow!("{} and yt-dlp lookup failed: {}", reason, e))
+    }
+
+    async fn videos_details(&self, video_ids: &[String]) -> Vec<Result<Option<VideoDetails>>> {
+        if !youtube_api::available() {
+            let mut results = Vec::with_capacity(video_ids.len());
+            for video_id in video_ids {
+                results.push(self.video_details(video_id).await);
+            }
+            return results;
+        }
+
+        let mut results = YouTubeApi.videos_details(video_ids).await;
+
+        // If the API stopped being usable part way through, look the rest up with yt-dlp
+        for (video_id, result) in video_ids.iter().zip(results.iter_mut()) {
+            if result.is_err() && !youtube_api::available() {
+                *result = self.video_details(video_id).await;
+            }
+        }
+        results
+    }
+}
+
+lazy_static! {
+    // KARAOKE_METADATA picks one provider: api, ytdlp, oembed or mock
+    static ref METADATA_PROVIDER: Box<dyn MetadataProvider> = {
+        let provider: Box<dyn MetadataProvider> = match env::var("KARAOKE_METADATA")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "api" => Box::new(YouTubeApi),
+            "ytdlp" | "yt-dlp" => Box::new(YtDlp),
+            "oembed" => Box::new(OEmbed),
+            "mock" => Box::new(MockMetadata),
+            _ => Box::new(Automatic),
+        };
+        info!("Looking up videos with the {} provider", provider.name());
+        provider
+    };
+}
+
+// Look up a video with the configured provider
+pub async fn video_details(video_id: &str) -> Result<Option<VideoDetails>> {
+    METADATA_PROVIDER.video_details(video_id).await
+}
+
+// Look up several videos with the configured provider, in the order given
+pub async fn videos_details(video_ids: &[String]) -> Vec<Result<Option<VideoDetails>>> {
+    METADATA_PROVIDER.videos_details(video_ids).await
+}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use serde_json::Value;
+use std::fmt;
+
+// Format sessions are saved in. When a Session or QueueItem field is renamed or
+// changes type, bump this and add a step to MIGRATIONS that rewrites the old shape.
+// Fields that are only added just need #[serde(default)].
+pub const SCHEMA_VERSION: u32 = 1;
+
+// A step upgrading a saved session from one version to the next
+type Migration = fn(&mut serde_json::Map<String, Value>) -> Result<()>;
+
+// MIGRATIONS[n] upgrades a session saved as version n to version n + 1
+const MIGRATIONS: &[Migration] = &[unversioned_to_v1];
+
+// Sessions saved before they had a version. Every field added since then has a
+// default, so they read as they are.
+fn unversioned_to_v1(_session: &mut serde_json::Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+// Sessions were saved by a newer version of the bot, which this one can't read.
+// Startup stops rather than overwriting them.
+#[derive(Debug)]
+pub struct NewerSchema {
+    pub session_code: String,
+    pub version: u32,
+}
+
+impl fmt::Display for NewerSchema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Session {} was saved by a newer version of the bot (format {}, this one reads up to {})",
+            self.session_code, self.version, SCHEMA_VERSION
+        )
+    }
+}
+
+impl std::error::Error for NewerSchema {}
+
+// Upgrade a saved session to the current format, ready to deserialize
+pub fn migrate_session(mut session: Value) -> Result<Value> {
+    let Value::Object(fields) = &mut session else {
+        return Err(anyhow!("Saved session isn't an object"));
+    };
+
+    let version = match fields.get("schema_version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow!("Saved session has an invalid schema_version: {}", version))?,
+    };
+    let session_code = fields
+        .get("code")
+        .and_then(Value::as_str)
+        .unwrap_or("?")
+        .to_string();
+
+    if version > SCHEMA_VERSION {
+        return Err(NewerSchema {
+            session_code,
+            version,
+        }
+        .into());
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(session);
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(fields).map_err(|e| {
+            anyhow!(
+                "Failed to upgrade session {} from format {}: {}",
+                session_code,
+                from,
+                e
+            )
+        })?;
+    }
+    fields.insert("schema_version".to_string(), Value::from(SCHEMA_VERSION));
+
+    info!(
+        "Upgraded session {} from format {} to {}",
+        session_code, version, SCHEMA_VERSION
+    );
+    Ok(session)
+}
+
+// Upgrade every session in a saved SessionState
+pub fn migrate_state(mut state: Value) -> Result<Value> {
+    if let Some(Value::Object(sessions)) = state.get_mut("sessions") {
+        for session in sessions.values_mut() {
+            *session = migrate_session(session.take())?;
+        }
+    }
+    Ok(state)
+}
+
+// Upgrade every ended session in a saved SessionArchive
+pub fn migrate_archive(mut archive: Value) -> Result<Value> {
+    if let Some(Value::Array(archived)) = archive.get_mut("sessions") {
+        for archived in archived {
+            if let Some(session) = archived.get_mut("session") {
+                *session = migrate_session(session.take())?;
+            }
+        }
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionState;
+    use crate::source::VideoSource;
+    use crate::store;
+    use teloxide::types::UserId;
+
+    fn load(json: &str) -> Result<SessionState> {
+        store::parse(json, migrate_state)
+    }
+
+    #[test]
+    fn unversioned_sessions_are_upgraded() {
+        let state = load(include_str!("../tests/fixtures/sessions_v0.json")).unwrap();
+        let session = &state.sessions["TIGER-42"];
+
+        assert_eq!(session.schema_version, SCHEMA_VERSION);
+        assert_eq!(session.owner, UserId(1001));
+        assert_eq!(
+            session.users,
+            vec![
+                (UserId(1001), Some("anna".to_string())),
+                (UserId(1002), None)
+            ]
+        );
+        assert_eq!(state.user_sessions[&UserId(1002)], "TIGER-42");
+
+        // Fields added since get their defaults
+        let item = &session.queue[1];
+        assert_eq!(item.video_info.id, "9bZkp7q19f0");
+        assert_eq!(item.video_info.source, VideoSource::YouTube);
+        assert_eq!(item.video_info.duration, None);
+        assert_eq!(item.note.as_deref(), Some("for the birthday"));
+        assert_eq!(item.played_at, None);
+        assert_eq!(item.failed, None);
+        assert!(session.queue[0].played);
+
+        assert_eq!(
+            session.cast_status.cast_device.as_deref(),
+            Some("Living Room TV")
+        );
+        assert_eq!(session.cast_status.cast_backend, None);
+        assert!(session.cast_status.is_playing);
+        assert!(!session.settings.public);
+        assert_eq!(session.settings.max_users, None);
+        assert_eq!(session.starts_at, None);
+        assert!(session.muted.is_empty());
+        assert_eq!(session.backup_owner, None);
+        assert!(state.presets.is_empty());
+    }
+
+    #[test]
+    fn current_sessions_read_as_they_are() {
+        let state = load(include_str!("../tests/fixtures/sessions_v1.json")).unwrap();
+        let session = &state.sessions["PANDA-17"];
+
+        assert_eq!(session.schema_version, 1);
+        assert_eq!(session.users[1].1.as_deref(), Some("Carla M"));
+
+        let video = &session.queue[0].video_info;
+        assert_eq!(video.duration, Some(282));
+        assert_eq!(video.start_time, Some(30));
+        assert_eq!(video.channel_title.as_deref(), Some("Sing King"));
+        assert_eq!(video.language.as_deref(), Some("es"));
+
+        assert_eq!(session.cast_status.cast_backend.as_deref(), Some("dlna"));
+        assert_eq!(session.cast_status.volume, Some(60));
+        assert_eq!(session.settings.max_users, Some(12));
+        assert!(session.settings.public);
+        assert_eq!(session.settings.timezone.as_deref(), Some("Europe/Madrid"));
+        assert_eq!(session.settings.intermission, Some(15));
+        assert_eq!(session.starts_at, Some(1710003600));
+        assert_eq!(session.muted[&UserId(2002)], Some(1710001800));
+        assert_eq!(session.backup_owner, Some(UserId(2002)));
+        assert_eq!(session.last_seen[&UserId(2001)], 1709999000);
+    }
+
+    #[test]
+    fn newer_sessions_are_refused() {
+        let Err(e) = load(include_str!("../tests/fixtures/sessions_newer.json")) else {
+            panic!("a session from a newer version was read");
+        };
+        let newer = e
+            .downcast_ref::<NewerSchema>()
+            .expect("refused for being newer");
+
+        assert_eq!(newer.session_code, "OTTER-88");
+        assert_eq!(newer.version, 99);
+    }
+
+    #[test]
+    fn invalid_versions_are_errors() {
+        let session = serde_json::json!({ "code": "LEMON-11", "schema_version": "two" });
+        assert!(migrate_session(session).is_err());
+    }
+}
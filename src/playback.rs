@@ -0,0 +1,695 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::InputFile;
+use tokio::sync::broadcast::Receiver;
+
+use crate::cast::{
+    cast_video, connect_device, get_media_status, play_announcement, preload_video, show_idle_card,
+    show_message, stop_casting, update_now_playing, CastDevice, CastTarget, IdleCard, LoadFailed,
+    PlayerState,
+};
+use crate::events::{self, publish, PlaybackEvent};
+use crate::session::QueueItem;
+use crate::source::playback_problem;
+use crate::tts;
+use crate::SharedState;
+
+// How often the auto-advance task checks what the cast device is doing
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How many times to try casting a song before giving up on it
+const CAST_ATTEMPTS: u32 = 4;
+
+// Wait before the first retry, doubled after each failed attempt
+const CAST_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// Longest caption Telegram allows on a photo
+const CAPTION_LIMIT: usize = 1024;
+
+// Roughly how fast announcements are spoken, to know when the song can start
+const ANNOUNCEMENT_WORDS_PER_SECOND: f64 = 2.5;
+
+// Title on the idle screen of sessions without one
+const DEFAULT_IDLE_TITLE: &str = "Karaoke night";
+
+// The bot's Telegram username, for join links on the idle screen
+static BOT_USERNAME: OnceLock<String> = OnceLock::new();
+
+lazy_static! {
+    // Sessions that currently have an auto-advance task running
+    static ref AUTO_ADVANCE_SESSIONS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+
+    // Sessions moving on to their next song, which the watcher leaves alone until it starts
+    static ref ADVANCING_SESSIONS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// Sessions moving on to their next song. A task that panicked while holding the
+// lock doesn't change the set, so it's still good to use.
+fn advancing_sessions() -> MutexGuard<'static, HashSet<String>> {
+    ADVANCING_SESSIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Cast a queue item to the session's device and mark it as played once the device
+// loaded it, retrying with backoff in case the device is asleep or the network
+// dropped. If every attempt fails the item stays at the front of the queue.
+// Returns the "Now playing" announcement for the chat.
+async fn play_item(state: &SharedState, session_code: &str, item: &QueueItem) -> Result<String> {
+    // Videos get taken down while they wait in the queue, so check before announcing it
+    if let Some(reason) = playback_problem(&item.video_info).await {
+        return Err(LoadFailed { reason }.into());
+    }
+
+    let (cast_target, user_name, now_playing, announce) = {
+        let state_guard = state.lock().await;
+        let session = state_guard
+            .sessions
+            .get(session_code)
+            .ok_or_else(|| anyhow!("Session not found"))?;
+
+        (
+            session.cast_status.target(),
+            session.item_user_name(item),
+            session.now_playing_with(item),
+            session.settings.announce,
+        )
+    };
+
+    let video_title = item
+        .video_info
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+
+    if announce {
+        let text = match &item.video_info.title {
+            Some(title) => format!("Next up: {} singing {}", user_name, title),
+            None => format!("Next up: {}", user_name),
+        };
+        announce_singer(session_code, &cast_target, &text).await;
+    }
+
+    // The lock is released while casting so other handlers aren't blocked
+    let mut delay = CAST_RETRY_DELAY;
+    let mut attempt = 1;
+    while let Err(e) = cast_video(&item.video_info, &now_playing, &cast_target).await {
+        // The device won't load this video however often we ask
+        if e.downcast_ref::<LoadFailed>().is_some() {
+            return Err(e);
+        }
+
+        if attempt == CAST_ATTEMPTS {
+            return Err(anyhow!(
+                "{} (tried {} times). The song is still at the front of the queue, try /next again once the device is ready.",
+                e,
+                CAST_ATTEMPTS
+            ));
+        }
+
+        warn!(
+            "Casting in {} failed (attempt {} of {}), retrying in {}s: {}",
+            session_code,
+            attempt,
+            CAST_ATTEMPTS,
+            delay.as_secs(),
+            e
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+
+    state.lock().await.start_item(session_code, item);
+
+    publish(PlaybackEvent::SongStarted {
+        session_code: session_code.to_string(),
+        item: Box::new(item.clone()),
+    });
+
+    Ok(format!(
+        "Now playing: {} (added by {})",
+        video_title, user_name
+    ))
+}
+
+// Play a queue item, skipping past any songs the cast device refuses to load
+// and telling whoever added them why. Returns the announcement for the chat.
+pub async fn play_or_skip(
+    bot: &Bot,
+    state: &SharedState,
+    session_code: &str,
+    item: QueueItem,
+) -> Result<String> {
+    let mut item = item;
+    let mut skipped = String::new();
+
+    loop {
+        let e = match play_item(state, session_code, &item).await {
+            Ok(announcement) => return Ok(format!("{}{}", skipped, announcement)),
+            Err(e) => e,
+        };
+
+        let Some(load_failed) = e.downcast_ref::<LoadFailed>() else {
+            publish(PlaybackEvent::CastError {
+                session_code: session_code.to_string(),
+                error: e.to_string(),
+            });
+            return Err(if skipped.is_empty() {
+                e
+            } else {
+                anyhow!("{}{}", skipped, e)
+            });
+        };
+
+        let note = skip_unplayable(bot, state, session_code, &item, &load_failed.reason).await;
+        skipped.push_str(&note);
+        skipped.push('\n');
+
+        item = match state.lock().await.upcoming_item(session_code) {
+            Some(next_item) => next_item,
+            None => {
+                state.lock().await.set_session_playing(session_code, false);
+                show_idle_screen(state, session_code).await;
+                return Ok(format!(
+                    "{}That was the last song in the queue. Add more with /add [youtube_url]",
+                    skipped
+                ));
+            }
+        };
+    }
+}
+
+// Mark a song the cast device couldn't play as failed and let whoever added it know.
+// Returns the note for the session's chat.
+pub async fn skip_unplayable(
+    bot: &Bot,
+    state: &SharedState,
+    session_code: &str,
+    item: &QueueItem,
+    reason: &str,
+) -> String {
+    let user_name = {
+        let mut state_guard = state.lock().await;
+        state_guard.mark_failed(session_code, item, reason);
+        state_guard
+            .sessions
+            .get(session_code)
+            .map(|session| session.item_user_name(item))
+            .unwrap_or_default()
+    };
+
+    let video_title = item
+        .video_info
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+    info!(
+        "Skipping {} in session {}: {}",
+        item.video_info.id, session_code, reason
+    );
+
+    if let Err(e) = bot
+        .send_message(
+            item.added_by,
+            format!(
+                "Your song {} couldn't be played and was skipped: {}. Try adding a different version with /add",
+                video_title, reason
+            ),
+        )
+        .await
+    {
+        error!("Failed to tell {} their song was skipped: {}", user_name, e);
+    }
+
+    format!(
+        "Skipped {} (added by {}): {}",
+        video_title, user_name, reason
+    )
+}
+
+// Send a message with the video's thumbnail above it, or as plain text when
+// there's no thumbnail or Telegram won't fetch it
+pub async fn send_with_thumbnail(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: String,
+    thumbnail: Option<&str>,
+) -> ResponseResult<()> {
+    let photo = thumbnail
+        .and_then(|url| reqwest::Url::parse(url).ok())
+        .filter(|_| text.chars().count() <= CAPTION_LIMIT);
+
+    if let Some(url) = photo {
+        match bot
+            .send_photo(chat_id, InputFile::url(url))
+            .caption(text.clone())
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) => warn!("Failed to send thumbnail to {}: {}", chat_id, e),
+        }
+    }
+
+    bot.send_message(chat_id, text).await?;
+    Ok(())
+}
+
+// Speak an announcement on the session's device and wait for it to finish.
+// Failures are only logged, the song still plays without it.
+async fn announce_singer(session_code: &str, target: &CastTarget, text: &str) {
+    let audio_url = match tts::speech_url(text).await {
+        Ok(audio_url) => audio_url,
+        Err(e) => {
+            error!(
+                "Failed to announce the next singer in {}: {}",
+                session_code, e
+            );
+            return;
+        }
+    };
+
+    match play_announcement(target.device.as_ref(), &audio_url).await {
+        Ok(true) => {
+            let words = text.split_whitespace().count() as f64;
+            let length = words / ANNOUNCEMENT_WORDS_PER_SECOND + 1.0;
+            tokio::time::sleep(Duration::from_secs_f64(length)).await;
+        }
+        Ok(false) => info!(
+            "The cast device in {} can't play announcements",
+            session_code
+        ),
+        Err(e) => error!(
+            "Failed to announce the next singer in {}: {}",
+            session_code, e
+        ),
+    }
+}
+
+// Refresh the up next list on the session's TV, e.g. after a song was added
+async fn refresh_now_playing(state: SharedState, session_code: String) {
+    let (cast_device, now_playing) = {
+        let state_guard = state.lock().await;
+        let Some(session) = state_guard.sessions.get(&session_code) else {
+            return;
+        };
+
+        if !session.cast_status.is_playing {
+            return;
+        }
+
+        (session.cast_status.device(), session.now_playing())
+    };
+
+    if let Err(e) = update_now_playing(cast_device.as_ref(), &now_playing).await {
+        error!("Failed to update up next for {}: {}", session_code, e);
+    }
+
+    // The song after this one may have changed
+    preload_next(state, session_code).await;
+}
+
+// Have the session's device buffer the next song in the queue, if it can
+async fn preload_next(state: SharedState, session_code: String) {
+    let (cast_device, next_video) = {
+        let state_guard = state.lock().await;
+        let Some(session) = state_guard.sessions.get(&session_code) else {
+            return;
+        };
+        // A queued song starts by itself when the one before ends, which would cut
+        // off the intermission or announcement
+        if session.settings.intermission.is_some() || session.settings.announce {
+            return;
+        }
+        let Some(next_item) = session.next_item() else {
+            return;
+        };
+
+        (session.cast_status.device(), next_item.video_info.clone())
+    };
+
+    match preload_video(cast_device.as_ref(), &next_video).await {
+        Ok(true) => info!("Preloaded {} for {}", next_video.id, session_code),
+        Ok(false) => {}
+        Err(e) => error!(
+            "Failed to preload the next song for {}: {}",
+            session_code, e
+        ),
+    }
+}
+
+// Start the tasks that react to playback events: moving on to the next song,
+// keeping the TV's up next list current and recording what's playing
+pub fn spawn_subscribers(bot: Bot, state: SharedState) {
+    tokio::spawn(run_auto_advance(bot, state.clone(), events::subscribe()));
+    tokio::spawn(run_up_next(state.clone(), events::subscribe()));
+    tokio::spawn(run_playing_state(state, events::subscribe()));
+}
+
+// Play the next song whenever the current one finishes
+async fn run_auto_advance(bot: Bot, state: SharedState, mut events: Receiver<PlaybackEvent>) {
+    while let Some(event) = events::next_event(&mut events).await {
+        if let PlaybackEvent::SongFinished {
+            session_code,
+            chat_id,
+            error,
+        } = event
+        {
+            // Intermissions take a while, so other sessions aren't held up
+            let (bot, state) = (bot.clone(), state.clone());
+            tokio::spawn(async move {
+                advance_after_song(&bot, &state, &session_code, chat_id, error).await;
+
+                advancing_sessions().remove(&session_code);
+            });
+        }
+    }
+}
+
+// Keep the TV's up next list and the preloaded song in step with the queue
+async fn run_up_next(state: SharedState, mut events: Receiver<PlaybackEvent>) {
+    while let Some(event) = events::next_event(&mut events).await {
+        match event {
+            // Buffer the following song in the background so the next transition is instant
+            PlaybackEvent::SongStarted { session_code, .. } => {
+                tokio::spawn(preload_next(state.clone(), session_code));
+            }
+            PlaybackEvent::QueueUpdated { session_code } => {
+                tokio::spawn(refresh_now_playing(state.clone(), session_code));
+            }
+            _ => {}
+        }
+    }
+}
+
+// Save whether each session is playing as songs start and fail
+async fn run_playing_state(state: SharedState, mut events: Receiver<PlaybackEvent>) {
+    while let Some(event) = events::next_event(&mut events).await {
+        match event {
+            PlaybackEvent::SongStarted { session_code, item } => {
+                let mut state_guard = state.lock().await;
+                // /stop or /next may have replaced the song in the meantime
+                let still_current = state_guard
+                    .current_item(&session_code)
+                    .is_some_and(|current| current.same_item(&item));
+                if still_current {
+                    state_guard.set_session_playing(&session_code, true);
+                }
+            }
+            PlaybackEvent::CastError {
+                session_code,
+                error,
+            } => {
+                warn!(
+                    "Playback in {} stopped after a cast error: {}",
+                    session_code, error
+                );
+                state.lock().await.set_session_playing(&session_code, false);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Start a background task that watches the session's device and reports when
+// each song finishes, so the next one is announced in `chat_id`. Does nothing if
+// the session already has one running.
+pub fn spawn_auto_advance(state: SharedState, session_code: String, chat_id: ChatId) {
+    match AUTO_ADVANCE_SESSIONS.lock() {
+        Ok(mut sessions) => {
+            if !sessions.insert(session_code.clone()) {
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+
+    tokio::spawn(async move {
+        info!("Auto-advance started for session {}", session_code);
+        watch_playback(&state, &session_code, chat_id).await;
+        info!("Auto-advance stopped for session {}", session_code);
+
+        if let Ok(mut sessions) = AUTO_ADVANCE_SESSIONS.lock() {
+            sessions.remove(&session_code);
+        }
+    });
+}
+
+// Resume auto-advance for sessions that were playing when the bot last stopped,
+// announcing to each session's owner
+pub async fn resume_auto_advance(state: &SharedState) {
+    let playing: Vec<(String, UserId)> = state
+        .lock()
+        .await
+        .sessions
+        .values()
+        .filter(|session| session.cast_status.is_playing)
+        .map(|session| (session.code.clone(), session.owner))
+        .collect();
+
+    for (session_code, owner) in playing {
+        spawn_auto_advance(state.clone(), session_code, owner.into());
+    }
+}
+
+// Remember the bot's username once it's known, so the idle screen can link to it
+pub fn set_bot_username(username: &str) {
+    let _ = BOT_USERNAME.set(username.to_string());
+}
+
+// Show the session's title, join code and a QR code to join on its device while
+// nothing is playing, so people walking in know how to get in the queue
+pub async fn show_idle_screen(state: &SharedState, session_code: &str) {
+    let (cast_device, card) = {
+        let state_guard = state.lock().await;
+        let Some(session) = state_guard.sessions.get(session_code) else {
+            return;
+        };
+
+        let card = IdleCard {
+            title: session
+                .settings
+                .title
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IDLE_TITLE.to_string()),
+            code: session.code.clone(),
+            join_link: BOT_USERNAME
+                .get()
+                .map(|username| format!("https://t.me/{}?start={}", username, session.code)),
+        };
+        (session.cast_status.device(), card)
+    };
+
+    if let Err(e) = show_idle_card(cast_device.as_ref(), &card).await {
+        error!("Failed to show the idle screen for {}: {}", session_code, e);
+    }
+}
+
+// Stop the video an ended session left on its device, so the TV doesn't sit on a
+// frozen frame. The web player goes back to its waiting screen.
+pub async fn clear_ended_session(session_code: &str, target: &CastTarget) {
+    match stop_casting(target).await {
+        Ok(_) => info!("Cleared the cast device of ended session {}", session_code),
+        Err(e) => error!(
+            "Failed to stop casting for ended session {}: {}",
+            session_code, e
+        ),
+    }
+}
+
+// Reconnect to the cast devices sessions were using when the bot last stopped
+pub async fn restore_cast_connections(state: &SharedState) {
+    let mut devices: Vec<CastDevice> = Vec::new();
+    for session in state.lock().await.sessions.values() {
+        if let Some(device) = session.cast_status.device() {
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+        }
+    }
+
+    for device in devices {
+        match connect_device(&device).await {
+            Ok(()) => info!("Restored connection to cast device {}", device),
+            Err(e) => error!("Failed to reconnect to cast device {}: {}", device, e),
+        }
+    }
+}
+
+async fn watch_playback(state: &SharedState, session_code: &str, chat_id: ChatId) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Wait for the next song to start before looking at the device again
+        if advancing_sessions().contains(session_code) {
+            continue;
+        }
+
+        let cast_device = {
+            let state_guard = state.lock().await;
+            match state_guard.sessions.get(session_code) {
+                Some(session) if session.cast_status.is_playing => session.cast_status.device(),
+                // Paused or stopped, check again later
+                Some(_) => continue,
+                // The session ended
+                None => return,
+            }
+        };
+
+        let status = match get_media_status(cast_device.as_ref()).await {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to get media status for {}: {}", session_code, e);
+                continue;
+            }
+        };
+
+        // The device gave up on the video, so move on rather than leave the TV stuck
+        if status.error.is_none() {
+            if status.player_state != PlayerState::Idle || !status.finished {
+                continue;
+            }
+            info!(
+                "Song finished in session {} at {}s of {:?}s",
+                session_code, status.position, status.duration
+            );
+        }
+
+        advancing_sessions().insert(session_code.to_string());
+        publish(PlaybackEvent::SongFinished {
+            session_code: session_code.to_string(),
+            chat_id,
+            error: status.error,
+        });
+    }
+}
+
+// Move a session on to its next song once the current one finished, skipping it
+// first if the device gave up on it, and announce what's next in `chat_id`
+async fn advance_after_song(
+    bot: &Bot,
+    state: &SharedState,
+    session_code: &str,
+    chat_id: ChatId,
+    error: Option<String>,
+) {
+    if let Some(reason) = &error {
+        let current_item = state.lock().await.current_item(session_code);
+        if let Some(item) = current_item {
+            let note = skip_unplayable(bot, state, session_code, &item, reason).await;
+            if let Err(e) = bot.send_message(chat_id, note).await {
+                error!(
+                    "Failed to announce skipped song for {}: {}",
+                    session_code, e
+                );
+            }
+        }
+    }
+
+    let next_item = state.lock().await.upcoming_item(session_code);
+
+    if let Some(item) = &next_item {
+        if !run_intermission(bot, state, session_code, chat_id, item).await {
+            info!("Intermission in {} was interrupted", session_code);
+            return;
+        }
+    }
+
+    let mut thumbnail = None;
+    let announcement = match next_item {
+        Some(item) => match play_or_skip(bot, state, session_code, item).await {
+            Ok(announcement) => {
+                thumbnail = state
+                    .lock()
+                    .await
+                    .current_item(session_code)
+                    .and_then(|item| item.video_info.thumbnail);
+                announcement
+            }
+            Err(e) => {
+                error!("Error casting video: {}", e);
+                format!("Error casting the next video: {}", e)
+            }
+        },
+        None => {
+            state.lock().await.set_session_playing(session_code, false);
+            show_idle_screen(state, session_code).await;
+            "That was the last song in the queue. Add more with /add [youtube_url]".to_string()
+        }
+    };
+
+    if let Err(e) = send_with_thumbnail(bot, chat_id, announcement, thumbnail.as_deref()).await {
+        error!("Failed to announce next song for {}: {}", session_code, e);
+    }
+}
+
+// Announce the next singer on the TV and in the chat, then wait out the session's
+// intermission. Returns false if the owner stopped or skipped ahead in the meantime.
+async fn run_intermission(
+    bot: &Bot,
+    state: &SharedState,
+    session_code: &str,
+    chat_id: ChatId,
+    item: &QueueItem,
+) -> bool {
+    let (delay, cast_device, user_name, finished_item) = {
+        let state_guard = state.lock().await;
+        let Some(session) = state_guard.sessions.get(session_code) else {
+            return false;
+        };
+
+        match session.settings.intermission {
+            Some(delay) => (
+                delay,
+                session.cast_status.device(),
+                session.item_user_name(item),
+                state_guard.current_item(session_code),
+            ),
+            None => return true,
+        }
+    };
+
+    let video_title = item
+        .video_info
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id));
+    let text = format!("Up next: {} \u{2014} sung by {}", video_title, user_name);
+
+    if let Err(e) = show_message(cast_device.as_ref(), &text).await {
+        error!("Failed to show intermission for {}: {}", session_code, e);
+    }
+
+    if let Err(e) = bot
+        .send_message(chat_id, format!("{}\nStarting in {} seconds.", text, delay))
+        .await
+    {
+        error!(
+            "Failed to announce intermission for {}: {}",
+            session_code, e
+        );
+    }
+
+    tokio::time::sleep(Duration::from_secs(delay)).await;
+
+    // /stop clears the current video and /next replaces it
+    let state_guard = state.lock().await;
+    let current_item = state_guard.current_item(session_code);
+    match state_guard.sessions.get(session_code) {
+        Some(session) => {
+            session.cast_status.is_playing
+                && match (&current_item, &finished_item) {
+                    (Some(current), Some(finished)) => current.same_item(finished),
+                    (current, finished) => current.is_none() && finished.is_none(),
+                }
+        }
+        None => false,
+    }
+}
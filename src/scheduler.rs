@@ -0,0 +1,142 @@
+use log::{error, info};
+use std::time::Duration;
+use teloxide::prelude::*;
+
+use crate::events::{self, PlaybackEvent};
+use crate::playback::clear_ended_session;
+use crate::source::refetch_video_infos;
+use crate::{announce_owner_change, SharedState};
+
+// How often the scheduler wakes up to look for due work
+const TICK_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long before a scheduled session starts its members get reminded
+const REMINDER_LEAD_TIME: i64 = 15 * 60;
+
+// How often songs added while their lookup failed are looked up again
+const TITLE_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How long an owner can go without interacting before another member takes over
+const OWNER_INACTIVITY_TIMEOUT: i64 = 60 * 60;
+
+// How long a session can sit idle before the owner is warned it will close
+const IDLE_WARNING_TIMEOUT: i64 = 2 * 60 * 60;
+
+// How long after the idle warning the session is closed if nobody responds
+const IDLE_GRACE_PERIOD: i64 = 30 * 60;
+
+// Background task that handles time-based session events, such as reminding
+// members that a scheduled session is about to start, replacing an inactive owner
+// or closing sessions nobody is using anymore
+pub async fn run_scheduler(bot: Bot, state: SharedState) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Collect the work while holding the lock, then release it before messaging users
+        let reminders = state.lock().await.take_due_reminders(REMINDER_LEAD_TIME);
+
+        for reminder in reminders {
+            info!("Sending start reminders for session {}", reminder.code);
+
+            for user_id in reminder.users {
+                if let Err(e) = bot
+                    .send_message(
+                        user_id,
+                        format!(
+                            "Karaoke session {} starts soon, at {}. Get your songs queued up!",
+                            reminder.code, reminder.starts_at
+                        ),
+                    )
+                    .await
+                {
+                    error!("Failed to send reminder to {}: {}", user_id, e);
+                }
+            }
+        }
+
+        let owner_changes = state
+            .lock()
+            .await
+            .take_inactive_owner_changes(OWNER_INACTIVITY_TIMEOUT);
+
+        for change in owner_changes {
+            info!("Owner of session {} went inactive", change.code);
+            announce_owner_change(&bot, &change, "the previous owner has been inactive").await;
+        }
+
+        let warnings = state.lock().await.take_idle_warnings(IDLE_WARNING_TIMEOUT);
+
+        for warning in warnings {
+            info!("Session {} is idle, warning the owner", warning.code);
+
+            if let Err(e) = bot
+                .send_message(
+                    warning.owner,
+                    format!(
+                        "Your session {} has been idle for {} hours and will close in {} minutes. Reply /keepalive to keep it open.",
+                        warning.code,
+                        IDLE_WARNING_TIMEOUT / 3600,
+                        IDLE_GRACE_PERIOD / 60
+                    ),
+                )
+                .await
+            {
+                error!("Failed to send idle warning to {}: {}", warning.owner, e);
+            }
+        }
+
+        let expired_sessions = state
+            .lock()
+            .await
+            .expire_idle_sessions(IDLE_WARNING_TIMEOUT + IDLE_GRACE_PERIOD);
+
+        for expired in expired_sessions {
+            info!("Closed idle session {}", expired.code);
+
+            if let Some(target) = &expired.cast_target {
+                clear_ended_session(&expired.code, target).await;
+            }
+
+            for user_id in expired.members {
+                if let Err(e) = bot
+                    .send_message(
+                        user_id,
+                        format!(
+                            "Session {} was closed after being idle.\n\n{}",
+                            expired.code, expired.stats
+                        ),
+                    )
+                    .await
+                {
+                    error!("Failed to announce closed session to {}: {}", user_id, e);
+                }
+            }
+        }
+    }
+}
+
+// Background task that looks up queued songs whose lookup failed when they were
+// added, e.g. while the YouTube API was down, and fills in their titles
+pub async fn run_title_refresher(state: SharedState) {
+    let mut interval = tokio::time::interval(TITLE_RETRY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Look the videos up without holding the lock, it can take a while
+        let videos = state.lock().await.videos_needing_titles(None);
+        if videos.is_empty() {
+            continue;
+        }
+
+        let refetched = refetch_video_infos(&videos).await;
+        let updated_sessions = state.lock().await.update_video_details(&refetched);
+
+        for session_code in updated_sessions {
+            info!("Filled in missing titles in session {}", session_code);
+            events::publish(PlaybackEvent::QueueUpdated { session_code });
+        }
+    }
+}
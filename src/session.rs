@@ -1,31 +1,181 @@
 use anyhow::Result;
-use chrono;
+use chrono::TimeZone;
+use chrono_tz::Tz;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
 use teloxide::types::UserId;
 
-use crate::cast::CastStatus;
-use crate::youtube::{create_video_info, validate_youtube_url, VideoInfo};
+use crate::archive::SessionArchive;
+use crate::cast::{CastDevice, CastStatus, CastTarget, NowPlaying, DEFAULT_DEVICE};
+use crate::migrations::{NewerSchema, SCHEMA_VERSION};
+use crate::source::{validate_video_url, VideoSource};
+use crate::store;
+use crate::youtube::{extract_channel_id, VideoInfo};
 
-const SESSION_FILE: &str = "sessions.json";
+// How many upcoming songs are shown on the TV while a video plays
+const UP_NEXT_COUNT: usize = 2;
+
+// Longest intermission between songs an owner can set, in seconds
+const MAX_INTERMISSION: u64 = 300;
+
+// Short, easy to shout words used to build session codes like TIGER-42
+const SESSION_CODE_WORDS: &[&str] = &[
+    "APPLE", "BANJO", "BEAR", "CANDY", "CLOUD", "COMET", "CORAL", "DISCO", "EAGLE", "EMBER",
+    "FALCON", "FLAME", "GHOST", "GROOVE", "HONEY", "JAZZ", "JUNGLE", "KOALA", "LASER", "LEMON",
+    "LION", "LUNAR", "MANGO", "MAPLE", "MOON", "NEON", "OCEAN", "OTTER", "PANDA", "PEACH", "PIANO",
+    "PIXEL", "PLANET", "RADIO", "RAVEN", "ROBOT", "ROCKET", "SALSA", "SHARK", "SOLAR", "SPARK",
+    "STAR", "STORM", "TANGO", "TIGER", "TULIP", "VELVET", "VIOLIN", "WAVE", "ZEBRA",
+];
 
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct SessionState {
     pub sessions: HashMap<String, Session>,
     pub user_sessions: HashMap<UserId, String>, // Maps Telegram UserId to session code
+    #[serde(default)]
+    pub presets: HashMap<UserId, HashMap<String, SessionPreset>>, // Saved presets per owner
+    #[serde(skip)]
+    pub archive: SessionArchive, // Ended sessions, stored in their own file
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Session {
+    #[serde(default)]
+    pub schema_version: u32, // Format the session was saved in, see migrations.rs
     pub code: String,
-    pub users: Vec<(UserId, Option<String>)>, // (user_id, username)
+    pub users: Vec<(UserId, Option<String>)>, // (user_id, display name: username or /nickname)
     pub queue: Vec<QueueItem>,
     pub owner: UserId,           // Track who created the session
     pub cast_status: CastStatus, // Track current casting status
     pub created_at: i64,         // Unix timestamp when session was created
+    #[serde(default)]
+    pub settings: SessionSettings, // Owner-configurable session settings
+    #[serde(default)]
+    pub starts_at: Option<i64>, // Unix timestamp of a scheduled start, None if started right away
+    #[serde(default)]
+    pub reminder_sent: bool, // Whether the pre-start reminder went out to members
+    #[serde(default)]
+    pub muted: HashMap<UserId, Option<i64>>, // Members blocked from adding songs, with optional expiry
+    #[serde(default)]
+    pub backup_owner: Option<UserId>, // Member who takes over if the owner leaves or goes quiet
+    #[serde(default)]
+    pub last_seen: HashMap<UserId, i64>, // Unix timestamp of each member's last interaction
+    #[serde(default)]
+    pub merge_request: Option<String>, // Code of a session the owner asked to merge with
+    #[serde(default)]
+    pub idle_warning_sent: bool, // Whether the owner was warned the session is about to expire
+}
+
+// Warning for an owner that their idle session is about to be closed
+pub struct IdleWarning {
+    pub code: String,
+    pub owner: UserId,
+}
+
+// A session closed for being idle too long
+pub struct ExpiredSession {
+    pub code: String,
+    pub members: Vec<UserId>,
+    pub stats: String,
+    pub cast_target: Option<CastTarget>, // Where it was casting, so the TV can be cleared
+}
+
+// Result of asking to merge two sessions
+#[derive(Debug, PartialEq)]
+pub enum MergeResult {
+    // Waiting for the other session's owner to agree
+    Requested {
+        other_owner: UserId,
+    },
+    // Both sessions are now one, under `code`
+    Merged {
+        code: String,
+        merged_code: String,
+        members: Vec<UserId>,
+    },
+}
+
+// Ownership of a session passing to another member
+#[derive(Debug, PartialEq)]
+pub struct OwnerChange {
+    pub code: String,
+    pub new_owner: UserId,
+    pub new_owner_name: String,
+    pub members: Vec<UserId>,
+}
+
+// A reminder that a scheduled session is about to start
+pub struct StartReminder {
+    pub code: String,
+    pub starts_at: String, // Start time formatted in the session's timezone
+    pub users: Vec<UserId>,
+}
+
+// Settings the session owner can change with /settings
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub max_users: Option<usize>,      // None means unlimited
+    pub public: bool,                  // Listed in /browse so anyone can find and join
+    pub title: Option<String>,         // Name shown in /browse
+    pub timezone: Option<String>,      // IANA timezone for displayed times, None means UTC
+    pub intermission: Option<u64>,     // Seconds to show who's up next between songs, None skips it
+    pub announce: bool, // Speak the next singer's name on the cast device before each song
+    pub max_duration: Option<u64>, // Longest song that can be added, in seconds, None means no limit
+    pub blocked_channels: Vec<String>, // YouTube channel IDs whose videos are turned away
+    pub allowed_channels: Vec<String>, // If any, the only YouTube channels videos can come from
+    pub language: Option<String>,  // Only songs sung in this language, e.g. for a Spanish night
+}
+
+// A public session as listed by /browse
+pub struct PublicSession {
+    pub code: String,
+    pub title: String,
+    pub members: usize,
+}
+
+// Session settings saved under a name so an owner can reuse them for future sessions
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionPreset {
+    pub settings: SessionSettings,
+    pub cast_device: Option<String>,
+    #[serde(default)]
+    pub cast_backend: Option<String>,
+}
+
+// Result of leaving a session
+#[derive(Debug, PartialEq)]
+pub enum LeaveResult {
+    Left,
+    OwnerChanged(OwnerChange), // The owner left and someone else took over
+    SessionEnded(String, Option<CastTarget>), // Last user left, with the statistics and where it was casting
+    NotInSession,
+}
+
+// Result of trying to add a video to the queue
+#[derive(Debug, PartialEq)]
+pub enum AddResult {
+    Added,
+    Rejected(String), // Not added, with a reason to show the user
+}
+
+// How many songs of a playlist made it into the queue
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaylistImport {
+    pub added: usize,
+    pub duplicates: usize,     // Already waiting in the queue
+    pub too_long: usize,       // Over the session's maxduration
+    pub unavailable: usize,    // Private, not embeddable or blocked in KARAOKE_REGION
+    pub wrong_channel: usize,  // From a channel the owner blocked or didn't allow
+    pub wrong_language: usize, // Not in the session's language
+}
+
+// Result of trying to join a session
+#[derive(Debug, PartialEq)]
+pub enum JoinResult {
+    Joined,
+    SessionFull(usize),
+    NotFound,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -36,39 +186,61 @@ pub struct QueueItem {
     pub added_at: i64,
     pub played: bool,
     pub note: Option<String>, // Optional note for the queue item
+    #[serde(default)]
+    pub played_at: Option<i64>, // Unix timestamp when the item started playing
+    #[serde(default)]
+    pub failed: Option<String>, // Why the cast device couldn't play it, if it was skipped
 }
 
 impl SessionState {
-    pub fn new() -> Self {
-        Self::load().unwrap_or_else(|_| Self::default())
+    // Load the saved sessions. Only fails if they're from a newer version of the bot,
+    // since starting without them would overwrite them.
+    pub fn new() -> Result<Self> {
+        let mut state = match Self::load() {
+            Ok(state) => state,
+            Err(e) if e.downcast_ref::<NewerSchema>().is_some() => return Err(e),
+            Err(e) => {
+                log::error!("Failed to load saved sessions, starting without any: {}", e);
+                Self::default()
+            }
+        };
+        state.archive = SessionArchive::new()?;
+        Ok(state)
     }
 
+    // Have the state saved in the background shortly, see store::run_saver
     pub fn save(&self) -> Result<()> {
-        let json = serde_json::to_string_pretty(self)?;
-        fs::write(SESSION_FILE, json)?;
-        Ok(())
+        store::schedule_save()
+    }
+
+    // Have one session saved shortly, which some stores can do without rewriting the rest
+    pub fn save_session(&self, session_code: &str) -> Result<()> {
+        store::schedule_session_save(session_code)
     }
 
     pub fn load() -> Result<Self> {
-        if Path::new(SESSION_FILE).exists() {
-            let json = fs::read_to_string(SESSION_FILE)?;
-            let state: SessionState = serde_json::from_str(&json)?;
-            Ok(state)
-        } else {
-            Ok(SessionState::default())
-        }
+        Ok(store::store().load()?.unwrap_or_default())
     }
 
     pub fn create_session(&mut self, user_id: UserId, username: Option<String>) -> String {
-        let session_code = generate_session_code();
+        let session_code = generate_session_code(&self.sessions);
 
         let new_session = Session {
+            schema_version: SCHEMA_VERSION,
             code: session_code.clone(),
             users: vec![(user_id, username)],
             queue: Vec::new(),
             owner: user_id,
             cast_status: CastStatus::default(),
             created_at: chrono::Utc::now().timestamp(),
+            settings: SessionSettings::default(),
+            starts_at: None,
+            reminder_sent: false,
+            muted: HashMap::new(),
+            backup_owner: None,
+            last_seen: HashMap::new(),
+            merge_request: None,
+            idle_warning_sent: false,
         };
 
         self.sessions.insert(session_code.clone(), new_session);
@@ -82,32 +254,278 @@ impl SessionState {
         session_code
     }
 
-    pub fn join_session(&mut self, user_id: UserId, username: Option<String>, code: &str) -> bool {
-        if let Some(session) = self.sessions.get_mut(code) {
+    // Create a new session using the settings from one of the user's saved presets
+    pub fn create_session_from_preset(
+        &mut self,
+        user_id: UserId,
+        username: Option<String>,
+        preset_name: &str,
+    ) -> Result<String> {
+        let preset = self
+            .presets
+            .get(&user_id)
+            .and_then(|presets| presets.get(&preset_name.to_lowercase()))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("You don't have a preset named {}.", preset_name))?;
+
+        let session_code = self.create_session(user_id, username);
+
+        if let Some(session) = self.sessions.get_mut(&session_code) {
+            session.settings = preset.settings;
+            session.cast_status.cast_device = preset.cast_device;
+            session.cast_status.cast_backend = preset.cast_backend;
+        }
+
+        // Save state after applying preset
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(session_code)
+    }
+
+    // Copy the queue of one of the user's archived sessions into their current session,
+    // either just the songs that never got played or the whole queue
+    pub fn copy_archived_queue(
+        &mut self,
+        user_id: &UserId,
+        source: &str,
+        full_queue: bool,
+    ) -> Result<usize> {
+        let archived = self
+            .archive
+            .find(user_id, source)
+            .ok_or_else(|| anyhow::anyhow!("Archived session not found"))?;
+
+        let now = chrono::Utc::now().timestamp();
+        let items: Vec<QueueItem> = archived
+            .session
+            .queue
+            .iter()
+            .filter(|item| full_queue || !item.played)
+            .map(|item| QueueItem {
+                added_at: now,
+                played: false,
+                played_at: None,
+                failed: None,
+                ..item.clone()
+            })
+            .collect();
+
+        let session_code = self
+            .user_sessions
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not in a session"))?;
+
+        let session = self
+            .sessions
+            .get_mut(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        let copied = items.len();
+        session.queue.extend(items);
+
+        // Save state after copying queue
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(copied)
+    }
+
+    // Save the settings of the user's current session as a named preset (session owner only)
+    pub fn save_preset(&mut self, user_id: &UserId, preset_name: &str) -> Result<()> {
+        if !self.is_session_owner(user_id) {
+            return Err(anyhow::anyhow!("Only the session owner can save presets."));
+        }
+
+        let session_code = self
+            .user_sessions
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not in a session"))?;
+
+        let session = self
+            .sessions
+            .get(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        let preset = SessionPreset {
+            settings: session.settings.clone(),
+            cast_device: session.cast_status.cast_device.clone(),
+            cast_backend: session.cast_status.cast_backend.clone(),
+        };
+
+        self.presets
+            .entry(*user_id)
+            .or_default()
+            .insert(preset_name.to_lowercase(), preset);
+
+        // Save state after saving preset
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(())
+    }
+
+    // Get the names of the user's saved presets
+    pub fn get_preset_names(&self, user_id: &UserId) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .presets
+            .get(user_id)
+            .map(|presets| presets.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    // Create a session that accepts members and songs now but can't be played until it
+    // starts. `start` is a local time like "2024-06-01 20:00", optionally followed by a
+    // timezone, otherwise it's in the timezone of the user's current session or UTC.
+    // The new session shows its times in that timezone. Returns the code and start time.
+    pub fn schedule_session(
+        &mut self,
+        user_id: UserId,
+        username: Option<String>,
+        start: &str,
+    ) -> Result<(String, i64)> {
+        let usage = || {
+            anyhow::anyhow!(
+                "Please give the start time as YYYY-MM-DD HH:MM and optionally a timezone, e.g. /schedule-session 2024-06-01 20:00 Europe/Berlin"
+            )
+        };
+
+        let (time, timezone) = match start.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [date, time] => (format!("{} {}", date, time), None),
+            [date, time, timezone] => (format!("{} {}", date, time), Some(*timezone)),
+            _ => return Err(usage()),
+        };
+        let timezone: Option<Tz> = match timezone {
+            Some(timezone) => Some(timezone.parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "Unknown timezone {}. Use a name like Europe/Berlin or America/New_York.",
+                    timezone
+                )
+            })?),
+            None => self
+                .user_sessions
+                .get(&user_id)
+                .and_then(|session_code| self.sessions.get(session_code))
+                .and_then(|session| session.settings.timezone.as_deref())
+                .and_then(|timezone| timezone.parse().ok()),
+        };
+
+        let starts_at = parse_local_time(&time, timezone).ok_or_else(usage)?;
+        if starts_at <= chrono::Utc::now().timestamp() {
+            return Err(anyhow::anyhow!("The start time must be in the future."));
+        }
+
+        let session_code = self.create_session(user_id, username);
+
+        if let Some(session) = self.sessions.get_mut(&session_code) {
+            session.starts_at = Some(starts_at);
+            session.settings.timezone = timezone.map(|timezone| timezone.name().to_string());
+        }
+
+        // Save state after scheduling session
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok((session_code, starts_at))
+    }
+
+    // Format a Unix timestamp in the timezone of the user's session
+    pub fn format_time(&self, user_id: &UserId, timestamp: i64) -> String {
+        match self
+            .user_sessions
+            .get(user_id)
+            .and_then(|session_code| self.sessions.get(session_code))
+        {
+            Some(session) => session.format_time(timestamp),
+            None => format_timestamp(timestamp, None),
+        }
+    }
+
+    // Get the scheduled start time of the user's session if it hasn't started yet
+    pub fn pending_start(&self, user_id: &UserId) -> Option<i64> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        session
+            .starts_at
+            .filter(|starts_at| *starts_at > chrono::Utc::now().timestamp())
+    }
+
+    // Collect reminders for scheduled sessions starting within `lead_time` seconds,
+    // marking each one as sent so members are only reminded once
+    pub fn take_due_reminders(&mut self, lead_time: i64) -> Vec<StartReminder> {
+        let now = chrono::Utc::now().timestamp();
+        let mut reminders = Vec::new();
+
+        for session in self.sessions.values_mut() {
+            if let Some(starts_at) = session.starts_at {
+                if !session.reminder_sent && starts_at > now && starts_at - now <= lead_time {
+                    session.reminder_sent = true;
+                    reminders.push(StartReminder {
+                        code: session.code.clone(),
+                        starts_at: session.format_time(starts_at),
+                        users: session.users.iter().map(|(id, _)| *id).collect(),
+                    });
+                }
+            }
+        }
+
+        if !reminders.is_empty() {
+            // Save state after marking reminders as sent
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+        }
+
+        reminders
+    }
+
+    pub fn join_session(
+        &mut self,
+        user_id: UserId,
+        username: Option<String>,
+        code: &str,
+    ) -> JoinResult {
+        let code = normalize_session_code(code);
+
+        if let Some(session) = self.sessions.get_mut(&code) {
             // Add user to session if not already in it
             if !session.users.iter().any(|(id, _)| *id == user_id) {
+                // Refuse new members once the session is at capacity
+                if let Some(max_users) = session.settings.max_users {
+                    if session.users.len() >= max_users {
+                        return JoinResult::SessionFull(max_users);
+                    }
+                }
                 session.users.push((user_id, username));
             }
-            self.user_sessions.insert(user_id, code.to_string());
+            self.user_sessions.insert(user_id, code);
 
             // Save state after joining session
             if let Err(e) = self.save() {
                 eprintln!("Failed to save session state: {}", e);
             }
 
-            true
+            JoinResult::Joined
         } else {
-            false
+            JoinResult::NotFound
         }
     }
 
-    pub async fn add_to_queue(
+    // Add a looked up video to the user's session, unless the session turns it away
+    pub fn add_to_queue(
         &mut self,
         user_id: UserId,
-        url: String,
+        video_info: VideoInfo,
         username: Option<String>,
         note: Option<String>,
-    ) -> Result<bool> {
+    ) -> Result<AddResult> {
         let session_code = self
             .user_sessions
             .get(&user_id)
@@ -118,7 +536,27 @@ impl SessionState {
             .get_mut(session_code)
             .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
 
-        let video_info = create_video_info(&url).await?;
+        if session.is_muted(&user_id) {
+            return Ok(AddResult::Rejected(
+                "The session owner has muted you, so you can't add songs right now.".to_string(),
+            ));
+        }
+
+        if let Some(reason) = video_info.unplayable {
+            return Ok(AddResult::Rejected(reason));
+        }
+
+        if let Some(reason) = session.length_rejection(&video_info) {
+            return Ok(AddResult::Rejected(reason));
+        }
+
+        if let Some(reason) = session.channel_rejection(&video_info) {
+            return Ok(AddResult::Rejected(reason));
+        }
+
+        if let Some(reason) = session.language_rejection(&video_info) {
+            return Ok(AddResult::Rejected(reason));
+        }
 
         let queue_item = QueueItem {
             video_info,
@@ -127,133 +565,834 @@ impl SessionState {
             added_at: chrono::Utc::now().timestamp(),
             played: false,
             note,
+            played_at: None,
+            failed: None,
         };
 
         session.queue.push(queue_item);
 
         // Save state after adding to queue
-        if let Err(e) = self.save() {
+        if let Err(e) = self.save_session(session_code) {
             eprintln!("Failed to save session state: {}", e);
         }
 
-        Ok(true)
+        Ok(AddResult::Added)
     }
 
-    pub fn get_queue(&self, user_id: &UserId) -> Option<Vec<&QueueItem>> {
-        let session_code = self.user_sessions.get(user_id)?;
-        let session = self.sessions.get(session_code)?;
+    // Add several videos at once (a playlist, or links sent together) to the user's
+    // session, skipping any already waiting in the queue, too long for the session or
+    // unplayable, and report what happened to each
+    pub fn add_playlist_videos(
+        &mut self,
+        user_id: UserId,
+        username: Option<String>,
+        videos: Vec<VideoInfo>,
+    ) -> Result<PlaylistImport> {
+        let session_code = self
+            .user_sessions
+            .get(&user_id)
+            .ok_or_else(|| anyhow::anyhow!("You're not in a session."))?;
 
-        let items = session.queue.iter().filter(|item| !item.played).collect();
+        let session = self
+            .sessions
+            .get_mut(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
 
-        Some(items)
-    }
+        if session.is_muted(&user_id) {
+            return Err(anyhow::anyhow!(
+                "The session owner has muted you, so you can't add songs right now."
+            ));
+        }
 
-    pub fn leave_session(&mut self, user_id: &UserId) -> bool {
-        if let Some(session_code) = self.user_sessions.remove(user_id) {
-            if let Some(session) = self.sessions.get_mut(&session_code) {
-                // Remove user from session
-                session.users.retain(|(id, _)| *id != *user_id);
+        let mut import = PlaylistImport::default();
+        let added_at = chrono::Utc::now().timestamp();
 
-                // If session is empty, remove it
-                if session.users.is_empty() {
-                    self.sessions.remove(&session_code);
-                }
-            }
+        for video_info in videos {
+            let queued = session
+                .queue
+                .iter()
+                .any(|item| !item.played && item.video_info.id == video_info.id);
 
-            // Save state after leaving session
-            if let Err(e) = self.save() {
-                eprintln!("Failed to save session state: {}", e);
+            if queued {
+                import.duplicates += 1;
+            } else if video_info.unplayable.is_some() {
+                import.unavailable += 1;
+            } else if session.length_rejection(&video_info).is_some() {
+                import.too_long += 1;
+            } else if session.channel_rejection(&video_info).is_some() {
+                import.wrong_channel += 1;
+            } else if session.language_rejection(&video_info).is_some() {
+                import.wrong_language += 1;
+            } else {
+                session.queue.push(QueueItem {
+                    video_info,
+                    added_by: user_id,
+                    username: username.clone(),
+                    added_at,
+                    played: false,
+                    note: None,
+                    played_at: None,
+                    failed: None,
+                });
+                import.added += 1;
             }
+        }
 
-            true
-        } else {
-            false
+        // Save state after adding the playlist
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
         }
+
+        Ok(import)
     }
 
-    pub fn is_in_session(&self, user_id: &UserId) -> bool {
-        self.user_sessions.contains_key(user_id)
+    // A song the user added that's still waiting in their session's queue
+    pub fn queued_item(&self, user_id: &UserId, added_at: i64) -> Option<&QueueItem> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        session
+            .queue
+            .iter()
+            .find(|item| !item.played && item.added_by == *user_id && item.added_at == added_at)
     }
 
-    // Check if user is the session owner
-    pub fn is_session_owner(&self, user_id: &UserId) -> bool {
-        if let Some(session_code) = self.user_sessions.get(user_id) {
-            if let Some(session) = self.sessions.get(session_code) {
-                return session.owner == *user_id;
-            }
+    // Swap the video of a song the user added for another one, keeping its place in
+    // the queue and its note. Fails with the reason to show the user.
+    pub fn replace_queued_video(
+        &mut self,
+        user_id: &UserId,
+        added_at: i64,
+        video_info: VideoInfo,
+    ) -> Result<()> {
+        let session_code = self
+            .user_sessions
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("You're not in a session."))?;
+
+        let session = self
+            .sessions
+            .get_mut(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        if let Some(reason) = &video_info.unplayable {
+            return Err(anyhow::anyhow!("{}", reason));
+        }
+        if let Some(reason) = session.length_rejection(&video_info) {
+            return Err(anyhow::anyhow!("{}", reason));
+        }
+        if let Some(reason) = session.channel_rejection(&video_info) {
+            return Err(anyhow::anyhow!("{}", reason));
+        }
+        if let Some(reason) = session.language_rejection(&video_info) {
+            return Err(anyhow::anyhow!("{}", reason));
         }
-        false
-    }
 
-    // Get the next item in the queue and mark it as current
-    pub fn next_in_queue(&mut self, user_id: &UserId) -> Option<QueueItem> {
-        // Only allow session owner to advance the queue
-        if !self.is_session_owner(user_id) {
-            return None;
+        let item = session
+            .queue
+            .iter_mut()
+            .find(|item| !item.played && item.added_by == *user_id && item.added_at == added_at)
+            .ok_or_else(|| anyhow::anyhow!("That song isn't in the queue any more."))?;
+        item.video_info = video_info;
+
+        // Save state after swapping the video
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
         }
 
-        let session_code = self.user_sessions.get(user_id)?;
+        Ok(())
+    }
 
-        // First, find the next unplayed item and clone it
-        let next_item = {
-            let session = self.sessions.get(session_code)?;
-            let next_item_index = session.queue.iter().position(|item| !item.played)?;
-            session.queue[next_item_index].clone()
-        };
+    // Videos waiting in the queue whose titles are still placeholders from a failed
+    // lookup, in one session or in all of them
+    pub fn videos_needing_titles(&self, session_code: Option<&str>) -> Vec<(VideoSource, String)> {
+        let mut videos: Vec<(VideoSource, String)> = Vec::new();
 
-        // Then, update the session state
-        if let Some(session) = self.sessions.get_mut(session_code) {
-            if let Some(index) = session.queue.iter().position(|item| !item.played) {
-                // Mark item as played
-                session.queue[index].played = true;
+        for session in self.sessions.values() {
+            if session_code.is_some_and(|code| code != session.code) {
+                continue;
+            }
+            for item in session.queue.iter().filter(|item| !item.played) {
+                let video = (item.video_info.source, item.video_info.id.clone());
+                if item.video_info.needs_title() && !videos.contains(&video) {
+                    videos.push(video);
+                }
+            }
+        }
+        videos
+    }
+
+    // Fill in queued videos that were looked up again and now have real titles,
+    // keeping each song's link and start time. Returns the sessions whose queues changed.
+    pub fn update_video_details(&mut self, videos: &[VideoInfo]) -> Vec<String> {
+        let mut updated_sessions = Vec::new();
 
-                // Set current video in cast status
-                session.cast_status.current_video = Some(session.queue[index].video_info.clone());
+        for session in self.sessions.values_mut() {
+            let mut updated = false;
 
-                // Save state after advancing queue
-                if let Err(e) = self.save() {
-                    eprintln!("Failed to save session state: {}", e);
+            for item in session.queue.iter_mut() {
+                if item.played || !item.video_info.needs_title() {
+                    continue;
                 }
+                let Some(found) = videos.iter().find(|video| {
+                    !video.needs_title()
+                        && video.source == item.video_info.source
+                        && video.id == item.video_info.id
+                }) else {
+                    continue;
+                };
+
+                item.video_info = VideoInfo {
+                    url: item.video_info.url.clone(),
+                    start_time: item.video_info.start_time,
+                    ..found.clone()
+                };
+                updated = true;
+            }
+
+            if updated {
+                updated_sessions.push(session.code.clone());
+            }
+        }
+
+        if !updated_sessions.is_empty() {
+            // Save state after filling in the titles
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
             }
         }
 
-        Some(next_item)
+        updated_sessions
     }
 
-    // Get the current playing video
-    pub fn get_current_video(&self, user_id: &UserId) -> Option<&VideoInfo> {
-        let session_code = self.user_sessions.get(user_id)?;
-        let session = self.sessions.get(session_code)?;
+    // Replace every session with the ones from a backup, keeping the archive.
+    // Returns the codes of the restored sessions.
+    pub fn restore(&mut self, backup: SessionState) -> Vec<String> {
+        self.sessions = backup.sessions;
+        self.user_sessions = backup.user_sessions;
+        self.presets = backup.presets;
 
-        session.cast_status.current_video.as_ref()
+        // Save state after restoring the backup
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        self.sessions.keys().cloned().collect()
     }
 
-    // Get history of played videos
-    pub fn get_history(&self, user_id: &UserId) -> Option<Vec<&QueueItem>> {
+    pub fn get_queue(&self, user_id: &UserId) -> Option<Vec<&QueueItem>> {
         let session_code = self.user_sessions.get(user_id)?;
         let session = self.sessions.get(session_code)?;
 
-        let items = session.queue.iter().filter(|item| item.played).collect();
+        let items = session.queue.iter().filter(|item| !item.played).collect();
 
         Some(items)
     }
 
-    pub fn get_session_info(&self, user_id: &UserId) -> Option<String> {
+    // Roughly how long until each song in the user's queue starts, in the order of get_queue
+    pub fn get_wait_times(&self, user_id: &UserId) -> Option<Vec<Option<i64>>> {
         let session_code = self.user_sessions.get(user_id)?;
         let session = self.sessions.get(session_code)?;
 
-        let duration = chrono::Utc::now().timestamp() - session.created_at;
-        let hours = duration / 3600;
-        let minutes = (duration % 3600) / 60;
+        Some(session.wait_times())
+    }
 
-        let mut info = format!(
-            "Session ID: {}\nDuration: {}h {}m\nUsers in session: {}",
-            session.code,
-            hours,
-            minutes,
+    pub fn leave_session(&mut self, user_id: &UserId) -> LeaveResult {
+        if let Some(session_code) = self.user_sessions.remove(user_id) {
+            let mut result = LeaveResult::Left;
+
+            if let Some(session) = self.sessions.get_mut(&session_code) {
+                // Remove user from session
+                session.users.retain(|(id, _)| *id != *user_id);
+
+                // If session is empty, archive it and report how the night went
+                if session.users.is_empty() {
+                    if let Some((stats, cast_target)) = self.end_session(&session_code) {
+                        result = LeaveResult::SessionEnded(stats, cast_target);
+                    }
+                } else if session.owner == *user_id {
+                    // Hand the session over so someone can still run /next
+                    if let Some(change) = session.promote_new_owner(None) {
+                        result = LeaveResult::OwnerChanged(change);
+                    }
+                }
+            }
+
+            // Save state after leaving session
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+
+            result
+        } else {
+            LeaveResult::NotInSession
+        }
+    }
+
+    // Remove a session, moving it to the archive, and return its statistics
+    // along with the device it left a video on, if any
+    fn end_session(&mut self, session_code: &str) -> Option<(String, Option<CastTarget>)> {
+        let session = self.sessions.remove(session_code)?;
+
+        for (user_id, _) in &session.users {
+            self.user_sessions.remove(user_id);
+        }
+
+        let stats = format_session_stats(&session);
+        let cast_target = session
+            .cast_status
+            .current_video
+            .is_some()
+            .then(|| session.cast_status.target());
+        self.archive.add(session);
+
+        Some((stats, cast_target))
+    }
+
+    // Collect warnings for sessions that have been idle for `idle_timeout` seconds,
+    // marking each one so the owner is only warned once per idle stretch
+    pub fn take_idle_warnings(&mut self, idle_timeout: i64) -> Vec<IdleWarning> {
+        let now = chrono::Utc::now().timestamp();
+        let mut warnings = Vec::new();
+
+        for session in self.sessions.values_mut() {
+            if !session.idle_warning_sent && now - session.last_activity() > idle_timeout {
+                session.idle_warning_sent = true;
+                warnings.push(IdleWarning {
+                    code: session.code.clone(),
+                    owner: session.owner,
+                });
+            }
+        }
+
+        if !warnings.is_empty() {
+            // Save state after marking warnings as sent
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+        }
+
+        warnings
+    }
+
+    // Close warned sessions that have now been idle for `expiry_timeout` seconds
+    pub fn expire_idle_sessions(&mut self, expiry_timeout: i64) -> Vec<ExpiredSession> {
+        let now = chrono::Utc::now().timestamp();
+
+        let expired_codes: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| {
+                session.idle_warning_sent && now - session.last_activity() > expiry_timeout
+            })
+            .map(|session| session.code.clone())
+            .collect();
+
+        let mut expired = Vec::new();
+        for code in expired_codes {
+            let members = match self.sessions.get(&code) {
+                Some(session) => session.users.iter().map(|(id, _)| *id).collect(),
+                None => continue,
+            };
+
+            if let Some((stats, cast_target)) = self.end_session(&code) {
+                expired.push(ExpiredSession {
+                    code,
+                    members,
+                    stats,
+                    cast_target,
+                });
+            }
+        }
+
+        if !expired.is_empty() {
+            // Save state after closing idle sessions
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+        }
+
+        expired
+    }
+
+    // Set the user's display name in their current session
+    pub fn set_nickname(&mut self, user_id: &UserId, nickname: &str) -> bool {
+        let Some(session_code) = self.user_sessions.get(user_id) else {
+            return false;
+        };
+
+        let Some(session) = self.sessions.get_mut(session_code) else {
+            return false;
+        };
+
+        match session.users.iter_mut().find(|(id, _)| id == user_id) {
+            Some(member) => member.1 = Some(nickname.to_string()),
+            None => return false,
+        }
+
+        // Save state after changing nickname
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        true
+    }
+
+    // Get the display name of whoever added a queue item in the user's session
+    pub fn display_name(&self, user_id: &UserId, item: &QueueItem) -> String {
+        match self
+            .user_sessions
+            .get(user_id)
+            .and_then(|session_code| self.sessions.get(session_code))
+        {
+            Some(session) => session.item_user_name(item),
+            None => item_user_name(item),
+        }
+    }
+
+    // Block a member of the owner's session from adding songs, optionally for a number of minutes
+    pub fn mute_user(
+        &mut self,
+        owner_id: &UserId,
+        target: &str,
+        minutes: Option<i64>,
+    ) -> Result<String> {
+        let session = self.owned_session_mut(owner_id)?;
+        let (target_id, name) = session.find_member(target)?;
+
+        if target_id == session.owner {
+            return Err(anyhow::anyhow!("The session owner can't be muted."));
+        }
+
+        let until = minutes.map(|minutes| chrono::Utc::now().timestamp() + minutes * 60);
+        session.muted.insert(target_id, until);
+
+        // Save state after muting user
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(name)
+    }
+
+    // Let a muted member of the owner's session add songs again
+    pub fn unmute_user(&mut self, owner_id: &UserId, target: &str) -> Result<String> {
+        let session = self.owned_session_mut(owner_id)?;
+        let (target_id, name) = session.find_member(target)?;
+
+        if session.muted.remove(&target_id).is_none() {
+            return Err(anyhow::anyhow!("{} isn't muted.", name));
+        }
+
+        // Save state after unmuting user
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(name)
+    }
+
+    // Designate the member who takes over if the owner leaves or goes inactive
+    pub fn set_backup_owner(&mut self, owner_id: &UserId, target: &str) -> Result<String> {
+        let session = self.owned_session_mut(owner_id)?;
+        let (target_id, name) = session.find_member(target)?;
+
+        if target_id == session.owner {
+            return Err(anyhow::anyhow!("You're already the session owner."));
+        }
+
+        session.backup_owner = Some(target_id);
+
+        // Save state after setting backup owner
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(name)
+    }
+
+    // Record that the user just interacted with the bot
+    pub fn touch_user(&mut self, user_id: &UserId) {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get_mut(session_code) {
+                session
+                    .last_seen
+                    .insert(*user_id, chrono::Utc::now().timestamp());
+                session.idle_warning_sent = false;
+            }
+        }
+    }
+
+    // Hand over sessions whose owner has been inactive for `timeout` seconds
+    // while another member is still active
+    pub fn take_inactive_owner_changes(&mut self, timeout: i64) -> Vec<OwnerChange> {
+        let now = chrono::Utc::now().timestamp();
+        let mut changes = Vec::new();
+
+        for session in self.sessions.values_mut() {
+            if now - session.last_seen_at(&session.owner) <= timeout {
+                continue;
+            }
+            // Nobody else was around lately either, so leave the owner be
+            if let Some(change) = session.promote_new_owner(Some(timeout)) {
+                changes.push(change);
+            }
+        }
+
+        if !changes.is_empty() {
+            // Save state after changing owners
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+        }
+
+        changes
+    }
+
+    // Merge another session into the owner's session. Unless the same person owns both,
+    // the other owner has to agree by asking to merge back before anything changes.
+    pub fn merge_sessions(&mut self, owner_id: &UserId, other_code: &str) -> Result<MergeResult> {
+        let other_code = normalize_session_code(other_code);
+        let code = self.owned_session_mut(owner_id)?.code.clone();
+
+        if code == other_code {
+            return Err(anyhow::anyhow!("You can't merge a session with itself."));
+        }
+
+        let other = self
+            .sessions
+            .get(&other_code)
+            .ok_or_else(|| anyhow::anyhow!("There's no session with code {}.", other_code))?;
+
+        let agreed = other.owner == *owner_id || other.merge_request.as_deref() == Some(&code);
+        let other_owner = other.owner;
+
+        if !agreed {
+            if let Some(session) = self.sessions.get_mut(&code) {
+                session.merge_request = Some(other_code);
+            }
+
+            // Save state after requesting merge
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save session state: {}", e);
+            }
+
+            return Ok(MergeResult::Requested { other_owner });
+        }
+
+        // The session that asked first keeps its code
+        let (code, other_code) = if other.merge_request.as_deref() == Some(&code) {
+            (other_code, code)
+        } else {
+            (code, other_code)
+        };
+
+        let other = self
+            .sessions
+            .remove(&other_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+        let session = self
+            .sessions
+            .get_mut(&code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        session.merge_with(other);
+
+        let members: Vec<UserId> = session.users.iter().map(|(id, _)| *id).collect();
+        for user_id in &members {
+            self.user_sessions.insert(*user_id, code.clone());
+        }
+
+        // Save state after merging sessions
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(MergeResult::Merged {
+            code,
+            merged_code: other_code,
+            members,
+        })
+    }
+
+    // Get the session the user owns, for owner-only changes
+    fn owned_session_mut(&mut self, user_id: &UserId) -> Result<&mut Session> {
+        let session_code = self
+            .user_sessions
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("You're not in a session."))?;
+
+        let session = self
+            .sessions
+            .get_mut(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        if session.owner != *user_id {
+            return Err(anyhow::anyhow!("Only the session owner can do that."));
+        }
+
+        Ok(session)
+    }
+
+    pub fn is_in_session(&self, user_id: &UserId) -> bool {
+        self.user_sessions.contains_key(user_id)
+    }
+
+    // Check if user is the session owner
+    pub fn is_session_owner(&self, user_id: &UserId) -> bool {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get(session_code) {
+                return session.owner == *user_id;
+            }
+        }
+        false
+    }
+
+    // Get the next item in the queue, which stays queued until it starts playing
+    pub fn next_in_queue(&mut self, user_id: &UserId) -> Option<QueueItem> {
+        // Only allow session owner to advance the queue
+        if !self.is_session_owner(user_id) {
+            return None;
+        }
+
+        let session_code = self.user_sessions.get(user_id)?.clone();
+        self.upcoming_item(&session_code)
+    }
+
+    // The next unplayed item of a session
+    pub fn upcoming_item(&self, session_code: &str) -> Option<QueueItem> {
+        let session = self.sessions.get(session_code)?;
+        session.next_item().cloned()
+    }
+
+    // Mark an item the cast device loaded as played and current
+    pub fn start_item(&mut self, session_code: &str, item: &QueueItem) {
+        if let Some(session) = self.sessions.get_mut(session_code) {
+            if let Some(queued) = session
+                .queue
+                .iter_mut()
+                .find(|queued| !queued.played && queued.same_item(item))
+            {
+                queued.played = true;
+                queued.played_at = Some(chrono::Utc::now().timestamp());
+            }
+
+            // Set current video in cast status
+            session.cast_status.current_video = Some(item.video_info.clone());
+        }
+
+        // Save state after starting the item
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Mark an item the cast device couldn't play as failed, so it leaves the queue
+    pub fn mark_failed(&mut self, session_code: &str, item: &QueueItem, reason: &str) {
+        if let Some(session) = self.sessions.get_mut(session_code) {
+            if let Some(queued) = session
+                .queue
+                .iter_mut()
+                .find(|queued| queued.same_item(item))
+            {
+                if !queued.played {
+                    queued.played = true;
+                    queued.played_at = Some(chrono::Utc::now().timestamp());
+                }
+                queued.failed = Some(reason.to_string());
+            }
+        }
+
+        // Save state after marking the item
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // The queue item for the video the session is playing
+    pub fn current_item(&self, session_code: &str) -> Option<QueueItem> {
+        let session = self.sessions.get(session_code)?;
+        let video = session.cast_status.current_video.as_ref()?;
+
+        session
+            .queue
+            .iter()
+            .filter(|item| item.played && item.video_info.id == video.id)
+            .max_by_key(|item| item.played_at)
+            .cloned()
+    }
+
+    // Record whether a session's video is playing
+    pub fn set_session_playing(&mut self, session_code: &str, playing: bool) {
+        if let Some(session) = self.sessions.get_mut(session_code) {
+            session.cast_status.is_playing = playing;
+        }
+
+        // Save state after changing playback
+        if let Err(e) = self.save_session(session_code) {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Get the current playing video
+    pub fn get_current_video(&self, user_id: &UserId) -> Option<&VideoInfo> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        session.cast_status.current_video.as_ref()
+    }
+
+    // Get the cast device chosen for the user's session
+    pub fn get_cast_device(&self, user_id: &UserId) -> Option<CastDevice> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        session.cast_status.device()
+    }
+
+    // Get where the user's session plays videos, for commands that also go to the speaker group
+    pub fn get_cast_target(&self, user_id: &UserId) -> CastTarget {
+        self.user_sessions
+            .get(user_id)
+            .and_then(|session_code| self.sessions.get(session_code))
+            .map(|session| session.cast_status.target())
+            .unwrap_or_default()
+    }
+
+    // Choose a speaker group to play along with the video, or None to stop using one
+    pub fn set_audio_group(&mut self, user_id: &UserId, group: Option<&str>) {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get_mut(session_code) {
+                session.cast_status.audio_group = group.map(|group| group.to_string());
+            }
+        }
+
+        // Save state after choosing speaker group
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Choose the cast device for the user's session
+    pub fn set_cast_device(&mut self, user_id: &UserId, device: &CastDevice) {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get_mut(session_code) {
+                session.cast_status.set_device(device);
+            }
+        }
+
+        // Save state after choosing device
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Record whether the video in the user's session is playing or paused
+    pub fn set_playing(&mut self, user_id: &UserId, playing: bool) {
+        if let Some(session_code) = self.user_sessions.get(user_id).cloned() {
+            self.set_session_playing(&session_code, playing);
+        }
+    }
+
+    // Record the volume of the user's session's cast device
+    pub fn set_volume(&mut self, user_id: &UserId, volume: Option<u8>, muted: bool) {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get_mut(session_code) {
+                if volume.is_some() {
+                    session.cast_status.volume = volume;
+                }
+                session.cast_status.muted = muted;
+            }
+        }
+
+        // Save state after changing volume
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Get the cast status of the user's session
+    pub fn get_cast_status(&self, user_id: &UserId) -> Option<&CastStatus> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        Some(&session.cast_status)
+    }
+
+    // Record that playback in the user's session was stopped
+    pub fn stop_playback(&mut self, user_id: &UserId) {
+        if let Some(session_code) = self.user_sessions.get(user_id) {
+            if let Some(session) = self.sessions.get_mut(session_code) {
+                session.cast_status.is_playing = false;
+                session.cast_status.current_video = None;
+            }
+        }
+
+        // Save state after stopping playback
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+    }
+
+    // Get history of played videos
+    pub fn get_history(&self, user_id: &UserId) -> Option<Vec<&QueueItem>> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        let items = session.queue.iter().filter(|item| item.played).collect();
+
+        Some(items)
+    }
+
+    pub fn get_session_info(&self, user_id: &UserId) -> Option<String> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        let duration = chrono::Utc::now().timestamp() - session.created_at;
+        let hours = duration / 3600;
+        let minutes = (duration % 3600) / 60;
+
+        let mut info = format!(
+            "Session ID: {}\nDuration: {}h {}m\nUsers in session: {}",
+            session.code,
+            hours,
+            minutes,
             session.users.len()
         );
 
+        if let Some(starts_at) = session.starts_at {
+            info.push_str(&format!(
+                "\nScheduled start: {}",
+                session.format_time(starts_at)
+            ));
+        }
+
+        info.push_str(&format!(
+            "\nCast device: {}",
+            session
+                .cast_status
+                .device()
+                .map(|device| device.to_string())
+                .unwrap_or_else(|| DEFAULT_DEVICE.to_string())
+        ));
+
+        if let Some(group) = &session.cast_status.audio_group {
+            info.push_str(&format!("\nSpeaker group: {}", group));
+        }
+
+        if let Some(video) = &session.cast_status.current_video {
+            let video_title = video
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Video ID: {}", video.id));
+            let state = if session.cast_status.is_playing {
+                "Playing"
+            } else {
+                "Paused"
+            };
+            info.push_str(&format!("\n{}: {}", state, video_title));
+        }
+
         // If user is the owner, add list of users
         if session.owner == *user_id {
             info.push_str("\n\nUsers in session:");
@@ -265,15 +1404,757 @@ impl SessionState {
 
         Some(info)
     }
+
+    // Get statistics for the user's session
+    pub fn get_stats(&self, user_id: &UserId) -> Option<String> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        Some(format_session_stats(session))
+    }
+
+    // List public sessions that still have room, busiest first
+    pub fn get_public_sessions(&self) -> Vec<PublicSession> {
+        let mut sessions: Vec<PublicSession> = self
+            .sessions
+            .values()
+            .filter(|session| session.settings.public)
+            .filter(|session| match session.settings.max_users {
+                Some(max_users) => session.users.len() < max_users,
+                None => true,
+            })
+            .map(|session| PublicSession {
+                code: session.code.clone(),
+                title: session
+                    .settings
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Session {}", session.code)),
+                members: session.users.len(),
+            })
+            .collect();
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.members));
+        sessions
+    }
+
+    // Describe the current settings of the user's session
+    pub fn get_settings(&self, user_id: &UserId) -> Option<String> {
+        let session_code = self.user_sessions.get(user_id)?;
+        let session = self.sessions.get(session_code)?;
+
+        let settings = &session.settings;
+
+        let max_users = match settings.max_users {
+            Some(max) => max.to_string(),
+            None => "unlimited".to_string(),
+        };
+
+        let lines = [
+            format!("- maxusers: {}", max_users),
+            format!("- public: {}", if settings.public { "on" } else { "off" }),
+            format!("- title: {}", settings.title.as_deref().unwrap_or("(none)")),
+            format!("- tz: {}", settings.timezone.as_deref().unwrap_or("UTC")),
+            format!(
+                "- intermission: {}",
+                match settings.intermission {
+                    Some(seconds) => format!("{}s", seconds),
+                    None => "off".to_string(),
+                }
+            ),
+            format!(
+                "- announce: {}",
+                if settings.announce { "on" } else { "off" }
+            ),
+            format!(
+                "- maxduration: {}",
+                match settings.max_duration {
+                    Some(seconds) => format!("{} minutes", seconds / 60),
+                    None => "off".to_string(),
+                }
+            ),
+            format!(
+                "- blockchannels: {}",
+                channel_list(&settings.blocked_channels)
+            ),
+            format!(
+                "- allowchannels: {}",
+                channel_list(&settings.allowed_channels)
+            ),
+            format!(
+                "- language: {}",
+                settings.language.as_deref().unwrap_or("off")
+            ),
+        ];
+
+        Some(format!(
+            "Session settings:\n{}\n\nChange a setting with /settings [name] [value]",
+            lines.join("\n")
+        ))
+    }
+
+    // Change a setting of the user's session (session owner only)
+    pub fn update_setting(&mut self, user_id: &UserId, name: &str, value: &str) -> Result<String> {
+        if !self.is_session_owner(user_id) {
+            return Err(anyhow::anyhow!(
+                "Only the session owner can change settings."
+            ));
+        }
+
+        let session_code = self
+            .user_sessions
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not in a session"))?;
+
+        let session = self
+            .sessions
+            .get_mut(session_code)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+
+        let message = match name.to_lowercase().as_str() {
+            "maxusers" => {
+                if value.eq_ignore_ascii_case("off") {
+                    session.settings.max_users = None;
+                    "Session capacity removed.".to_string()
+                } else {
+                    let max_users: usize =
+                        value.parse().ok().filter(|max| *max > 0).ok_or_else(|| {
+                            anyhow::anyhow!("maxusers must be a positive number or \"off\".")
+                        })?;
+                    session.settings.max_users = Some(max_users);
+                    format!(
+                        "Session capacity set to {} users ({} currently joined).",
+                        max_users,
+                        session.users.len()
+                    )
+                }
+            }
+            "public" => {
+                session.settings.public = parse_toggle(name, value)?;
+                if session.settings.public {
+                    "Session is now public and listed in /browse.".to_string()
+                } else {
+                    "Session is now private.".to_string()
+                }
+            }
+            "title" => {
+                if value.eq_ignore_ascii_case("off") {
+                    session.settings.title = None;
+                    "Session title removed.".to_string()
+                } else {
+                    session.settings.title = Some(value.to_string());
+                    format!("Session title set to {}.", value)
+                }
+            }
+            "tz" | "timezone" => {
+                if value.eq_ignore_ascii_case("utc") || value.eq_ignore_ascii_case("off") {
+                    session.settings.timezone = None;
+                    "Times will be shown in UTC.".to_string()
+                } else {
+                    let tz: Tz = value.parse().map_err(|_| {
+                        anyhow::anyhow!(
+                            "Unknown timezone {}. Use a name like Europe/Berlin or America/New_York.",
+                            value
+                        )
+                    })?;
+                    session.settings.timezone = Some(tz.name().to_string());
+                    format!("Times will be shown in {}.", tz.name())
+                }
+            }
+            "intermission" => {
+                if value.eq_ignore_ascii_case("off") || value == "0" {
+                    session.settings.intermission = None;
+                    "Songs will now start right after each other.".to_string()
+                } else {
+                    let seconds: u64 = value
+                        .trim_end_matches('s')
+                        .parse()
+                        .ok()
+                        .filter(|seconds| (1..=MAX_INTERMISSION).contains(seconds))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "intermission must be a number of seconds up to {} or \"off\".",
+                                MAX_INTERMISSION
+                            )
+                        })?;
+                    session.settings.intermission = Some(seconds);
+                    format!(
+                        "The next singer will be announced on the TV for {}s between songs.",
+                        seconds
+                    )
+                }
+            }
+            "announce" => {
+                session.settings.announce = parse_toggle(name, value)?;
+                if session.settings.announce {
+                    "The next singer will be announced out loud before each song.".to_string()
+                } else {
+                    "Songs will start without a spoken announcement.".to_string()
+                }
+            }
+            "maxduration" => {
+                if value.eq_ignore_ascii_case("off") {
+                    session.settings.max_duration = None;
+                    "Songs of any length can be added.".to_string()
+                } else {
+                    let minutes: u64 = value
+                        .trim_end_matches('m')
+                        .parse()
+                        .ok()
+                        .filter(|minutes| *minutes > 0)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("maxduration must be a number of minutes or \"off\".")
+                        })?;
+                    session.settings.max_duration = Some(minutes * 60);
+                    format!("Songs longer than {} minutes will be turned away.", minutes)
+                }
+            }
+            "blockchannels" => {
+                session.settings.blocked_channels = parse_channels(name, value)?;
+                if session.settings.blocked_channels.is_empty() {
+                    "Videos from any channel can be added.".to_string()
+                } else {
+                    format!(
+                        "Videos from {} blocked channel(s) will be turned away.",
+                        session.settings.blocked_channels.len()
+                    )
+                }
+            }
+            "allowchannels" => {
+                session.settings.allowed_channels = parse_channels(name, value)?;
+                if session.settings.allowed_channels.is_empty() {
+                    "Videos no longer have to come from particular channels.".to_string()
+                } else {
+                    format!(
+                        "Only videos from {} allowed channel(s) can be added.",
+                        session.settings.allowed_channels.len()
+                    )
+                }
+            }
+            "language" => {
+                if value.eq_ignore_ascii_case("off") {
+                    session.settings.language = None;
+                    "Songs in any language can be added.".to_string()
+                } else {
+                    let language = value.trim().to_lowercase();
+                    let valid = (2..=3).contains(&language.len())
+                        && language.chars().all(|c| c.is_ascii_lowercase());
+                    if !valid {
+                        return Err(anyhow::anyhow!(
+                            "language must be a language code like en, es or ja, or \"off\"."
+                        ));
+                    }
+                    format!(
+                        "Only songs in {} will be taken. Songs whose language isn't known are let through.",
+                        session.settings.language.insert(language)
+                    )
+                }
+            }
+            _ => return Err(anyhow::anyhow!("Unknown setting: {}", name)),
+        };
+
+        // Save state after changing settings
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save session state: {}", e);
+        }
+
+        Ok(message)
+    }
+}
+
+impl Session {
+    // Format a Unix timestamp in the session's timezone
+    pub fn format_time(&self, timestamp: i64) -> String {
+        format_timestamp(timestamp, self.settings.timezone.as_deref())
+    }
+
+    // Find a member by display name, with or without a leading @, or by Telegram user ID
+    pub fn find_member(&self, name: &str) -> Result<(UserId, String)> {
+        let name = name.trim().trim_start_matches('@');
+        let user_id = name.parse::<u64>().ok().map(UserId);
+
+        self.users
+            .iter()
+            .find_map(|(id, member_name)| {
+                let display_name = member_name
+                    .clone()
+                    .unwrap_or_else(|| format!("User {}", id.0));
+                let name_matches = member_name
+                    .as_ref()
+                    .is_some_and(|member_name| member_name.eq_ignore_ascii_case(name));
+
+                (name_matches || user_id == Some(*id)).then_some((*id, display_name))
+            })
+            .ok_or_else(|| anyhow::anyhow!("No one called {} is in this session.", name))
+    }
+
+    // Absorb another session's members, history and queue. History is kept in the
+    // order songs were played and the upcoming songs of both queues take turns.
+    fn merge_with(&mut self, other: Session) {
+        for (user_id, name) in other.users {
+            if !self.users.iter().any(|(id, _)| *id == user_id) {
+                self.users.push((user_id, name));
+            }
+        }
+
+        let (mut played, unplayed): (Vec<QueueItem>, Vec<QueueItem>) =
+            std::mem::take(&mut self.queue)
+                .into_iter()
+                .partition(|item| item.played);
+        let (other_played, other_unplayed): (Vec<QueueItem>, Vec<QueueItem>) =
+            other.queue.into_iter().partition(|item| item.played);
+
+        played.extend(other_played);
+        played.sort_by_key(|item| item.played_at);
+
+        let mut upcoming = unplayed.into_iter();
+        let mut other_upcoming = other_unplayed.into_iter();
+        let mut queue = played;
+        loop {
+            match (upcoming.next(), other_upcoming.next()) {
+                (None, None) => break,
+                (item, other_item) => queue.extend(item.into_iter().chain(other_item)),
+            }
+        }
+        self.queue = queue;
+
+        self.muted.extend(other.muted);
+        self.last_seen.extend(other.last_seen);
+        self.merge_request = None;
+    }
+
+    // Unix timestamp of the most recent sign of life: a member interacting, a song
+    // being added or played, or the scheduled start time if that's still ahead
+    fn last_activity(&self) -> i64 {
+        let last_seen = self.last_seen.values().copied();
+        let queue_activity = self
+            .queue
+            .iter()
+            .flat_map(|item| std::iter::once(item.added_at).chain(item.played_at));
+
+        last_seen
+            .chain(queue_activity)
+            .chain(self.starts_at)
+            .fold(self.created_at, i64::max)
+    }
+
+    // When a member last interacted with the bot, or when the session started if never
+    fn last_seen_at(&self, user_id: &UserId) -> i64 {
+        self.last_seen
+            .get(user_id)
+            .copied()
+            .unwrap_or(self.created_at)
+    }
+
+    // Make the backup owner, or else the most recently active other member, the new
+    // owner. With `active_within` only members seen in that many seconds qualify, and
+    // nobody is promoted if none were.
+    fn promote_new_owner(&mut self, active_within: Option<i64>) -> Option<OwnerChange> {
+        let now = chrono::Utc::now().timestamp();
+        let candidates: Vec<UserId> = self
+            .users
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| *id != self.owner)
+            .filter(|id| active_within.is_none_or(|within| now - self.last_seen_at(id) <= within))
+            .collect();
+
+        let backup = self
+            .backup_owner
+            .filter(|backup| candidates.contains(backup));
+        let new_owner = backup.or_else(|| {
+            candidates
+                .iter()
+                .copied()
+                .max_by_key(|id| self.last_seen_at(id))
+        })?;
+
+        if backup.is_some() {
+            self.backup_owner = None;
+        }
+        self.owner = new_owner;
+
+        let new_owner_name = self
+            .users
+            .iter()
+            .find(|(id, _)| *id == new_owner)
+            .and_then(|(_, name)| name.clone())
+            .unwrap_or_else(|| format!("User {}", new_owner.0));
+
+        Some(OwnerChange {
+            code: self.code.clone(),
+            new_owner,
+            new_owner_name,
+            members: self.users.iter().map(|(id, _)| *id).collect(),
+        })
+    }
+
+    // Whether a member is currently blocked from adding songs
+    pub fn is_muted(&self, user_id: &UserId) -> bool {
+        match self.muted.get(user_id) {
+            Some(Some(until)) => *until > chrono::Utc::now().timestamp(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    // Why a video can't be added because of its length, if it's over the session's limit.
+    // Songs whose length couldn't be looked up are let through.
+    fn length_rejection(&self, video_info: &VideoInfo) -> Option<String> {
+        let max_duration = self.settings.max_duration?;
+        let duration = video_info.duration?;
+
+        (duration > max_duration).then(|| {
+            format!(
+                "That video is {} long, but songs in this session can be at most {}.",
+                format_duration(duration as i64),
+                format_duration(max_duration as i64)
+            )
+        })
+    }
+
+    // Why a video can't be added because of the channel it's from, if the owner blocked
+    // that channel or only allows others. Videos whose channel isn't known are let through.
+    fn channel_rejection(&self, video_info: &VideoInfo) -> Option<String> {
+        let channel_id = video_info.channel_id.as_ref()?;
+
+        if self.settings.blocked_channels.contains(channel_id) {
+            Some("The session owner has blocked videos from that channel. Try another version of the song.".to_string())
+        } else if !self.settings.allowed_channels.is_empty()
+            && !self.settings.allowed_channels.contains(channel_id)
+        {
+            Some("This session only takes videos from channels the owner picked. Try another version of the song.".to_string())
+        } else {
+            None
+        }
+    }
+
+    // Why a video can't be added because it isn't in the session's language.
+    // Videos whose language isn't known are let through.
+    fn language_rejection(&self, video_info: &VideoInfo) -> Option<String> {
+        let wanted = self.settings.language.as_deref()?;
+        let language = video_info.language.as_deref()?.to_lowercase();
+
+        // "es" covers regional variants like "es-419"
+        let primary = language.split('-').next().unwrap_or_default();
+        (primary != wanted).then(|| {
+            format!(
+                "This session only takes songs in {}, and that one is in {}.",
+                wanted, language
+            )
+        })
+    }
+
+    // Display name for whoever added a queue item, preferring their current
+    // membership entry (which holds any /nickname) over the name stored on the item
+    pub fn item_user_name(&self, item: &QueueItem) -> String {
+        self.users
+            .iter()
+            .find(|(id, _)| *id == item.added_by)
+            .and_then(|(_, name)| name.clone())
+            .unwrap_or_else(|| item_user_name(item))
+    }
+
+    // The song that plays after the current one
+    pub fn next_item(&self) -> Option<&QueueItem> {
+        self.queue.iter().find(|item| !item.played)
+    }
+
+    // Roughly how many seconds until each unplayed song starts, in queue order.
+    // None once a song ahead of it has no known length.
+    pub fn wait_times(&self) -> Vec<Option<i64>> {
+        let now = chrono::Utc::now().timestamp();
+
+        // What's left of the song playing now
+        let mut wait = match &self.cast_status.current_video {
+            None => Some(0),
+            Some(video) => {
+                let started_at = self
+                    .queue
+                    .iter()
+                    .filter(|item| item.played && item.video_info.id == video.id)
+                    .filter_map(|item| item.played_at)
+                    .max();
+
+                match (video.duration, started_at) {
+                    (Some(duration), Some(started_at)) => {
+                        Some((duration as i64 - (now - started_at)).max(0))
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        self.queue
+            .iter()
+            .filter(|item| !item.played)
+            .map(|item| {
+                let starts_in = wait;
+                wait = wait
+                    .zip(item.video_info.duration)
+                    .map(|(wait, duration)| wait + duration as i64);
+                starts_in
+            })
+            .collect()
+    }
+
+    // Who's singing the song that played last and who's next, for the TV
+    pub fn now_playing(&self) -> NowPlaying {
+        let singer = self
+            .queue
+            .iter()
+            .filter(|item| item.played)
+            .max_by_key(|item| item.played_at)
+            .map(|item| self.item_user_name(item))
+            .unwrap_or_default();
+
+        NowPlaying {
+            singer,
+            up_next: self.up_next(None),
+        }
+    }
+
+    // What the TV shows once `item` starts, before it's marked as played
+    pub fn now_playing_with(&self, item: &QueueItem) -> NowPlaying {
+        NowPlaying {
+            singer: self.item_user_name(item),
+            up_next: self.up_next(Some(item)),
+        }
+    }
+
+    // The next few songs for the TV, leaving out one that's about to start
+    fn up_next(&self, starting: Option<&QueueItem>) -> Vec<String> {
+        self.queue
+            .iter()
+            .filter(|item| !item.played)
+            .filter(|item| !starting.is_some_and(|starting| item.same_item(starting)))
+            .take(UP_NEXT_COUNT)
+            .map(|item| {
+                format!(
+                    "{} \u{2014} sung by {}",
+                    item_video_title(item),
+                    self.item_user_name(item)
+                )
+            })
+            .collect()
+    }
 }
 
-// Generate a random 4-digit session code
-pub fn generate_session_code() -> String {
+impl QueueItem {
+    // Whether two copies are of the same entry in the queue
+    pub fn same_item(&self, other: &QueueItem) -> bool {
+        self.added_at == other.added_at
+            && self.added_by == other.added_by
+            && self.video_info.id == other.video_info.id
+    }
+}
+
+// Parse an on/off setting value
+fn parse_toggle(name: &str, value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => Err(anyhow::anyhow!("{} must be \"on\" or \"off\".", name)),
+    }
+}
+
+// Parse a list of YouTube channels for the blockchannels and allowchannels settings,
+// as channel IDs or links separated by spaces or commas. "off" clears the list.
+fn parse_channels(name: &str, value: &str) -> Result<Vec<String>> {
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(Vec::new());
+    }
+
+    let mut channels = Vec::new();
+    for channel in value.split(|c: char| c == ',' || c.is_whitespace()) {
+        if channel.is_empty() {
+            continue;
+        }
+        let channel_id = extract_channel_id(channel).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} must be YouTube channel IDs (starting with UC) or links to youtube.com/channel/..., or \"off\".",
+                name
+            )
+        })?;
+        if !channels.contains(&channel_id) {
+            channels.push(channel_id);
+        }
+    }
+
+    if channels.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} needs at least one channel or \"off\".",
+            name
+        ));
+    }
+    Ok(channels)
+}
+
+// How a list of channels reads in /settings
+fn channel_list(channels: &[String]) -> String {
+    if channels.is_empty() {
+        "off".to_string()
+    } else {
+        channels.join(", ")
+    }
+}
+
+// Display name stored on a queue item when it was added
+fn item_user_name(item: &QueueItem) -> String {
+    item.username
+        .clone()
+        .unwrap_or_else(|| format!("User {}", item.added_by.0))
+}
+
+// Display name for a queue item's video
+pub fn item_video_title(item: &QueueItem) -> String {
+    item.video_info
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Video ID: {}", item.video_info.id))
+}
+
+// Format a number of seconds as e.g. "1h 5m" or "3m 20s"
+pub fn format_duration(seconds: i64) -> String {
+    let hours = seconds / 3600;
+    let minutes = (seconds % 3600) / 60;
+
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, seconds % 60)
+    }
+}
+
+// Read a date and time like 2024-06-01 20:00 on the clock in a timezone, or UTC if
+// unset, as a Unix timestamp. None if it isn't one or the clocks skip it, e.g. when
+// they go forward; when they go back the earlier of the two is used.
+fn parse_local_time(text: &str, timezone: Option<Tz>) -> Option<i64> {
+    let time = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").ok()?;
+
+    match timezone {
+        Some(tz) => tz
+            .from_local_datetime(&time)
+            .earliest()
+            .map(|time| time.timestamp()),
+        None => Some(time.and_utc().timestamp()),
+    }
+}
+
+// Format a Unix timestamp for display in the given IANA timezone, or UTC if unset
+pub fn format_timestamp(timestamp: i64, timezone: Option<&str>) -> String {
+    let Some(time) = chrono::DateTime::from_timestamp(timestamp, 0) else {
+        return timestamp.to_string();
+    };
+
+    match timezone.and_then(|timezone| timezone.parse::<Tz>().ok()) {
+        Some(tz) => time
+            .with_timezone(&tz)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string(),
+        None => time.format("%Y-%m-%d %H:%M UTC").to_string(),
+    }
+}
+
+// Build the statistics summary for a session from its queue history.
+// A song's length is its video's duration. When that isn't known it's measured
+// from when the song started until the next song started, up to MAX_UNTIMED_SONG.
+pub fn format_session_stats(session: &Session) -> String {
+    let played: Vec<&QueueItem> = session.queue.iter().filter(|item| item.played).collect();
+
+    if played.is_empty() {
+        return format!("Session {} stats:\nNo songs were played.", session.code);
+    }
+
+    let song_lengths: Vec<(&QueueItem, i64)> = played
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| {
+            if let Some(duration) = item.video_info.duration {
+                return Some((*item, duration as i64));
+            }
+            let start = item.played_at?;
+            let end = played.get(i + 1)?.played_at?;
+            Some((*item, (end - start).min(MAX_UNTIMED_SONG)))
+        })
+        .collect();
+
+    let total_time: i64 = song_lengths.iter().map(|(_, length)| length).sum();
+
+    // Count submissions per user, across played and unplayed songs
+    let mut submissions: HashMap<UserId, (String, usize)> = HashMap::new();
+    for item in &session.queue {
+        let entry = submissions
+            .entry(item.added_by)
+            .or_insert_with(|| (session.item_user_name(item), 0));
+        entry.1 += 1;
+    }
+
+    let mut stats = format!(
+        "Session {} stats:\nSongs played: {}\nTotal singing time: {}",
+        session.code,
+        played.len(),
+        format_duration(total_time)
+    );
+
+    if let Some((name, count)) = submissions.values().max_by_key(|(_, count)| *count) {
+        stats.push_str(&format!(
+            "\nMost active submitter: {} ({} songs)",
+            name, count
+        ));
+    }
+
+    if let Some((item, length)) = song_lengths.iter().max_by_key(|(_, length)| *length) {
+        stats.push_str(&format!(
+            "\nLongest song: {} ({})",
+            item_video_title(item),
+            format_duration(*length)
+        ));
+    }
+
+    for (label, item) in [("First", played[0]), ("Last", played[played.len() - 1])] {
+        stats.push_str(&format!("\n{} song: {}", label, item_video_title(item)));
+        if let Some(played_at) = item.played_at {
+            stats.push_str(&format!(" at {}", session.format_time(played_at)));
+        }
+    }
+
+    stats
+}
+
+// Longest a song without a known duration counts for in the stats, in seconds.
+// The gap until the next song also covers any break taken in between.
+const MAX_UNTIMED_SONG: i64 = 600;
+
+// Random codes tried with one length of number before adding a digit
+const SESSION_CODE_ATTEMPTS: usize = 20;
+
+// Generate a random session code like TIGER-42 that isn't used by any existing session.
+// When the two-digit codes are nearly all taken the number gets longer, e.g. TIGER-512,
+// so this always finds one.
+pub fn generate_session_code(existing: &HashMap<String, Session>) -> String {
     let mut rng = rand::thread_rng();
-    format!("{:04}", rng.gen_range(0..10000))
+    let mut low: u64 = 10;
+
+    loop {
+        for _ in 0..SESSION_CODE_ATTEMPTS {
+            let word = SESSION_CODE_WORDS[rng.gen_range(0..SESSION_CODE_WORDS.len())];
+            let code = format!("{}-{}", word, rng.gen_range(low..low * 10));
+
+            if !existing.contains_key(&code) {
+                return code;
+            }
+        }
+        low *= 10;
+    }
+}
+
+// Session codes are matched case-insensitively, so store and look them up in upper case
+pub fn normalize_session_code(code: &str) -> String {
+    code.trim().to_uppercase()
 }
 
-// Public function to validate YouTube URL
-pub fn is_valid_youtube_url(url: &str) -> bool {
-    validate_youtube_url(url)
+// Public function to validate a YouTube, Vimeo or Dailymotion URL
+pub fn is_valid_video_url(url: &str) -> bool {
+    validate_video_url(url)
 }
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::metadata;
+use crate::youtube::{self, VideoDetails, VideoInfo, NOT_EMBEDDABLE};
+
+// How long the check right before a song plays may take before it's played anyway
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    // e.g. https://vimeo.com/76979871 or https://player.vimeo.com/video/76979871
+    static ref VIMEO_URL_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?(?:www\.|player\.)?vimeo\.com/(?:video/)?(\d+)(?:[/?#]\S*)?$"
+    )
+    .expect("Invalid Vimeo URL regex pattern");
+    // e.g. https://www.dailymotion.com/video/x8abcde or https://dai.ly/x8abcde
+    static ref DAILYMOTION_URL_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?(?:(?:www\.)?dailymotion\.com/(?:embed/)?video/|dai\.ly/)([a-zA-Z0-9]+)(?:[_/?#]\S*)?$"
+    )
+    .expect("Invalid Dailymotion URL regex pattern");
+}
+
+// Where a video is hosted. Songs saved before other sites were supported are YouTube videos.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoSource {
+    #[default]
+    YouTube,
+    Vimeo,
+    Dailymotion,
+}
+
+impl VideoSource {
+    // Name shown to users
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoSource::YouTube => "YouTube",
+            VideoSource::Vimeo => "Vimeo",
+            VideoSource::Dailymotion => "Dailymotion",
+        }
+    }
+
+    // Page where the video can be watched
+    pub fn watch_url(&self, video_id: &str) -> String {
+        match self {
+            VideoSource::YouTube => youtube::get_watch_url(video_id),
+            VideoSource::Vimeo => format!("https://vimeo.com/{}", video_id),
+            VideoSource::Dailymotion => format!("https://www.dailymotion.com/video/{}", video_id),
+        }
+    }
+
+    // Title shown for a video that couldn't be looked up, e.g. "YouTube Video: dQw4w9WgXcQ"
+    pub fn placeholder_title(&self, video_id: &str) -> String {
+        format!("{} Video: {}", self.name(), video_id)
+    }
+}
+
+// Which site a link is for and the video's ID there, if it's a video link we know
+pub fn parse_video_url(url: &str) -> Option<(VideoSource, String)> {
+    if youtube::validate_youtube_url(url) {
+        return youtube::extract_video_id(url).map(|id| (VideoSource::YouTube, id));
+    }
+
+    [
+        (VideoSource::Vimeo, &*VIMEO_URL_REGEX),
+        (VideoSource::Dailymotion, &*DAILYMOTION_URL_REGEX),
+    ]
+    .into_iter()
+    .find_map(|(source, regex)| {
+        regex
+            .captures(url)
+            .and_then(|cap| cap.get(1).map(|m| (source, m.as_str().to_string())))
+    })
+}
+
+// Whether a link is a video on one of the sites songs can come from
+pub fn validate_video_url(url: &str) -> bool {
+    parse_video_url(url).is_some()
+}
+
+// Look up a video on whichever site it's from
+pub async fn create_video_info(url: &str) -> Result<VideoInfo> {
+    let (source, video_id) =
+        parse_video_url(url).ok_or_else(|| anyhow!("Failed to extract video ID from URL"))?;
+
+    let lookup = match source {
+        VideoSource::YouTube => return youtube::create_video_info(url).await,
+        VideoSource::Vimeo => fetch_vimeo_details(&video_id).await,
+        VideoSource::Dailymotion => fetch_dailymotion_details(&video_id).await,
+    };
+
+    let details = match lookup {
+        Ok(Some(details)) => details,
+        Ok(None) => VideoDetails {
+            title: source.placeholder_title(&video_id),
+            unplayable: Some(
+                "That video is private or has been removed, so it can't be played.".to_string(),
+            ),
+            ..VideoDetails::default()
+        },
+        Err(e) => {
+            // Log the error but don't fail the whole operation
+            log::warn!("Failed to fetch {} video details: {}", source.name(), e);
+            VideoDetails {
+                title: source.placeholder_title(&video_id),
+                ..VideoDetails::default()
+            }
+        }
+    };
+
+    Ok(details.into_video_info(&video_id, url, source))
+}
+
+// Look videos up again, e.g. ones whose lookup failed when they were added.
+// YouTube videos are looked up together, the others one at a time.
+pub async fn refetch_video_infos(videos: &[(VideoSource, String)]) -> Vec<VideoInfo> {
+    let youtube_ids: Vec<String> = videos
+        .iter()
+        .filter(|(source, _)| *source == VideoSource::YouTube)
+        .map(|(_, video_id)| video_id.clone())
+        .collect();
+    let mut refetched = youtube::fetch_video_infos(&youtube_ids).await;
+
+    for (source, video_id) in videos {
+        if *source != VideoSource::YouTube {
+            match create_video_info(&source.watch_url(video_id)).await {
+                Ok(video_info) => refetched.push(video_info),
+                Err(e) => log::warn!("Failed to look up {} again: {}", video_id, e),
+            }
+        }
+    }
+    refetched
+}
+
+// Look a video up again right before it plays, since videos get taken down while they
+// wait in the queue. Returns why it can't be played, or None if it can or the lookup
+// didn't get an answer in time.
+pub async fn playback_problem(video_info: &VideoInfo) -> Option<String> {
+    let lookup = match video_info.source {
+        VideoSource::YouTube => {
+            tokio::time::timeout(PREFLIGHT_TIMEOUT, metadata::video_details(&video_info.id)).await
+        }
+        VideoSource::Vimeo => {
+            tokio::time::timeout(PREFLIGHT_TIMEOUT, fetch_vimeo_details(&video_info.id)).await
+        }
+        VideoSource::Dailymotion => {
+            tokio::time::timeout(PREFLIGHT_TIMEOUT, fetch_dailymotion_details(&video_info.id)).await
+        }
+    };
+
+    match lookup {
+        Ok(Ok(Some(details))) => details.unplayable.map(|reason| {
+            log::info!("{} can't be played anymore: {}", video_info.id, reason);
+            "it can't be played on the cast device anymore".to_string()
+        }),
+        Ok(Ok(None)) => Some("it's been made private or taken down since it was added".to_string()),
+        Ok(Err(e)) => {
+            log::warn!("Failed to check {} before playing it: {}", video_info.id, e);
+            None
+        }
+        Err(_) => {
+            log::warn!("Checking {} before playing it took too long", video_info.id);
+            None
+        }
+    }
+}
+
+// Vimeo oEmbed response
+#[derive(Debug, Deserialize)]
+struct VimeoOEmbed {
+    title: String,
+    author_name: Option<String>,
+    duration: Option<u64>,
+    thumbnail_url: Option<String>,
+}
+
+// Look a Vimeo video up with its oEmbed endpoint, which needs no key.
+// Returns None if Vimeo doesn't know it or it's private.
+async fn fetch_vimeo_details(video_id: &str) -> Result<Option<VideoDetails>> {
+    let response = reqwest::Client::new()
+        .get("https://vimeo.com/api/oembed.json")
+        .query(&[("url", VideoSource::Vimeo.watch_url(video_id))])
+        .send()
+        .await
+        .map_err(|e| anyhow!("Vimeo request failed: {}", e))?;
+
+    match response.status() {
+        reqwest::StatusCode::NOT_FOUND => return Ok(None),
+        // Vimeo answers 403 for videos that can't be embedded on other sites
+        reqwest::StatusCode::FORBIDDEN => {
+            return Ok(Some(VideoDetails {
+                title: VideoSource::Vimeo.placeholder_title(video_id),
+                unplayable: Some(NOT_EMBEDDABLE.to_string()),
+                ..VideoDetails::default()
+            }))
+        }
+        status if !status.is_success() => {
+            return Err(anyhow!("Vimeo returned error: {}", status));
+        }
+        _ => {}
+    }
+
+    let video: VimeoOEmbed = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse Vimeo response: {}", e))?;
+
+    Ok(Some(VideoDetails {
+        title: video.title,
+        channel_title: video.author_name,
+        duration: video.duration.filter(|duration| *duration > 0),
+        thumbnail: video.thumbnail_url,
+        ..VideoDetails::default()
+    }))
+}
+
+// Dailymotion API response, for the fields asked for
+#[derive(Debug, Deserialize)]
+struct DailymotionVideo {
+    title: String,
+    duration: Option<u64>,
+    thumbnail_720_url: Option<String>,
+    #[serde(rename = "owner.screenname")]
+    owner_name: Option<String>,
+    channel: Option<String>, // Dailymotion's category, e.g. "music"
+    language: Option<String>,
+    #[serde(default = "default_allow_embed")]
+    allow_embed: bool,
+}
+
+fn default_allow_embed() -> bool {
+    true
+}
+
+// Look a Dailymotion video up with its public API, which needs no key.
+// Returns None if Dailymotion doesn't know it or it's private.
+async fn fetch_dailymotion_details(video_id: &str) -> Result<Option<VideoDetails>> {
+    let response = reqwest::Client::new()
+        .get(format!("https://api.dailymotion.com/video/{}", video_id))
+        .query(&[(
+            "fields",
+            "title,duration,thumbnail_720_url,owner.screenname,channel,language,allow_embed",
+        )])
+        .send()
+        .await
+        .map_err(|e| anyhow!("Dailymotion request failed: {}", e))?;
+
+    match response.status() {
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::FORBIDDEN => return Ok(None),
+        status if !status.is_success() => {
+            return Err(anyhow!("Dailymotion returned error: {}", status));
+        }
+        _ => {}
+    }
+
+    let video: DailymotionVideo = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse Dailymotion response: {}", e))?;
+
+    Ok(Some(VideoDetails {
+        title: video.title,
+        channel_title: video.owner_name,
+        category: video.channel,
+        language: video.language,
+        duration: video.duration.filter(|duration| *duration > 0),
+        thumbnail: video.thumbnail_720_url,
+        unplayable: (!video.allow_embed).then(|| NOT_EMBEDDABLE.to_string()),
+        ..VideoDetails::default()
+    }))
+}
@@ -0,0 +1,811 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{error, info};
+use redis::Commands;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use teloxide::types::UserId;
+use tokio::sync::Notify;
+
+use crate::archive::SessionArchive;
+use crate::migrations::{self, NewerSchema};
+use crate::session::{SessionPreset, SessionState};
+use crate::updates::LastUpdate;
+use crate::SharedState;
+
+// Files the JSON store keeps everything in
+const SESSION_FILE: &str = "sessions.json";
+const ARCHIVE_FILE: &str = "archive.json";
+const LAST_UPDATE_FILE: &str = "last_update.json";
+
+// Database the SQLite store uses when KARAOKE_SQLITE_PATH isn't set
+const DEFAULT_SQLITE_FILE: &str = "karaoke.db";
+
+// Redis server used when KARAOKE_REDIS_URL isn't set
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
+
+// Start of every key the Redis store uses
+const REDIS_PREFIX: &str = "karaoke:";
+
+// How long an untouched session is kept in Redis when KARAOKE_REDIS_TTL_DAYS isn't set
+const DEFAULT_REDIS_TTL_DAYS: i64 = 7;
+
+// Directory everything is kept in when KARAOKE_DATA_DIR isn't set
+const DEFAULT_DATA_DIR: &str = ".";
+
+// How long changes are collected before they're saved together
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+// How long to wait before trying again after a save failed
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Where the bot keeps sessions and the archive between restarts
+pub trait SessionStore: Send + Sync {
+    // Name shown in the logs
+    fn name(&self) -> &'static str;
+
+    // The saved sessions, or None if nothing has been saved yet
+    fn load(&self) -> Result<Option<SessionState>>;
+
+    // Replace everything saved with the current sessions
+    fn save(&self, state: &SessionState) -> Result<()>;
+
+    // Save the changes to one session, leaving the others alone.
+    // Stores that keep every session together save them all.
+    fn save_session(&self, state: &SessionState, _session_code: &str) -> Result<()> {
+        self.save(state)
+    }
+
+    // Whether save_session only needs the one session, not all of them
+    fn saves_sessions_separately(&self) -> bool {
+        false
+    }
+
+    // The ended sessions, or None if none have been saved yet
+    fn load_archive(&self) -> Result<Option<SessionArchive>>;
+
+    fn save_archive(&self, archive: &SessionArchive) -> Result<()>;
+
+    // The last Telegram update the bot handled, or None if it hasn't handled any
+    fn load_last_update(&self) -> Result<Option<LastUpdate>>;
+
+    fn save_last_update(&self, last_update: &LastUpdate) -> Result<()>;
+}
+
+// Everything in pretty-printed JSON files: sessions.json, archive.json and last_update.json
+pub struct JsonFileStore {
+    session_file: PathBuf,
+    archive_file: PathBuf,
+    last_update_file: PathBuf,
+}
+
+impl JsonFileStore {
+    // Read a file written by write, upgrading it from older formats with migrate.
+    // Falls back to the previous copy in .bak if the file is missing or can't be
+    // parsed, e.g. after a crash halfway through a write.
+    fn read<T: DeserializeOwned>(
+        path: &Path,
+        migrate: fn(Value) -> Result<Value>,
+    ) -> Result<Option<T>> {
+        let backup = with_suffix(path, "bak");
+
+        let error = match fs::read_to_string(path) {
+            Ok(json) => match parse(&json, migrate) {
+                Ok(value) => return Ok(Some(value)),
+                // Not broken, just from a newer bot, so leave it be
+                Err(e) if e.downcast_ref::<NewerSchema>().is_some() => return Err(e),
+                Err(e) => anyhow!("{} is corrupt: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !backup.exists() => return Ok(None),
+            Err(e) => anyhow!("Failed to read {}: {}", path.display(), e),
+        };
+
+        error!(
+            "{}, restoring the previous copy from {}",
+            error,
+            backup.display()
+        );
+
+        // Keep the broken file around for a closer look rather than overwriting it later
+        if path.exists() {
+            let corrupt = with_suffix(path, "corrupt");
+            match fs::rename(path, &corrupt) {
+                Ok(()) => error!("Moved the broken file to {}", corrupt.display()),
+                Err(e) => error!("Failed to move {} aside: {}", path.display(), e),
+            }
+        }
+
+        let json = fs::read_to_string(&backup)
+            .map_err(|e| anyhow!("{} and its backup couldn't be read either: {}", error, e))?;
+        let value = parse(&json, migrate)
+            .map_err(|e| anyhow!("{} and its backup is corrupt too: {}", error, e))?;
+        Ok(Some(value))
+    }
+
+    // Write a file without ever leaving a half-written one behind: the new contents go
+    // to a temporary file that replaces the old one in one step, and the old one is
+    // kept as .bak
+    fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+        let json = serde_json::to_string_pretty(value)?;
+
+        let temporary = with_suffix(path, "tmp");
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        if path.exists() {
+            fs::copy(path, with_suffix(path, "bak"))?;
+        }
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+// Parse saved JSON, upgrading it to the current format first
+pub fn parse<T: DeserializeOwned>(json: &str, migrate: fn(Value) -> Result<Value>) -> Result<T> {
+    let value = migrate(serde_json::from_str(json)?)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+// The path with an extra extension, e.g. sessions.json.bak
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+impl SessionStore for JsonFileStore {
+    fn name(&self) -> &'static str {
+        "JSON files"
+    }
+
+    fn load(&self) -> Result<Option<SessionState>> {
+        Self::read(&self.session_file, migrations::migrate_state)
+    }
+
+    fn save(&self, state: &SessionState) -> Result<()> {
+        Self::write(&self.session_file, state)
+    }
+
+    fn load_archive(&self) -> Result<Option<SessionArchive>> {
+        Self::read(&self.archive_file, migrations::migrate_archive)
+    }
+
+    fn save_archive(&self, archive: &SessionArchive) -> Result<()> {
+        Self::write(&self.archive_file, archive)
+    }
+
+    fn load_last_update(&self) -> Result<Option<LastUpdate>> {
+        Self::read(&self.last_update_file, Ok)
+    }
+
+    fn save_last_update(&self, last_update: &LastUpdate) -> Result<()> {
+        Self::write(&self.last_update_file, last_update)
+    }
+}
+
+// A SQLite database with a row per session, so changing one session doesn't
+// rewrite the rest. Who's in which session, presets and the archive are kept as
+// JSON in a table of their own.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (code TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS documents (name TEXT PRIMARY KEY, data TEXT NOT NULL);",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| anyhow!("SQLite connection lock poisoned"))
+    }
+
+    fn read_document(connection: &Connection, name: &str) -> Result<Option<String>> {
+        Ok(connection
+            .query_row(
+                "SELECT data FROM documents WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn write_document(connection: &Connection, name: &str, json: &str) -> Result<()> {
+        connection.execute(
+            "INSERT INTO documents (name, data) VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET data = excluded.data",
+            params![name, json],
+        )?;
+        Ok(())
+    }
+
+    // Save who's in which session and the presets, which change along with sessions
+    fn write_members(connection: &Connection, state: &SessionState) -> Result<()> {
+        Self::write_document(
+            connection,
+            "user_sessions",
+            &serde_json::to_string(&state.user_sessions)?,
+        )?;
+        Self::write_document(
+            connection,
+            "presets",
+            &serde_json::to_string(&state.presets)?,
+        )
+    }
+}
+
+impl SessionStore for SqliteStore {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    fn load(&self) -> Result<Option<SessionState>> {
+        let connection = self.connection()?;
+
+        let Some(user_sessions) = Self::read_document(&connection, "user_sessions")? else {
+            return Ok(None);
+        };
+        let user_sessions: HashMap<UserId, String> = serde_json::from_str(&user_sessions)?;
+        let presets: HashMap<UserId, HashMap<String, SessionPreset>> =
+            match Self::read_document(&connection, "presets")? {
+                Some(json) => serde_json::from_str(&json)?,
+                None => HashMap::new(),
+            };
+
+        let mut statement = connection.prepare("SELECT code, data FROM sessions")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut sessions = HashMap::new();
+        for row in rows {
+            let (code, data) = row?;
+            sessions.insert(code, parse(&data, migrations::migrate_session)?);
+        }
+
+        Ok(Some(SessionState {
+            sessions,
+            user_sessions,
+            presets,
+            ..SessionState::default()
+        }))
+    }
+
+    fn save(&self, state: &SessionState) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        transaction.execute("DELETE FROM sessions", [])?;
+        for (code, session) in &state.sessions {
+            transaction.execute(
+                "INSERT INTO sessions (code, data) VALUES (?1, ?2)",
+                params![code, serde_json::to_string(session)?],
+            )?;
+        }
+        Self::write_members(&transaction, state)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn save_session(&self, state: &SessionState, session_code: &str) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        match state.sessions.get(session_code) {
+            Some(session) => transaction.execute(
+                "INSERT INTO sessions (code, data) VALUES (?1, ?2)
+                 ON CONFLICT(code) DO UPDATE SET data = excluded.data",
+                params![session_code, serde_json::to_string(session)?],
+            )?,
+            None => transaction.execute(
+                "DELETE FROM sessions WHERE code = ?1",
+                params![session_code],
+            )?,
+        };
+        Self::write_members(&transaction, state)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn saves_sessions_separately(&self) -> bool {
+        true
+    }
+
+    fn load_archive(&self) -> Result<Option<SessionArchive>> {
+        let connection = self.connection()?;
+        Self::read_document(&connection, "archive")?
+            .map(|json| parse(&json, migrations::migrate_archive))
+            .transpose()
+    }
+
+    fn save_archive(&self, archive: &SessionArchive) -> Result<()> {
+        let connection = self.connection()?;
+        Self::write_document(&connection, "archive", &serde_json::to_string(archive)?)
+    }
+
+    fn load_last_update(&self) -> Result<Option<LastUpdate>> {
+        let connection = self.connection()?;
+        Self::read_document(&connection, "last_update")?
+            .map(|json| Ok(serde_json::from_str(&json)?))
+            .transpose()
+    }
+
+    fn save_last_update(&self, last_update: &LastUpdate) -> Result<()> {
+        let connection = self.connection()?;
+        Self::write_document(
+            &connection,
+            "last_update",
+            &serde_json::to_string(last_update)?,
+        )
+    }
+}
+
+// Redis, for setups that already run it. Each session is a hash under
+// karaoke:session:<code> with a field per setting, so other programs can read a
+// session's queue without parsing the rest. Sessions nobody touched for
+// KARAOKE_REDIS_TTL_DAYS expire on their own.
+pub struct RedisStore {
+    client: redis::Client,
+    ttl: i64, // Seconds a session's key lives after its last change
+}
+
+impl RedisStore {
+    pub fn open(url: &str, ttl_days: i64) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        // Fail at startup rather than on the first save if Redis can't be reached
+        client.get_connection()?;
+
+        Ok(Self {
+            client,
+            ttl: ttl_days * 24 * 3600,
+        })
+    }
+
+    fn session_key(session_code: &str) -> String {
+        format!("{}session:{}", REDIS_PREFIX, session_code)
+    }
+
+    fn key(name: &str) -> String {
+        format!("{}{}", REDIS_PREFIX, name)
+    }
+
+    // Queue a session's hash to be replaced, or removed if the session is gone
+    fn write_session(
+        pipe: &mut redis::Pipeline,
+        state: &SessionState,
+        session_code: &str,
+        ttl: i64,
+    ) -> Result<()> {
+        let key = Self::session_key(session_code);
+        pipe.del(&key).ignore();
+
+        let Some(session) = state.sessions.get(session_code) else {
+            pipe.srem(Self::key("sessions"), session_code).ignore();
+            return Ok(());
+        };
+
+        let Value::Object(fields) = serde_json::to_value(session)? else {
+            return Err(anyhow!(
+                "Session {} didn't serialize to an object",
+                session_code
+            ));
+        };
+        let fields: Vec<(String, String)> = fields
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect();
+
+        pipe.hset_multiple(&key, &fields)
+            .ignore()
+            .expire(&key, ttl)
+            .ignore()
+            .sadd(Self::key("sessions"), session_code)
+            .ignore();
+        Ok(())
+    }
+
+    // Queue who's in which session and the presets to be replaced
+    fn write_members(pipe: &mut redis::Pipeline, state: &SessionState) -> Result<()> {
+        let user_sessions_key = Self::key("user_sessions");
+        pipe.del(&user_sessions_key).ignore();
+
+        let members: Vec<(String, &String)> = state
+            .user_sessions
+            .iter()
+            .map(|(user_id, session_code)| (user_id.0.to_string(), session_code))
+            .collect();
+        if !members.is_empty() {
+            pipe.hset_multiple(&user_sessions_key, &members).ignore();
+        }
+
+        pipe.set(Self::key("presets"), serde_json::to_string(&state.presets)?)
+            .ignore();
+        Ok(())
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn name(&self) -> &'static str {
+        "Redis"
+    }
+
+    fn load(&self) -> Result<Option<SessionState>> {
+        let mut connection = self.client.get_connection()?;
+
+        let session_codes: Vec<String> = connection.smembers(Self::key("sessions"))?;
+        let presets: Option<String> = connection.get(Self::key("presets"))?;
+        if session_codes.is_empty() && presets.is_none() {
+            return Ok(None);
+        }
+
+        let mut sessions = HashMap::new();
+        for session_code in session_codes {
+            let fields: HashMap<String, String> =
+                connection.hgetall(Self::session_key(&session_code))?;
+
+            // The key expired, so the session is gone
+            if fields.is_empty() {
+                let _: () = connection.srem(Self::key("sessions"), &session_code)?;
+                continue;
+            }
+
+            let mut session = serde_json::Map::new();
+            for (name, value) in fields {
+                session.insert(name, serde_json::from_str(&value)?);
+            }
+            let session = migrations::migrate_session(Value::Object(session))?;
+            sessions.insert(session_code, serde_json::from_value(session)?);
+        }
+
+        let members: HashMap<String, String> = connection.hgetall(Self::key("user_sessions"))?;
+        let user_sessions = members
+            .into_iter()
+            .filter(|(_, session_code)| sessions.contains_key(session_code))
+            .filter_map(|(user_id, session_code)| {
+                Some((UserId(user_id.parse().ok()?), session_code))
+            })
+            .collect();
+
+        Ok(Some(SessionState {
+            sessions,
+            user_sessions,
+            presets: match presets {
+                Some(json) => serde_json::from_str(&json)?,
+                None => HashMap::new(),
+            },
+            ..SessionState::default()
+        }))
+    }
+
+    fn save(&self, state: &SessionState) -> Result<()> {
+        let mut connection = self.client.get_connection()?;
+        let saved_codes: Vec<String> = connection.smembers(Self::key("sessions"))?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        // Sessions that ended since the last save
+        for session_code in &saved_codes {
+            if !state.sessions.contains_key(session_code) {
+                Self::write_session(&mut pipe, state, session_code, self.ttl)?;
+            }
+        }
+        for session_code in state.sessions.keys() {
+            Self::write_session(&mut pipe, state, session_code, self.ttl)?;
+        }
+        Self::write_members(&mut pipe, state)?;
+
+        pipe.query::<()>(&mut connection)?;
+        Ok(())
+    }
+
+    fn save_session(&self, state: &SessionState, session_code: &str) -> Result<()> {
+        let mut connection = self.client.get_connection()?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        Self::write_session(&mut pipe, state, session_code, self.ttl)?;
+        Self::write_members(&mut pipe, state)?;
+
+        pipe.query::<()>(&mut connection)?;
+        Ok(())
+    }
+
+    fn saves_sessions_separately(&self) -> bool {
+        true
+    }
+
+    fn load_archive(&self) -> Result<Option<SessionArchive>> {
+        let mut connection = self.client.get_connection()?;
+        let json: Option<String> = connection.get(Self::key("archive"))?;
+        json.map(|json| parse(&json, migrations::migrate_archive))
+            .transpose()
+    }
+
+    fn save_archive(&self, archive: &SessionArchive) -> Result<()> {
+        let mut connection = self.client.get_connection()?;
+        let _: () = connection.set(Self::key("archive"), serde_json::to_string(archive)?)?;
+        Ok(())
+    }
+
+    fn load_last_update(&self) -> Result<Option<LastUpdate>> {
+        let mut connection = self.client.get_connection()?;
+        let json: Option<String> = connection.get(Self::key("last_update"))?;
+        json.map(|json| Ok(serde_json::from_str(&json)?))
+            .transpose()
+    }
+
+    fn save_last_update(&self, last_update: &LastUpdate) -> Result<()> {
+        let mut connection = self.client.get_connection()?;
+        let _: () = connection.set(
+            Self::key("last_update"),
+            serde_json::to_string(last_update)?,
+        )?;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    // KARAOKE_STORE=sqlite keeps everything in a SQLite database at KARAOKE_SQLITE_PATH,
+    // KARAOKE_STORE=redis in Redis at KARAOKE_REDIS_URL, otherwise it's kept in JSON files
+    static ref STORE: Box<dyn SessionStore> = {
+        let store: Box<dyn SessionStore> = match env::var("KARAOKE_STORE") {
+            Ok(kind) if kind.eq_ignore_ascii_case("sqlite") => {
+                let path = env::var("KARAOKE_SQLITE_PATH")
+                    .ok()
+                    .filter(|path| !path.trim().is_empty())
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| data_path(DEFAULT_SQLITE_FILE));
+                Box::new(SqliteStore::open(&path).unwrap_or_else(|e| {
+                    panic!("Failed to open SQLite database {}: {}", path.display(), e)
+                }))
+            }
+            Ok(kind) if kind.eq_ignore_ascii_case("redis") => {
+                let url = env::var("KARAOKE_REDIS_URL")
+                    .unwrap_or_else(|_| DEFAULT_REDIS_URL.to_string());
+                let ttl_days = env::var("KARAOKE_REDIS_TTL_DAYS")
+                    .ok()
+                    .and_then(|days| days.trim().parse::<i64>().ok())
+                    .filter(|days| *days > 0)
+                    .unwrap_or(DEFAULT_REDIS_TTL_DAYS);
+                Box::new(RedisStore::open(&url, ttl_days).unwrap_or_else(|e| {
+                    panic!("Failed to connect to Redis at {}: {}", url, e)
+                }))
+            }
+            _ => Box::new(JsonFileStore {
+                session_file: data_path(SESSION_FILE),
+                archive_file: data_path(ARCHIVE_FILE),
+                last_update_file: data_path(LAST_UPDATE_FILE),
+            }),
+        };
+        info!("Keeping sessions in {}", store.name());
+        store
+    };
+}
+
+// Directory the bot keeps its files in, from KARAOKE_DATA_DIR, e.g. /var/lib/karaoke
+// under systemd or a mounted volume in Docker
+pub fn data_dir() -> PathBuf {
+    env::var("KARAOKE_DATA_DIR")
+        .ok()
+        .filter(|dir| !dir.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR))
+}
+
+// Where a file of the bot's goes in the data directory
+pub fn data_path(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+// Make sure the data directory exists before anything is loaded or saved
+pub fn create_data_dir() -> Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(&dir)
+        .map_err(|e| anyhow!("Failed to create data directory {}: {}", dir.display(), e))?;
+    info!("Keeping data in {}", dir.display());
+    Ok(())
+}
+
+// The configured store
+pub fn store() -> &'static dyn SessionStore {
+    STORE.as_ref()
+}
+
+// What changed since the last save
+#[derive(Clone, Default)]
+struct PendingChanges {
+    everything: bool,                // Something outside a single session changed
+    sessions: HashSet<String>,       // Sessions that changed on their own
+    archive: bool,                   // An ended session was archived
+    last_update: Option<LastUpdate>, // Newest Telegram update handled
+}
+
+impl PendingChanges {
+    fn is_empty(&self) -> bool {
+        !self.everything && !self.archive && self.sessions.is_empty() && self.last_update.is_none()
+    }
+
+    // Add changes that still need saving, e.g. ones a failed save put back
+    fn merge(&mut self, other: PendingChanges) {
+        self.everything |= other.everything;
+        self.sessions.extend(other.sessions);
+        self.archive |= other.archive;
+        if let Some(other) = other.last_update {
+            if self.last_update.is_none_or(|last| other.id > last.id) {
+                self.last_update = Some(other);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref PENDING: Mutex<PendingChanges> = Mutex::new(PendingChanges::default());
+    static ref CHANGED: Notify = Notify::new();
+}
+
+fn mark_changed(change: impl FnOnce(&mut PendingChanges)) -> Result<()> {
+    let mut pending = PENDING
+        .lock()
+        .map_err(|_| anyhow!("Pending changes lock poisoned"))?;
+    change(&mut pending);
+    CHANGED.notify_one();
+    Ok(())
+}
+
+// Have everything saved shortly, along with whatever else changes meanwhile
+pub fn schedule_save() -> Result<()> {
+    mark_changed(|pending| pending.everything = true)
+}
+
+// Have one session saved shortly
+pub fn schedule_session_save(session_code: &str) -> Result<()> {
+    mark_changed(|pending| {
+        pending.sessions.insert(session_code.to_string());
+    })
+}
+
+// Have the archive saved shortly
+pub fn schedule_archive_save() -> Result<()> {
+    mark_changed(|pending| pending.archive = true)
+}
+
+// Have the newest handled Telegram update saved shortly, along with what it changed
+pub fn schedule_last_update_save(update_id: i32) -> Result<()> {
+    mark_changed(|pending| {
+        if pending.last_update.is_none_or(|last| update_id > last.id) {
+            pending.last_update = Some(LastUpdate {
+                id: update_id,
+                handled_at: chrono::Utc::now().timestamp(),
+            });
+        }
+    })
+}
+
+// Background task that saves changes at most once every SAVE_DELAY. Commands only
+// note what changed, so nobody waits on the disk while holding the state lock.
+pub async fn run_saver(state: SharedState) {
+    loop {
+        CHANGED.notified().await;
+        tokio::time::sleep(SAVE_DELAY).await;
+        if save_pending(&state).await.is_err() {
+            // Whatever failed is pending again, give the store a moment before retrying
+            tokio::time::sleep(SAVE_RETRY_DELAY).await;
+        }
+    }
+}
+
+// Save whatever changed since the last save, e.g. before shutting down. What
+// couldn't be saved is put back to be tried again.
+pub async fn save_pending(state: &SharedState) -> Result<()> {
+    let pending = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => return Err(anyhow!("Pending changes lock poisoned")),
+    };
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    // Copy what's saved so the lock isn't held while it's written out
+    let snapshot = snapshot(&*state.lock().await, &pending);
+
+    let retry = pending.clone();
+    let failed = tokio::task::spawn_blocking(move || write_pending(&snapshot, &pending))
+        .await
+        .unwrap_or_else(|e| {
+            error!("Saving sessions stopped unexpectedly: {}", e);
+            retry
+        });
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    mark_changed(|pending| pending.merge(failed))?;
+    Err(anyhow!("Some changes couldn't be saved"))
+}
+
+// The parts of the state a save needs. Stores that save sessions one at a time
+// only get the sessions that changed, and the archive only comes along if it changed.
+fn snapshot(state: &SessionState, pending: &PendingChanges) -> SessionState {
+    let sessions = if pending.everything || !store().saves_sessions_separately() {
+        state.sessions.clone()
+    } else {
+        pending
+            .sessions
+            .iter()
+            .filter_map(|code| Some((code.clone(), state.sessions.get(code)?.clone())))
+            .collect()
+    };
+
+    SessionState {
+        sessions,
+        user_sessions: state.user_sessions.clone(),
+        presets: state.presets.clone(),
+        archive: if pending.archive {
+            state.archive.clone()
+        } else {
+            SessionArchive::default()
+        },
+    }
+}
+
+// Write out the pending changes, going on with the rest when one fails.
+// Returns what couldn't be saved.
+fn write_pending(snapshot: &SessionState, pending: &PendingChanges) -> PendingChanges {
+    let mut failed = PendingChanges::default();
+
+    if pending.everything {
+        if let Err(e) = store().save(snapshot) {
+            error!("Failed to save sessions: {}", e);
+            failed.everything = true;
+        }
+    } else {
+        for session_code in &pending.sessions {
+            if let Err(e) = store().save_session(snapshot, session_code) {
+                error!("Failed to save session {}: {}", session_code, e);
+                failed.sessions.insert(session_code.clone());
+            }
+        }
+    }
+    if pending.archive {
+        if let Err(e) = store().save_archive(&snapshot.archive) {
+            error!("Failed to save the session archive: {}", e);
+            failed.archive = true;
+        }
+    }
+
+    // Only once what the updates changed is saved, or after a restart they'd be
+    // skipped with their changes lost
+    if let Some(last_update) = pending.last_update {
+        if !failed.is_empty() {
+            failed.last_update = Some(last_update);
+        } else if let Err(e) = store().save_last_update(&last_update) {
+            error!("Failed to save the last handled update: {}", e);
+            failed.last_update = Some(last_update);
+        }
+    }
+
+    failed
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+lazy_static! {
+    // e.g. https://open.spotify.com/track/4u7EnebtmKWzUH433cf5Qv or .../intl-de/track/...
+    static ref SPOTIFY_TRACK_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?open\.spotify\.com/(?:intl-[\w\-]+/)?track/[a-zA-Z0-9]+(?:[?#]\S*)?$"
+    )
+    .expect("Invalid Spotify URL regex pattern");
+    // e.g. https://music.apple.com/us/album/bohemian-rhapsody/1440650428?i=1440650711
+    // or https://music.apple.com/us/song/bohemian-rhapsody/1440650711
+    static ref APPLE_MUSIC_TRACK_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?music\.apple\.com/[a-z]{2}/(?:album/[^/\s]+/\d+\?(?:\S*&)?i=\d+|song/[^/\s]+/\d+)\S*$"
+    )
+    .expect("Invalid Apple Music URL regex pattern");
+}
+
+// A music streaming service people share songs from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamingService {
+    Spotify,
+    AppleMusic,
+}
+
+impl StreamingService {
+    // Name shown to users
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamingService::Spotify => "Spotify",
+            StreamingService::AppleMusic => "Apple Music",
+        }
+    }
+
+    // The service's oEmbed endpoint, which needs no key
+    fn oembed_url(&self) -> &'static str {
+        match self {
+            StreamingService::Spotify => "https://open.spotify.com/oembed",
+            StreamingService::AppleMusic => "https://music.apple.com/api/oembed",
+        }
+    }
+}
+
+// Which service a link is a song on, if it's a track link we know
+pub fn parse_track_link(url: &str) -> Option<StreamingService> {
+    if SPOTIFY_TRACK_REGEX.is_match(url) {
+        Some(StreamingService::Spotify)
+    } else if APPLE_MUSIC_TRACK_REGEX.is_match(url) {
+        Some(StreamingService::AppleMusic)
+    } else {
+        None
+    }
+}
+
+// oEmbed response, for the fields both services fill in
+#[derive(Debug, Deserialize)]
+struct TrackOEmbed {
+    title: String,
+    author_name: Option<String>,
+}
+
+// The song a track link is for, as "Artist - Title" when the service says who it's by
+pub async fn resolve_track(service: StreamingService, url: &str) -> Result<String> {
+    let response = reqwest::Client::new()
+        .get(service.oembed_url())
+        .query(&[("url", url)])
+        .send()
+        .await
+        .map_err(|e| anyhow!("{} request failed: {}", service.name(), e))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "{} returned error: {}",
+            service.name(),
+            response.status()
+        ));
+    }
+
+    let track: TrackOEmbed = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse {} response: {}", service.name(), e))?;
+
+    Ok(match track.author_name {
+        Some(artist) if !artist.is_empty() && !track.title.contains(&artist) => {
+            format!("{} - {}", artist, track.title)
+        }
+        _ => track.title,
+    })
+}
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use reqwest::Url;
+use std::env;
+
+// Google's translate speech endpoint, which needs no API key
+const GOOGLE_TTS_URL: &str = "https://translate.google.com/translate_tts";
+
+// Longest text the Google endpoint will speak in one request
+const GOOGLE_TTS_MAX_CHARS: usize = 200;
+
+// Language used when KARAOKE_TTS_LANG isn't set
+const DEFAULT_LANGUAGE: &str = "en";
+
+// Placeholder in KARAOKE_TTS_URL replaced with the text to speak
+const TEXT_PLACEHOLDER: &str = "{text}";
+
+// Turns announcement text into audio a cast device can play
+#[async_trait]
+pub trait TtsProvider: Send + Sync {
+    // Name shown in the logs
+    fn name(&self) -> &'static str;
+
+    // URL of an audio file speaking the text
+    async fn speech_url(&self, text: &str) -> Result<String>;
+}
+
+// Google Translate's speech endpoint, in the language from KARAOKE_TTS_LANG
+pub struct GoogleTranslateTts {
+    language: String,
+}
+
+#[async_trait]
+impl TtsProvider for GoogleTranslateTts {
+    fn name(&self) -> &'static str {
+        "Google Translate"
+    }
+
+    async fn speech_url(&self, text: &str) -> Result<String> {
+        // Cut long titles at a character boundary rather than failing
+        let text: String = text.chars().take(GOOGLE_TTS_MAX_CHARS).collect();
+
+        let url = Url::parse_with_params(
+            GOOGLE_TTS_URL,
+            &[
+                ("ie", "UTF-8"),
+                ("client", "tw-ob"),
+                ("tl", self.language.as_str()),
+                ("q", text.as_str()),
+            ],
+        )?;
+        Ok(url.to_string())
+    }
+}
+
+// A self-hosted speech service, e.g. KARAOKE_TTS_URL=http://tts.lan:5002/api/tts?text={text}
+pub struct UrlTemplateTts {
+    template: String,
+}
+
+#[async_trait]
+impl TtsProvider for UrlTemplateTts {
+    fn name(&self) -> &'static str {
+        "KARAOKE_TTS_URL"
+    }
+
+    async fn speech_url(&self, text: &str) -> Result<String> {
+        // Encode the text the same way a query parameter would be
+        let encoded = Url::parse_with_params("http://localhost/", &[("q", text)])?
+            .query()
+            .and_then(|query| query.strip_prefix("q="))
+            .map(|encoded| encoded.to_string())
+            .ok_or_else(|| anyhow!("Couldn't encode announcement text"))?;
+
+        Ok(self.template.replace(TEXT_PLACEHOLDER, &encoded))
+    }
+}
+
+lazy_static! {
+    // KARAOKE_TTS_URL if it's set, otherwise Google Translate
+    static ref TTS_PROVIDER: Box<dyn TtsProvider> = match env::var("KARAOKE_TTS_URL") {
+        Ok(template) if template.contains(TEXT_PLACEHOLDER) =>
+            Box::new(UrlTemplateTts { template }),
+        _ => Box::new(GoogleTranslateTts {
+            language: env::var("KARAOKE_TTS_LANG").unwrap_or_else(|_| DEFAULT_LANGUAGE.to_string()),
+        }),
+    };
+}
+
+// Get a URL of the text spoken by the configured provider
+pub async fn speech_url(text: &str) -> Result<String> {
+    TTS_PROVIDER.speech_url(text).await.map_err(|e| {
+        anyhow!(
+            "{} couldn't speak the announcement: {}",
+            TTS_PROVIDER.name(),
+            e
+        )
+    })
+}
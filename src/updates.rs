@@ -0,0 +1,120 @@
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::Mutex;
+use teloxide::prelude::*;
+
+use crate::store;
+
+// How long Telegram keeps updates the bot hasn't fetched, in seconds. An update
+// handled longer ago than that can't be delivered again, so it's ignored. That also
+// covers Telegram numbering updates from a random ID after a week without any.
+const UPDATE_RETENTION: i64 = 24 * 3600;
+
+// The newest Telegram update the bot handled
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LastUpdate {
+    pub id: i32,
+    pub handled_at: i64, // Unix timestamp
+}
+
+// Updates being handled, to know which ones are done
+#[derive(Default)]
+struct Progress {
+    handling: BTreeSet<i32>,      // Started but not finished
+    newest_finished: Option<i32>, // Newest update whose handler finished
+}
+
+lazy_static! {
+    // Newest update handled before the bot last stopped
+    static ref HANDLED_BEFORE_START: Mutex<Option<i32>> = Mutex::new(None);
+
+    static ref PROGRESS: Mutex<Progress> = Mutex::new(Progress::default());
+}
+
+// Find out which updates were handled before the last restart. Telegram keeps
+// messages sent while the bot is offline for a day and delivers them on startup,
+// but updates the bot fetched just before a crash come again too, and handling
+// those twice would add songs twice.
+pub async fn catch_up(bot: &Bot) {
+    let last_update = tokio::task::spawn_blocking(|| store::store().load_last_update()).await;
+    match last_update {
+        Ok(Ok(Some(last_update)))
+            if chrono::Utc::now().timestamp() - last_update.handled_at < UPDATE_RETENTION =>
+        {
+            if let Ok(mut handled) = HANDLED_BEFORE_START.lock() {
+                *handled = Some(last_update.id);
+            }
+        }
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!("Failed to load the last handled update: {}", e),
+        Err(e) => error!("Failed to load the last handled update: {}", e),
+    }
+
+    match bot.get_webhook_info().await {
+        Ok(info) if info.pending_update_count > 0 => info!(
+            "Catching up on {} updates sent while the bot was offline",
+            info.pending_update_count
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Failed to check for updates sent while offline: {}", e),
+    }
+}
+
+// Whether an update still needs handling, noting that its handling started if so
+pub fn first_delivery(update: &Update) -> bool {
+    let handled_before = HANDLED_BEFORE_START
+        .lock()
+        .map(|handled| *handled)
+        .unwrap_or_default();
+    if handled_before.is_some_and(|handled| update.id <= handled) {
+        info!(
+            "Skipping update {}, it was handled before the restart",
+            update.id
+        );
+        return false;
+    }
+
+    if let Ok(mut progress) = PROGRESS.lock() {
+        progress.handling.insert(update.id);
+    }
+    true
+}
+
+// Run the handler for an update, then note the update as handled
+pub async fn handle(
+    update: Update,
+    handler: impl Future<Output = ResponseResult<()>>,
+) -> ResponseResult<()> {
+    let result = handler.await;
+    finished(update.id);
+    result
+}
+
+// Note an update as handled, or as needing nothing done. Updates are handled at the
+// same time across chats, so they can finish out of order; the one saved is the
+// newest with every update before it finished, so none are skipped after a crash.
+pub fn finished(update_id: i32) {
+    let handled_up_to = {
+        let Ok(mut progress) = PROGRESS.lock() else {
+            return;
+        };
+        progress.handling.remove(&update_id);
+        progress.newest_finished = progress.newest_finished.max(Some(update_id));
+
+        match progress.handling.first() {
+            Some(oldest) => progress
+                .newest_finished
+                .map(|newest| newest.min(oldest - 1)),
+            None => progress.newest_finished,
+        }
+    };
+
+    if let Some(update_id) = handled_up_to {
+        if let Err(e) = store::schedule_last_update_save(update_id) {
+            error!("Failed to note update {} as handled: {}", update_id, e);
+        }
+    }
+}
@@ -1,21 +1,87 @@
 use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
-use log;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 
+use crate::metadata;
+use crate::source::VideoSource;
+use crate::youtube_api;
+
 lazy_static! {
+    // Video IDs are always 11 characters, which keeps playlist and channel pages from
+    // passing for videos. Covers watch, youtu.be, embed, Shorts and live links, on
+    // www., m. and music.youtube.com, with any share parameters like ?si= after the ID.
     static ref YOUTUBE_URL_REGEX: Regex = Regex::new(
-        r"^((?:https?:)?//)?((?:www|m)\.)?((?:youtube(-nocookie)?\.com|youtu.be))(/(?:[\w\-]+\?v=|embed/|v/)?)([\w\-]+)(\S+)?$"
+        r"^((?:https?:)?//)?((?:www|m|music)\.)?((?:youtube(-nocookie)?\.com|youtu\.be))(/(?:[\w\-]+\?v=|embed/|v/|shorts/|live/)?)([\w\-]{11})([^\w\-]\S*)?$"
     ).expect("Invalid YouTube URL regex pattern");
+    // A channel ID on its own or in a link like https://www.youtube.com/channel/UC...
+    static ref CHANNEL_ID_REGEX: Regex =
+        Regex::new(r"(?:^|/channel/)(UC[\w\-]{22})(?:$|[/?])").expect("Invalid YouTube channel regex pattern");
+    static ref PLAYLIST_ID_REGEX: Regex =
+        Regex::new(r"[?&]list=([\w\-]+)").expect("Invalid YouTube playlist regex pattern");
+    // A start time like ?t=90, &t=1m30s or &start=90
+    static ref START_TIME_REGEX: Regex = Regex::new(
+        r"[?&#](?:t|start)=(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)s?)?(?:[&#]|$)"
+    )
+    .expect("Invalid YouTube start time regex pattern");
 }
 
+// Most items the playlistItems API returns per page
+const PLAYLIST_PAGE_SIZE: usize = 50;
+
+// Words in a title that mark a video as a karaoke or instrumental version
+const KARAOKE_TITLE_WORDS: [&str; 7] = [
+    "karaoke",
+    "instrumental",
+    "backing track",
+    "sing along",
+    "sing-along",
+    "off vocal",
+    "minus one",
+];
+
+// Most video IDs the videos API takes in one request
+pub const VIDEOS_PER_REQUEST: usize = 50;
+
+// Why a video whose uploader turned off embedding is turned away
+pub const NOT_EMBEDDABLE: &str = "The uploader doesn't allow that video to be played outside YouTube, so it can't be cast. Try another version of the song.";
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VideoInfo {
     pub id: String,
     pub title: Option<String>,
     pub url: String,
+    #[serde(default)]
+    pub duration: Option<u64>, // Length in seconds, if known
+    #[serde(default)]
+    pub thumbnail: Option<String>, // URL of the video's preview image
+    #[serde(default)]
+    pub source: VideoSource, // Site the video is on
+    #[serde(default)]
+    pub channel_id: Option<String>, // YouTube channel that uploaded the video, if known
+    #[serde(default)]
+    pub age_restricted: bool, // Only plays for signed-in adults, so usually not on cast devices
+    #[serde(default)]
+    pub channel_title: Option<String>, // Name of the uploader, e.g. "Sing King"
+    #[serde(default)]
+    pub category: Option<String>, // YouTube category, e.g. "Music"
+    #[serde(default)]
+    pub language: Option<String>, // Language sung in, as a code like "en" or "es-419"
+    #[serde(default)]
+    pub start_time: Option<u64>, // Seconds in to start playing at, from a link like ?t=90
+    #[serde(skip)]
+    pub unplayable: Option<String>, // Why the video won't play on a cast device, found when it was looked up
+}
+
+impl VideoInfo {
+    // Whether the title is still the placeholder from a lookup that failed
+    pub fn needs_title(&self) -> bool {
+        self.title
+            .as_deref()
+            .is_none_or(|title| title == self.source.placeholder_title(&self.id))
+    }
 }
 
 // YouTube API response structures
@@ -26,12 +92,150 @@ struct YouTubeResponse {
 
 #[derive(Debug, Deserialize)]
 struct YouTubeItem {
+    id: String,
     snippet: YouTubeSnippet,
+    #[serde(rename = "contentDetails")]
+    content_details: Option<YouTubeContentDetails>,
+    status: Option<YouTubeStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeContentDetails {
+    duration: String, // ISO 8601, e.g. "PT4M13S"
+    #[serde(rename = "regionRestriction")]
+    region_restriction: Option<YouTubeRegionRestriction>,
+    #[serde(rename = "contentRating", default)]
+    content_rating: YouTubeContentRating,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct YouTubeContentRating {
+    #[serde(rename = "ytRating")]
+    yt_rating: Option<String>, // "ytAgeRestricted" for age-restricted videos
+}
+
+// Countries a video is limited to or kept out of, as ISO 3166-1 alpha-2 codes
+#[derive(Debug, Deserialize)]
+struct YouTubeRegionRestriction {
+    allowed: Option<Vec<String>>,
+    blocked: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeStatus {
+    #[serde(rename = "privacyStatus")]
+    privacy_status: String, // "public", "unlisted" or "private"
+    #[serde(default = "default_embeddable")]
+    embeddable: bool,
+}
+
+fn default_embeddable() -> bool {
+    true
+}
+
+// What the videos API (or yt-dlp) tells us about a video
+#[derive(Default)]
+pub struct VideoDetails {
+    pub title: String,
+    pub channel_id: Option<String>,
+    pub channel_title: Option<String>,
+    pub category: Option<String>,
+    pub language: Option<String>,
+    pub age_restricted: bool,
+    pub duration: Option<u64>,
+    pub thumbnail: Option<String>,
+    pub unplayable: Option<String>,
+}
+
+impl VideoDetails {
+    pub fn into_video_info(self, video_id: &str, url: &str, source: VideoSource) -> VideoInfo {
+        VideoInfo {
+            id: video_id.to_string(),
+            title: Some(self.title),
+            url: url.to_string(),
+            duration: self.duration,
+            thumbnail: self.thumbnail,
+            source,
+            channel_id: self.channel_id,
+            age_restricted: self.age_restricted,
+            channel_title: self.channel_title,
+            category: self.category,
+            language: self.language,
+            start_time: None,
+            unplayable: self.unplayable,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct YouTubeSnippet {
     title: String,
+    #[serde(rename = "channelId")]
+    channel_id: Option<String>,
+    #[serde(rename = "channelTitle")]
+    channel_title: Option<String>,
+    #[serde(rename = "categoryId")]
+    category_id: Option<String>,
+    #[serde(rename = "defaultAudioLanguage")]
+    default_audio_language: Option<String>,
+    #[serde(default)]
+    thumbnails: HashMap<String, YouTubeThumbnail>, // Keyed by size, e.g. "default" or "high"
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeThumbnail {
+    url: String,
+}
+
+// Playlist API response structures
+#[derive(Debug, Deserialize)]
+struct YouTubePlaylistResponse {
+    items: Vec<YouTubePlaylistItem>,
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubePlaylistItem {
+    #[serde(rename = "contentDetails")]
+    content_details: YouTubePlaylistItemDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubePlaylistItemDetails {
+    #[serde(rename = "videoId")]
+    video_id: String,
+}
+
+// oEmbed response, of which only the title is used
+#[derive(Debug, Deserialize)]
+struct YouTubeOEmbed {
+    title: String,
+}
+
+// Search API response structures
+#[derive(Debug, Deserialize)]
+struct YouTubeSearchResponse {
+    items: Vec<YouTubeSearchItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeSearchItem {
+    id: YouTubeSearchId,
+    snippet: YouTubeSnippet,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeSearchId {
+    #[serde(rename = "videoId")]
+    video_id: Option<String>,
+}
+
+// A video found with /search
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub id: String,
+    pub title: String,
 }
 
 pub fn validate_youtube_url(url: &str) -> bool {
@@ -44,48 +248,127 @@ pub fn extract_video_id(url: &str) -> Option<String> {
         .and_then(|cap| cap.get(6).map(|m| m.as_str().to_string()))
 }
 
+// Where a link says to start the video, in seconds. A start at 0 doesn't count.
+pub fn extract_start_time(url: &str) -> Option<u64> {
+    let cap = START_TIME_REGEX.captures(url)?;
+    let part = |i: usize| {
+        cap.get(i)
+            .and_then(|m| m.as_str().parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+
+    Some(part(1) * 3600 + part(2) * 60 + part(3)).filter(|seconds| *seconds > 0)
+}
+
+// The plain watch link for a pasted YouTube link, without the playlist, share and
+// tracking parameters the app adds, but keeping where it says to start
+pub fn normalize_url(url: &str) -> Option<String> {
+    let video_id = extract_video_id(url)?;
+    Some(match extract_start_time(url) {
+        Some(start) => format!("{}&t={}s", get_watch_url(&video_id), start),
+        None => get_watch_url(&video_id),
+    })
+}
+
+// Get a channel ID from the ID itself or a link to the channel's page
+pub fn extract_channel_id(text: &str) -> Option<String> {
+    CHANNEL_ID_REGEX
+        .captures(text.trim())
+        .and_then(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+}
+
+// Get the playlist ID from a link like https://www.youtube.com/playlist?list=PL...
+pub fn extract_playlist_id(url: &str) -> Option<String> {
+    PLAYLIST_ID_REGEX
+        .captures(url)
+        .and_then(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+}
+
 pub async fn create_video_info(url: &str) -> Result<VideoInfo> {
     let video_id =
         extract_video_id(url).ok_or_else(|| anyhow!("Failed to extract video ID from URL"))?;
+    let url = normalize_url(url).unwrap_or_else(|| get_watch_url(&video_id));
+
+    // Try to fetch title and length from YouTube API, but fall back gracefully
+    let lookup = metadata::video_details(&video_id).await;
+    let mut video_info = build_video_info(&video_id, &url, lookup).await;
+    video_info.start_time = extract_start_time(&url);
+    Ok(video_info)
+}
+
+// Look up several videos at once, up to 50 to an API request, returning them in the
+// order given. Without the API each video is looked up on its own.
+pub async fn fetch_video_infos(video_ids: &[String]) -> Vec<VideoInfo> {
+    let mut videos = Vec::with_capacity(video_ids.len());
+
+    let lookups = metadata::videos_details(video_ids).await;
+    for (video_id, lookup) in video_ids.iter().zip(lookups) {
+        videos.push(build_video_info(video_id, &get_watch_url(video_id), lookup).await);
+    }
+
+    videos
+}
 
-    // Try to fetch title from YouTube API, but fall back gracefully
-    let title = match fetch_video_title(&video_id).await {
-        Ok(Some(title)) => Some(title),
-        Ok(None) => Some(format!("YouTube Video: {}", video_id)),
+// Turn what a lookup found out about a video into its VideoInfo, falling back to
+// a placeholder title if the lookup failed
+async fn build_video_info(
+    video_id: &str,
+    url: &str,
+    lookup: Result<Option<VideoDetails>>,
+) -> VideoInfo {
+    let details = match lookup {
+        Ok(Some(details)) => details,
+        // The API leaves out private and removed videos
+        Ok(None) => VideoDetails {
+            title: VideoSource::YouTube.placeholder_title(video_id),
+            unplayable: Some(
+                "That video is private or has been removed, so it can't be played.".to_string(),
+            ),
+            ..VideoDetails::default()
+        },
         Err(e) => {
             // Log the error but don't fail the whole operation
-            log::warn!("Failed to fetch video title: {}", e);
-            Some(format!("YouTube Video: {}", video_id))
+            log::warn!("Failed to fetch video details: {}", e);
+
+            // oEmbed needs no key, so it can usually still find the title
+            let title = match fetch_oembed_title(video_id).await {
+                Ok(title) => title,
+                Err(e) => {
+                    log::warn!("Failed to fetch video title from oEmbed: {}", e);
+                    VideoSource::YouTube.placeholder_title(video_id)
+                }
+            };
+            VideoDetails {
+                title,
+                ..VideoDetails::default()
+            }
         }
     };
 
-    Ok(VideoInfo {
-        id: video_id.clone(),
-        title,
-        url: url.to_string(),
-    })
+    let mut video_info = details.into_video_info(video_id, url, VideoSource::YouTube);
+    // Every video has a thumbnail at a known address, even without the API
+    video_info
+        .thumbnail
+        .get_or_insert_with(|| get_thumbnail_url(video_id));
+    video_info
 }
 
-async fn fetch_video_title(video_id: &str) -> Result<Option<String>> {
-    // Get API key from environment, but don't fail if not present
-    let api_key = match env::var("YOUTUBE_API_KEY") {
-        Ok(key) => key,
-        Err(_) => return Err(anyhow!("YOUTUBE_API_KEY not set")),
-    };
-
-    // Build the API URL
-    let api_url = format!(
-        "https://www.googleapis.com/youtube/v3/videos?id={}&key={}&part=snippet",
-        video_id, api_key
-    );
+// Ask the videos API about up to 50 videos, keyed by video ID.
+// Videos that are private or don't exist are left out.
+pub async fn fetch_api_video_details(
+    video_ids: &[String],
+) -> Result<HashMap<String, VideoDetails>> {
+    let ids = video_ids.join(",");
 
     // Make the API request
-    let client = reqwest::Client::new();
-    let response = client
-        .get(&api_url)
-        .send()
-        .await
-        .map_err(|e| anyhow!("YouTube API request failed: {}", e))?;
+    let response = youtube_api::get(
+        "videos",
+        &[
+            ("part", "snippet,contentDetails,status"),
+            ("id", ids.as_str()),
+        ],
+    )
+    .await?;
 
     if !response.status().is_success() {
         return Err(anyhow!("YouTube API returned error: {}", response.status()));
@@ -96,17 +379,362 @@ async fn fetch_video_title(video_id: &str) -> Result<Option<String>> {
         .await
         .map_err(|e| anyhow!("Failed to parse YouTube API response: {}", e))?;
 
-    // Extract the title
-    if youtube_data.items.is_empty() {
-        // Video not found or API error
-        Ok(None)
-    } else {
-        let title = youtube_data.items[0].snippet.title.clone();
-        Ok(Some(title))
+    // Extract the title and length of each video
+    Ok(youtube_data
+        .items
+        .into_iter()
+        .map(|item| {
+            let details = VideoDetails {
+                unplayable: playback_restriction(&item),
+                thumbnail: best_thumbnail(&item.snippet.thumbnails),
+                channel_id: item.snippet.channel_id,
+                channel_title: item.snippet.channel_title,
+                category: item
+                    .snippet
+                    .category_id
+                    .as_deref()
+                    .and_then(category_name)
+                    .map(|name| name.to_string()),
+                language: item.snippet.default_audio_language,
+                title: item.snippet.title,
+                age_restricted: item.content_details.as_ref().is_some_and(|details| {
+                    details.content_rating.yt_rating.as_deref() == Some("ytAgeRestricted")
+                }),
+                duration: item
+                    .content_details
+                    .and_then(|details| parse_iso8601_duration(&details.duration)),
+            };
+            (item.id, details)
+        })
+        .collect())
+}
+
+// Get a video's title from YouTube's oEmbed endpoint, which needs no API key or quota
+pub async fn fetch_oembed_title(video_id: &str) -> Result<String> {
+    let response = reqwest::Client::new()
+        .get("https://www.youtube.com/oembed")
+        .query(&[
+            ("url", get_watch_url(video_id).as_str()),
+            ("format", "json"),
+        ])
+        .send()
+        .await
+        .map_err(|e| anyhow!("oEmbed request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("oEmbed returned error: {}", response.status()));
+    }
+
+    let oembed: YouTubeOEmbed = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse oEmbed response: {}", e))?;
+
+    Ok(oembed.title)
+}
+
+// Why a video would fail to play on a cast device, if it's private, can't be embedded
+// or is blocked in the country set in KARAOKE_REGION
+fn playback_restriction(item: &YouTubeItem) -> Option<String> {
+    if let Some(status) = &item.status {
+        if status.privacy_status == "private" {
+            return Some("That video is private, so it can't be played.".to_string());
+        }
+        if !status.embeddable {
+            return Some(NOT_EMBEDDABLE.to_string());
+        }
+    }
+
+    let region = env::var("KARAOKE_REGION")
+        .ok()
+        .map(|region| region.trim().to_uppercase())
+        .filter(|region| !region.is_empty())?;
+    let restriction = item.content_details.as_ref()?.region_restriction.as_ref()?;
+
+    let allowed = restriction
+        .allowed
+        .as_ref()
+        .is_none_or(|allowed| allowed.contains(&region));
+    let blocked = restriction
+        .blocked
+        .as_ref()
+        .is_some_and(|blocked| blocked.contains(&region));
+
+    (!allowed || blocked).then(|| {
+        format!(
+            "That video isn't available in {}, so it can't be played here. Try another version of the song.",
+            region
+        )
+    })
+}
+
+// Name of one of YouTube's video categories, from its ID
+fn category_name(category_id: &str) -> Option<&'static str> {
+    Some(match category_id {
+        "1" => "Film & Animation",
+        "2" => "Autos & Vehicles",
+        "10" => "Music",
+        "15" => "Pets & Animals",
+        "17" => "Sports",
+        "19" => "Travel & Events",
+        "20" => "Gaming",
+        "22" => "People & Blogs",
+        "23" => "Comedy",
+        "24" => "Entertainment",
+        "25" => "News & Politics",
+        "26" => "Howto & Style",
+        "27" => "Education",
+        "28" => "Science & Technology",
+        "29" => "Nonprofits & Activism",
+        _ => return None,
+    })
+}
+
+// Parse an ISO 8601 duration like "PT1H2M3S" into seconds. Live streams report "P0D",
+// which has no length, so zero comes back as None.
+fn parse_iso8601_duration(duration: &str) -> Option<u64> {
+    let duration = duration.strip_prefix('P')?;
+    let mut seconds = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+
+    for c in duration.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            unit => {
+                let value: u64 = number.parse().ok()?;
+                number.clear();
+                seconds += value
+                    * match (unit, in_time) {
+                        ('W', false) => 7 * 24 * 3600,
+                        ('D', false) => 24 * 3600,
+                        ('H', true) => 3600,
+                        ('M', true) => 60,
+                        ('S', true) => 1,
+                        _ => return None,
+                    };
+            }
+        }
+    }
+
+    (number.is_empty() && seconds > 0).then_some(seconds)
+}
+
+// Get the IDs of the videos in a playlist, in playlist order, stopping after `max_videos`
+pub async fn fetch_playlist_video_ids(playlist_id: &str, max_videos: usize) -> Result<Vec<String>> {
+    let page_size = PLAYLIST_PAGE_SIZE.to_string();
+    let mut video_ids = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut params = vec![
+            ("part", "contentDetails"),
+            ("maxResults", page_size.as_str()),
+            ("playlistId", playlist_id),
+        ];
+        if let Some(page_token) = &page_token {
+            params.push(("pageToken", page_token.as_str()));
+        }
+
+        let response = youtube_api::get("playlistItems", &params).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!("Playlist not found, it may be private"));
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!("YouTube API returned error: {}", response.status()));
+        }
+
+        let page: YouTubePlaylistResponse = response
+            .json()
+            .await
+            .map_err(|e| anyhow!("Failed to parse YouTube API response: {}", e))?;
+
+        video_ids.extend(
+            page.items
+                .into_iter()
+                .map(|item| item.content_details.video_id),
+        );
+
+        match page.next_page_token {
+            Some(next) if video_ids.len() < max_videos => page_token = Some(next),
+            _ => break,
+        }
+    }
+
+    video_ids.truncate(max_videos);
+    Ok(video_ids)
+}
+
+// Search YouTube for karaoke versions of a song, returning up to `max_results` videos
+pub async fn search_karaoke_videos(query: &str, max_results: usize) -> Result<Vec<SearchResult>> {
+    let query = format!("{} karaoke", query.trim());
+    let max_results = max_results.to_string();
+
+    let response = youtube_api::get(
+        "search",
+        &[
+            ("part", "snippet"),
+            ("type", "video"),
+            ("maxResults", max_results.as_str()),
+            ("q", query.as_str()),
+        ],
+    )
+    .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("YouTube API returned error: {}", response.status()));
     }
+
+    let search_data: YouTubeSearchResponse = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse YouTube API response: {}", e))?;
+
+    Ok(search_data
+        .items
+        .into_iter()
+        .filter_map(|item| {
+            item.id.video_id.map(|id| SearchResult {
+                id,
+                // Search results come with HTML entities in their titles, unlike video lookups
+                title: unescape_html(&item.snippet.title),
+            })
+        })
+        .collect())
 }
 
-// Function to get embed URL for a video
-pub fn get_embed_url(video_id: &str) -> String {
-    format!("https://www.youtube.com/embed/{}", video_id)
+// Whether searching YouTube is possible right now, it needs the API
+pub fn search_available() -> bool {
+    youtube_api::available()
+}
+
+// Whether a video's title says it's already a version to sing along to
+pub fn looks_like_karaoke(title: &str) -> bool {
+    let title = title.to_lowercase();
+    KARAOKE_TITLE_WORDS.iter().any(|word| title.contains(word))
+}
+
+// Decode the few HTML entities YouTube uses in search result titles
+fn unescape_html(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+// The largest of a video's thumbnails that's always there
+fn best_thumbnail(thumbnails: &HashMap<String, YouTubeThumbnail>) -> Option<String> {
+    ["high", "medium", "default"]
+        .iter()
+        .find_map(|size| thumbnails.get(*size))
+        .map(|thumbnail| thumbnail.url.clone())
+}
+
+// Function to get the thumbnail URL for a video
+pub fn get_thumbnail_url(video_id: &str) -> String {
+    format!("https://i.ytimg.com/vi/{}/hqdefault.jpg", video_id)
+}
+
+// Function to get the watch URL for a video
+pub fn get_watch_url(video_id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={}", video_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIDEO_ID: &str = "dQw4w9WgXcQ";
+
+    #[test]
+    fn watch_link() {
+        let url = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+        assert_eq!(normalize_url(url).as_deref(), Some(url));
+    }
+
+    #[test]
+    fn short_link() {
+        let url = "https://youtu.be/dQw4w9WgXcQ";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+    }
+
+    #[test]
+    fn shorts_link() {
+        let url = "https://www.youtube.com/shorts/dQw4w9WgXcQ";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+    }
+
+    #[test]
+    fn mobile_link() {
+        let url = "https://m.youtube.com/watch?v=dQw4w9WgXcQ";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+    }
+
+    #[test]
+    fn start_time_is_kept() {
+        let url = "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1m30s";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+        assert_eq!(extract_start_time(url), Some(90));
+        assert_eq!(
+            normalize_url(url).as_deref(),
+            Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90s")
+        );
+
+        let url = "https://youtu.be/dQw4w9WgXcQ?t=42";
+        assert_eq!(extract_start_time(url), Some(42));
+        assert_eq!(extract_start_time("https://youtu.be/dQw4w9WgXcQ?t=0"), None);
+    }
+
+    #[test]
+    fn playlist_parameters_are_dropped() {
+        let url = "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&index=3";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+        assert_eq!(
+            extract_playlist_id(url).as_deref(),
+            Some("PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI")
+        );
+        assert_eq!(
+            normalize_url(url).as_deref(),
+            Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ")
+        );
+    }
+
+    #[test]
+    fn music_link() {
+        let url = "https://music.youtube.com/watch?v=dQw4w9WgXcQ&feature=share";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+    }
+
+    #[test]
+    fn live_link() {
+        let url = "https://www.youtube.com/live/dQw4w9WgXcQ?feature=shared";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+    }
+
+    #[test]
+    fn share_parameter_is_dropped() {
+        let url = "https://youtu.be/dQw4w9WgXcQ?si=B2mYbP8kq3xF1c9Z";
+        assert_eq!(extract_video_id(url).as_deref(), Some(VIDEO_ID));
+        assert_eq!(
+            normalize_url(url).as_deref(),
+            Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ")
+        );
+    }
+
+    #[test]
+    fn pages_that_are_not_videos() {
+        assert!(!validate_youtube_url(
+            "https://www.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"
+        ));
+        assert!(!validate_youtube_url(
+            "https://www.youtube.com/channel/UCuAXFkgsw1L7xaCfnd5JJOw"
+        ));
+        assert!(!validate_youtube_url(
+            "https://example.com/watch?v=dQw4w9WgXcQ"
+        ));
+    }
 }
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use chrono::{Days, TimeZone, Utc};
+use chrono_tz::America::Los_Angeles;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Where the YouTube Data API lives
+const API_BASE_URL: &str = "https://www.googleapis.com/youtube/v3";
+
+// How long to wait for the API to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How many times to try a request that failed with a server or network error
+const REQUEST_ATTEMPTS: u32 = 3;
+
+// Wait before the first retry, doubled after each failed attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Requests in a row that can fail before the API is left alone for a while
+const FAILURE_THRESHOLD: u32 = 3;
+
+// How long to leave the API alone after it kept failing, in seconds
+const FAILURE_COOLDOWN: i64 = 120;
+
+// What we know about the API's health, shared by every request
+#[derive(Default)]
+struct ApiHealth {
+    quota_resets_at: Option<i64>, // Unix time the used-up daily quota comes back
+    failures: u32,                // Requests in a row that failed
+    paused_until: Option<i64>,    // Unix time to try the API again after it kept failing
+}
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    static ref HEALTH: Mutex<ApiHealth> = Mutex::new(ApiHealth::default());
+}
+
+// Whether the API can be used right now: there's a key, today's quota isn't used up
+// and it hasn't been failing. When it can't, lookups fall back to yt-dlp and oEmbed.
+pub fn available() -> bool {
+    unavailable_reason().is_none()
+}
+
+// Why the API can't be used right now, if it can't
+pub fn unavailable_reason() -> Option<String> {
+    if api_key().is_none() {
+        return Some("YOUTUBE_API_KEY not set".to_string());
+    }
+
+    let now = Utc::now().timestamp();
+    let health = HEALTH.lock().ok()?;
+
+    if health
+        .quota_resets_at
+        .is_some_and(|resets_at| resets_at > now)
+    {
+        Some("The YouTube API quota for today is used up".to_string())
+    } else if health.paused_until.is_some_and(|until| until > now) {
+        Some("The YouTube API keeps failing, so it's left alone for a bit".to_string())
+    } else {
+        None
+    }
+}
+
+// GET an API endpoint, e.g. "videos", retrying server and network errors.
+// Any other answer is returned for the caller to check.
+pub async fn get(endpoint: &str, params: &[(&str, &str)]) -> Result<reqwest::Response> {
+    if let Some(reason) = unavailable_reason() {
+        return Err(anyhow!("{}", reason));
+    }
+    let api_key = api_key().unwrap_or_default();
+    let url = format!("{}/{}", API_BASE_URL, endpoint);
+
+    let mut delay = RETRY_DELAY;
+    let mut last_error = anyhow!("YouTube API request wasn't sent");
+
+    for attempt in 1..=REQUEST_ATTEMPTS {
+        let result = CLIENT
+            .get(&url)
+            .query(params)
+            .query(&[("key", api_key.as_str())])
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_server_error() => {
+                last_error = anyhow!("YouTube API returned error: {}", response.status());
+            }
+            Ok(response) if response.status() == reqwest::StatusCode::FORBIDDEN => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                if body.contains("quotaExceeded") || body.contains("dailyLimitExceeded") {
+                    quota_exceeded();
+                    return Err(anyhow!("The YouTube API quota for today is used up"));
+                }
+                return Err(anyhow!("YouTube API returned error: {}", status));
+            }
+            Ok(response) => {
+                succeeded();
+                return Ok(response);
+            }
+            Err(e) => {
+                last_error = anyhow!("YouTube API request failed: {}", e);
+            }
+        }
+
+        if attempt < REQUEST_ATTEMPTS {
+            warn!(
+                "{} (attempt {} of {}), retrying in {:?}",
+                last_error, attempt, REQUEST_ATTEMPTS, delay
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    failed();
+    Err(last_error)
+}
+
+fn api_key() -> Option<String> {
+    env::var("YOUTUBE_API_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+}
+
+fn succeeded() {
+    if let Ok(mut health) = HEALTH.lock() {
+        health.failures = 0;
+        health.paused_until = None;
+    }
+}
+
+// Leave the API alone for a while once enough requests in a row failed
+fn failed() {
+    if let Ok(mut health) = HEALTH.lock() {
+        health.failures += 1;
+        if health.failures >= FAILURE_THRESHOLD {
+            warn!(
+                "The YouTube API failed {} times in a row, not using it for {} seconds",
+                health.failures, FAILURE_COOLDOWN
+            );
+            health.paused_until = Some(Utc::now().timestamp() + FAILURE_COOLDOWN);
+        }
+    }
+}
+
+// Stop using the API until the quota resets, at midnight Pacific time
+fn quota_exceeded() {
+    let today = Utc::now().with_timezone(&Los_Angeles).date_naive();
+    let resets_at = today
+        .checked_add_days(Days::new(1))
+        .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| Los_Angeles.from_local_datetime(&midnight).earliest())
+        .map(|midnight| midnight.timestamp())
+        .unwrap_or_else(|| Utc::now().timestamp() + 24 * 3600);
+
+    info!("YouTube API quota used up, using the fallbacks until it resets");
+    if let Ok(mut health) = HEALTH.lock() {
+        health.quota_resets_at = Some(resets_at);
+    }
+}
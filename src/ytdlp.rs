@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::env;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::youtube::{get_watch_url, VideoDetails, NOT_EMBEDDABLE};
+
+// yt-dlp binary used when KARAOKE_YTDLP isn't set
+const DEFAULT_YTDLP: &str = "yt-dlp";
+
+// How long yt-dlp gets to look a video up before we give up on it
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A single MP4 file with both video and sound, which every cast receiver can play
+const STREAM_FORMAT: &str = "best[ext=mp4][vcodec!=none][acodec!=none]/best";
+
+// The parts of yt-dlp's --dump-json output we use
+#[derive(Debug, Deserialize)]
+struct YtDlpVideo {
+    title: String,
+    channel_id: Option<String>,
+    duration: Option<f64>, // Seconds, missing for live streams
+    thumbnail: Option<String>,
+    playable_in_embed: Option<bool>,
+    age_limit: Option<u32>, // 18 for age-restricted videos
+    channel: Option<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+    language: Option<String>,
+}
+
+// Look a video up with yt-dlp, for setups without a YouTube API key.
+// Returns None if YouTube says the video is private or gone.
+pub async fn fetch_video_details(video_id: &str) -> Result<Option<VideoDetails>> {
+    let ytdlp = ytdlp_setting();
+
+    let lookup = Command::new(&ytdlp)
+        .arg("--dump-json")
+        .arg("--skip-download")
+        .arg("--no-playlist")
+        .arg("--no-warnings")
+        .arg(get_watch_url(video_id))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(LOOKUP_TIMEOUT, lookup)
+        .await
+        .map_err(|_| anyhow!("{} took too long to look up {}", ytdlp, video_id))?
+        .map_err(|e| anyhow!("Failed to run {}: {}", ytdlp, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("Private video") || stderr.contains("Video unavailable") {
+            return Ok(None);
+        }
+        return Err(anyhow!("{} failed: {}", ytdlp, stderr.trim()));
+    }
+
+    let video: YtDlpVideo = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow!("Failed to parse {} output: {}", ytdlp, e))?;
+
+    Ok(Some(VideoDetails {
+        title: video.title,
+        channel_id: video.channel_id,
+        channel_title: video.channel,
+        category: video.categories.into_iter().next(),
+        language: video.language,
+        age_restricted: video.age_limit.is_some_and(|age| age >= 18),
+        duration: video
+            .duration
+            .map(|duration| duration.round() as u64)
+            .filter(|duration| *duration > 0),
+        thumbnail: video.thumbnail,
+        unplayable: (video.playable_in_embed == Some(false)).then(|| NOT_EMBEDDABLE.to_string()),
+    }))
+}
+
+// A direct link to a video's MP4 stream, for players that can't play a web page,
+// like the Chromecast default media receiver. The link only works for a few hours.
+pub async fn stream_url(watch_url: &str) -> Result<String> {
+    let ytdlp = ytdlp_setting();
+
+    let lookup = Command::new(&ytdlp)
+        .arg("--get-url")
+        .arg("--format")
+        .arg(STREAM_FORMAT)
+        .arg("--no-playlist")
+        .arg("--no-warnings")
+        .arg(watch_url)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(LOOKUP_TIMEOUT, lookup)
+        .await
+        .map_err(|_| anyhow!("{} took too long to find a stream for {}", ytdlp, watch_url))?
+        .map_err(|e| anyhow!("Failed to run {}: {}", ytdlp, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("{} failed: {}", ytdlp, stderr.trim()));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("{} found no stream for {}", ytdlp, watch_url))
+}
+
+// The yt-dlp binary from KARAOKE_YTDLP, a name on the PATH or a full path
+fn ytdlp_setting() -> String {
+    env::var("KARAOKE_YTDLP")
+        .ok()
+        .filter(|ytdlp| !ytdlp.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_YTDLP.to_string())
+}